@@ -5,7 +5,9 @@ function onPopLoginWindow(el) {
 
 function loginInput(evt) {
   let input = evt.target;
-  if (input.type === 'text') {
+  if (input.dataset.field === 'invite') {
+    data.loginCtx.inviteInput = input.value;
+  } else if (input.type === 'text') {
     data.loginCtx.usernameInput = input.value;
   } else if (input.type === 'password') {
     data.loginCtx.passwordInput = input.value;
@@ -54,11 +56,10 @@ function doLogin(isInit) {
       'Content-Type': 'application/json; charset=UTF-8'
     },
     body: JSON.stringify(loginRequest)
-  }).then(response => {
+  }).then(async response => {
     if (!response.ok) {
       console.error("login bad response: ", response);
-      data.loginCtx.alertMessage = "server response: " + response.text();
-      throw new Error(data.loginCtx.alertMessage);
+      throw await responseError(response);
     }
     return response.json();
   }).then(json => {
@@ -67,30 +68,107 @@ function doLogin(isInit) {
   }).catch(error => {
     data.loginCtx.alertMessage = error;
     console.error(data.loginCtx.alertMessage);
+    // session token may be expired, try to get a new one
+    if (isInit && data.localConfig.refreshToken) {
+      doRefresh();
+    }
     ret = false;
   })
   return ret;
 }
 
+// create an account, the server decides whether an invitation code is needed
+function doSignup() {
+  let username = data.loginCtx.usernameInput;
+  let password = data.loginCtx.passwordInput;
+  if (username.length < 4 || username.length > 16) {
+    data.loginCtx.alertMessage = "username length not valid";
+    return;
+  }
+  if (password.length < 4 || password.length > 16) {
+    data.loginCtx.alertMessage = "password length not valid";
+    return;
+  }
+  let signupRequest = {
+    basic_info: {
+      time_stamp: Date.now()
+    },
+    username: username,
+    password: password,
+    invite_code: data.loginCtx.inviteInput || null
+  };
+  fetch(data.api.signup, {
+    method: 'POST',
+    headers: {
+      'Content-Type': 'application/json; charset=UTF-8'
+    },
+    body: JSON.stringify(signupRequest)
+  }).then(async response => {
+    if (!response.ok) {
+      throw await responseError(response);
+    }
+    return response.json();
+  }).then(json => {
+    console.log('get signup response: ', json);
+    onLogin(json, false);
+  }).catch(error => {
+    data.loginCtx.alertMessage = error;
+    console.error(data.loginCtx.alertMessage);
+  })
+}
+
+function doRefresh() {
+  let refreshRequest = {
+    basic_info: {
+      time_stamp: Date.now()
+    },
+    refresh_token: data.localConfig.refreshToken
+  };
+  // refresh token can only be used once
+  data.localConfig.refreshToken = "";
+  fetch(data.api.refresh, {
+    method: 'POST',
+    headers: {
+      'Content-Type': 'application/json; charset=UTF-8'
+    },
+    body: JSON.stringify(refreshRequest)
+  }).then(response => {
+    if (!response.ok) {
+      console.error("refresh bad response: ", response);
+      throw new Error("refresh failed");
+    }
+    return response.json();
+  }).then(json => {
+    console.log('get refresh response: ', json);
+    onLogin(json, true);
+  }).catch(error => {
+    console.error("refresh error ", error);
+  })
+}
+
+// headers of a json request made by a logged in user
+function authHeaders() {
+  return {
+    'Content-Type': 'application/json; charset=UTF-8',
+    'Authorization': `Bearer ${data.userCtx.token}`
+  };
+}
+
 function doLogout() {
   let logoutRequest = {
     basic_info: {
       time_stamp: Date.now()
     },
-    username: data.userCtx.username,
-    token: data.userCtx.token,
   };
   console.log("try logout with request ", logoutRequest);
   fetch(data.api.logout, {
     method: 'POST',
-    headers: {
-      'Content-Type': 'application/json; charset=UTF-8'
-    },
+    headers: authHeaders(),
     body: JSON.stringify(logoutRequest)
-  }).then(response => {
+  }).then(async response => {
     if (!response.ok) {
       console.error("logout bad response:", response);
-      throw new Error(data.loginCtx.alertMessage);
+      throw await responseError(response);
     }
     return response.json();
   }).then(json => {
@@ -103,16 +181,74 @@ function doLogout() {
 
 function onResponseCode(code) {
   // if server responsed code is enum::Ok, the code is "Ok" which is a string
-  // else enum::Err, then the code is { "Err": errmsg } which is an object
+  // else enum::Err, then the code is { "Err": { code, message } } which is an object
   if (typeof code === "object") {
-    throw new Error(code.Err);
+    throw new Error(code.Err.message);
   }
 }
 
+// a failed request answers { code, message }, or a response with such a code
+// for login, signup and logout. error.code is the stable code, e.g. "not_found"
+async function responseError(response) {
+  let text = await response.text();
+  let error = new Error(text || response.statusText);
+  try {
+    let json = JSON.parse(text);
+    let apiError = typeof json.code === "object" ? json.code.Err : json;
+    error = new Error(apiError.message);
+    error.code = apiError.code;
+  } catch {
+    // not json, e.g. from a proxy
+  }
+  return error;
+}
+
+// second login step for users with totp enabled
+function doLoginTotp(ticket, isInit) {
+  let code = window.prompt("Enter the code from your authenticator app, or a recovery code");
+  if (code == null) {
+    return;
+  }
+  let totpRequest = {
+    basic_info: {
+      time_stamp: Date.now()
+    },
+    ticket: ticket,
+    code: code.trim()
+  };
+  fetch(data.api.loginTotp, {
+    method: 'POST',
+    headers: {
+      'Content-Type': 'application/json; charset=UTF-8'
+    },
+    body: JSON.stringify(totpRequest)
+  }).then(async response => {
+    if (!response.ok) {
+      throw await responseError(response);
+    }
+    return response.json();
+  }).then(json => {
+    onLogin(json, isInit);
+  }).catch(error => {
+    data.loginCtx.alertMessage = error;
+    console.error(data.loginCtx.alertMessage);
+  })
+}
+
 function onLogin(response, isInit) {
   onResponseCode(response.code);
+  if (response.totp_ticket) {
+    doLoginTotp(response.totp_ticket, isInit);
+    return;
+  }
+  if (response.totp_enroll_required) {
+    notify(true, "Your account type requires two-factor authentication, please enroll it first");
+  }
   // login success
   data.localConfig.userToken = response.token;
+  if (response.refresh_token) {
+    data.localConfig.refreshToken = response.refresh_token;
+  }
   data.userCtx.login = true
   data.userCtx.token = response.token;
   data.localConfig.userconfig = observe(response.config);
@@ -153,6 +289,53 @@ function onLogout(response) {
   wssend(msg.toJson());
 }
 
+// other sessions of this user are signed out by the server
+function doChangePassword() {
+  let oldPassword = window.prompt("Current password");
+  if (oldPassword == null) {
+    return;
+  }
+  let newPassword = window.prompt("New password(4~16 char)");
+  if (newPassword == null) {
+    return;
+  }
+  fetch(data.api.changePassword, {
+    method: 'POST',
+    headers: authHeaders(),
+    body: JSON.stringify({ old_password: oldPassword, new_password: newPassword })
+  }).then(async response => {
+    if (!response.ok) {
+      throw await responseError(response);
+    }
+    notify(false, "Password changed, other sessions are signed out");
+  }).catch(error => {
+    notify(true, "Change password failed: " + error.message);
+  })
+}
+
+function doDeleteAccount() {
+  let password = window.prompt("Password, your account and files will be deleted");
+  if (password == null) {
+    return;
+  }
+  let archive = window.confirm("Keep an archive of your files on the server? Cancel to purge them");
+  fetch(data.api.deleteAccount, {
+    method: 'POST',
+    headers: authHeaders(),
+    body: JSON.stringify({ password: password, mode: archive ? "Archive" : "Purge" })
+  }).then(async response => {
+    if (!response.ok) {
+      throw await responseError(response);
+    }
+    data.localConfig.userToken = "";
+    data.localConfig.refreshToken = "";
+    data.userCtx.login = false;
+    notify(false, "Account deleted");
+  }).catch(error => {
+    notify(true, "Delete account failed: " + error.message);
+  })
+}
+
 function onUserProfile() {
 
 }
@@ -13,6 +13,7 @@ function defaultLocalConfig() {
     },
     notify: true,
     userToken: "",
+    refreshToken: "",
     username: "",
   }
 }
@@ -52,12 +53,20 @@ let rawData = {
   loginCtx: {
     usernameInput: "",
     passwordInput: "",
+    inviteInput: "",
     alertMessage: ""
   },
   api: {
     login: prefix_ + "login",
+    signup: prefix_ + "signup",
+    loginTotp: prefix_ + "login/totp",
+    refresh: prefix_ + "refresh",
     logout: prefix_ + "logout",
+    changePassword: prefix_ + "account/password",
+    deleteAccount: prefix_ + "account/delete",
+    preferences: prefix_ + "account/preferences",
     getfile: prefix_ + "files",
+    fileview: prefix_ + "files/view",
     deletefile: prefix_ + "delete_file",
     getfileelem: prefix_ + "file",
     getdownloadurl: prefix_ + "get_download_url",
@@ -66,12 +75,17 @@ let rawData = {
   localConfig: defaultLocalConfig(),
   ws: {
     established: false,
+    // the agreed handshake, see wsHandshake
+    protocol: null,
     socket: null,
     workers: [],
   },
   files: {
     uploadText: "upload",
     downloadText: "download",
+    moreText: "more",
+    // the page after the last one shown, null when all are shown
+    nextCursor: null,
   },
   uploader: null,
   dashboard: {
@@ -283,6 +297,10 @@ function specialLisenter() {
       document.querySelector("header").classList.remove("header-transparent");
     }
     lastScrollTop = currentScroll <= 0 ? 0 : currentScroll;
+    // the next page of files comes when the end of the list is near
+    if (data.tab === 'files' && window.innerHeight + currentScroll >= document.body.offsetHeight - 200) {
+      loadMoreFiles();
+    }
   }, false);
 }
 
@@ -1,56 +1,35 @@
 function toggleTheme() {
-  if (data.localConfig.userconfig.theme === 'dark') {
-    data.localConfig.userconfig.theme = 'light';
-  } else {
-    data.localConfig.userconfig.theme = 'dark';
-  }
+  let theme = data.localConfig.userconfig.theme === 'dark' ? 'light' : 'dark';
+  data.localConfig.userconfig.theme = theme;
+  fetch(data.api.preferences, {
+    method: 'POST',
+    headers: authHeaders(),
+    body: JSON.stringify({ theme: theme })
+  }).catch(e => {
+    console.error("save theme error ", e);
+  })
 }
 
+// the server keeps the view and returns the files sorted by it
 function sortTableToggle(column) {
-  if (data.localConfig.userconfig.filelist_config["/"].order_by === column) {
-    data.localConfig.userconfig.filelist_config["/"].order_asc =
-      !data.localConfig.userconfig.filelist_config["/"].order_asc;
+  let config = Object.assign({}, data.localConfig.userconfig.filelist_config["/"]);
+  if (config.order_by === column) {
+    config.order_asc = !config.order_asc;
   } else {
-    data.localConfig.userconfig.filelist_config["/"].order_by = column;
-  }
-  const tbody = document.querySelector('.file-list tbody');
-  let rows = Array.from(tbody.querySelectorAll('tr'));
-  sortTableImpl(rows, column, data.localConfig.userconfig.filelist_config['/'].order_asc, (obj) => {
-    return obj.querySelector(`td[data-key="${column}"]`).textContent;
-  });
-  rows.forEach(row => tbody.appendChild(row));
-}
-
-function sortTableImpl(rows, sortKey, asc, getContent) {
-  let convertSizeToBytes = function (sizeStr) {
-    const units = { 'Kb': 1024, 'Mb': 1024 * 1024, 'Gb': 1024 * 1024 * 1024 };
-    const regex = /(\d+(?:\.\d+)?)\s*(Kb|Mb|Gb)/;
-    const matches = sizeStr.match(regex);
-    if (matches) {
-      const value = parseFloat(matches[1]);
-      const unit = matches[2];
-      return value * units[unit];
-    }
-    return 0; // Return 0 if no matches or in case of invalid format
+    config.order_by = column;
   }
-  rows.sort((a, b) => {
-    let valA = getContent(a);
-    let valB = getContent(b);
-
-    if (sortKey === 'size') { // Size sorting
-      valA = convertSizeToBytes(valA);
-      valB = convertSizeToBytes(valB);
-    } else if (sortKey === 'create_t' || sortKey === 'modify_t' || sortKey === 'access_t') { // Date sorting
-      valA = new Date(valA);
-      valB = new Date(valB);
-    }
-
-    if (typeof valA === 'string' && typeof valB === 'string') {
-      return asc ? valA.localeCompare(valB) : valB.localeCompare(valA);
-    } else {
-      return asc ? valA - valB : valB - valA;
+  fetch(data.api.fileview, {
+    method: 'POST',
+    headers: authHeaders(),
+    body: JSON.stringify({ path: "/", config: config })
+  }).then(async response => {
+    if (!response.ok) {
+      throw await responseError(response);
     }
-  });
+    loadFileList();
+  }).catch(e => {
+    notify(true, "Sort failed: " + e.message);
+  })
 }
 
 // 
@@ -95,13 +74,9 @@ function getFileElem(newFileName) {
   let newFileElem = null;
   fetch(data.api.getfileelem, {
     method: 'POST',
-    headers: {
-      'Content-Type': 'application/json; charset=UTF-8'
-    },
+    headers: authHeaders(),
     body: JSON.stringify({
-      name: newFileName,
-      username: data.userCtx.username,
-      token: data.userCtx.token
+      name: newFileName
     })
   }).then(response => {
     if (!response.ok) {
@@ -194,21 +169,28 @@ function loadFileList(newFileName) {
     return;
   }
   console.log("load file");
-  createFileListHeader();
-  refreshDom();
-  // add file list element
-  let tbody = document.querySelector('tbody');
-  tbody.innerHTML = '';
+  loadFilePage(newFileName, null);
+}
+
+// a page is being fetched, no other one is asked for meanwhile
+let filePageLoading = false;
+// bumped by every first page, the pages of an older listing are dropped
+let filePageListing = 0;
 
+// pages are appended as they are asked for, the first one resets the table
+function loadFilePage(newFileName, cursor) {
+  if (cursor != null && filePageLoading) {
+    return;
+  }
+  if (cursor == null) {
+    filePageListing++;
+  }
+  const listing = filePageListing;
+  filePageLoading = true;
   fetch(data.api.getfile, {
     method: 'POST',
-    headers: {
-      'Content-Type': 'application/json; charset=UTF-8'
-    },
-    body: JSON.stringify({
-      username: data.userCtx.username,
-      token: data.userCtx.token
-    })
+    headers: authHeaders(),
+    body: JSON.stringify({ path: "/", cursor: cursor })
   }).then(response => {
     if (!response.ok) {
       console.error("get file bad response:", response);
@@ -216,18 +198,22 @@ function loadFileList(newFileName) {
     }
     return response.json();
   }).then(json => {
-    // struct FileListElem {
-    //   name: String,
-    //   size: String,
-    //   create_t: String,
-    //   access_t: String,
-    //   modify_t: String,
+    if (listing !== filePageListing) {
+      return;
+    }
+    // struct FileList {
+    //   path: String,
+    //   config: FileListPathConfig,
+    //   files: [{ column: value }], sorted and holding the configured columns only
+    //   next_cursor: String or null on the last page
     // }
-    sortTableImpl(json.files, data.localConfig.userconfig.filelist_config["/"].order_by,
-      data.localConfig.userconfig.filelist_config["/"].order_asc, (file) => {
-        let columnVal = file[data.localConfig.userconfig.filelist_config["/"].order_by];
-        return columnVal;
-      });
+    let tbody = document.querySelector('tbody');
+    if (cursor == null) {
+      data.localConfig.userconfig.filelist_config[json.path] = json.config;
+      createFileListHeader();
+      refreshDom();
+      tbody.innerHTML = '';
+    }
     json.files.forEach(fileElem => {
       let tr = createFileRowElem(fileElem);
       tbody.appendChild(tr);
@@ -242,11 +228,22 @@ function loadFileList(newFileName) {
         });
       }
     });
+    data.files.nextCursor = json.next_cursor;
   }).catch(e => {
     console.error("get file error ", e);
+  }).finally(() => {
+    if (listing === filePageListing) {
+      filePageLoading = false;
+    }
   })
 }
 
+function loadMoreFiles() {
+  if (data.files.nextCursor != null) {
+    loadFilePage(null, data.files.nextCursor);
+  }
+}
+
 function uploadFile(evt) {
   console.log('try upload');
   let numberOfActiveWorker = 0;
@@ -280,13 +277,9 @@ function downloadFile(filename) {
 
   fetch(data.api.getdownloadurl, {
     method: 'POST',
-    headers: {
-      'Content-Type': 'application/json; charset=UTF-8'
-    },
+    headers: authHeaders(),
     body: JSON.stringify({
-      name: filename,
-      username: data.userCtx.username,
-      token: data.userCtx.token
+      name: filename
     })
   }).then(response => {
     if (!response.ok) {
@@ -305,13 +298,9 @@ function downloadFile(filename) {
 function deleteFile(filename, ondelete) {
   fetch(data.api.deletefile, {
     method: 'POST',
-    headers: {
-      'Content-Type': 'application/json; charset=UTF-8'
-    },
+    headers: authHeaders(),
     body: JSON.stringify({
-      name: filename,
-      username: data.userCtx.username,
-      token: data.userCtx.token
+      name: filename
     })
   }).then(response => {
     if (!response.ok) {
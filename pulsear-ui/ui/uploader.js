@@ -24,8 +24,10 @@ class Uploader {
   //   this.#websocketNum = websocketNum;
   //   this.#workerNum = workerNum;
   // }
+  // told to the server in the ws handshake
+  static SLICE_SIZE = 40960
   #files
-  #sliceSize = 40960
+  #sliceSize = Uploader.SLICE_SIZE
 
   constructor() {
     this.#files = {};
@@ -89,23 +91,11 @@ class Uploader {
           nr_slice_ok: 0
         };
         if (file.isUploader) {
-          let wholeworker = 1;
-          let wholeslice = parseInt((file.req.size - 1) / file.req.slice_size + 1);
-          let worker_slice_devide = parseInt((wholeslice - 1) / wholeworker + 1);
-          let worker_slice_n = 0;
-          if (worker_slice_devide == 0) {
-            worker_slice_devide++;
-          }
-          for (let i = 0; i < wholeworker && worker_slice_n < wholeslice; i++) {
-            let slice_start = i * worker_slice_devide;
-            let slice_send = Math.min(worker_slice_devide, wholeslice - worker_slice_n);
-            giveWorkerMsg(file.worker_id = this.chooseWorker(), {
-              req: file.req,
-              f: file.f,
-              slice_idx: [slice_start, slice_start + slice_send],
-            });
-            worker_slice_n += slice_send;
-          }
+          // the server grants a window of slices, and one more on each file response
+          file.window = Math.max(file_sendable_resp.window, 1);
+          file.credits = file.window;
+          file.worker_id = this.chooseWorker();
+          this.sendWithCredits(file);
         }
 
         this.focusRow(file.tr);
@@ -131,7 +121,8 @@ class Uploader {
         // when all is zero, bs become empty
         if (this.#files[resp.file_hash].isUploader) {
           file.bs.setRange(resp.slice_idx[0], resp.slice_idx[1] - 1, 0);
-          file.bs_updated = true;
+          file.credits += resp.credits;
+          this.sendWithCredits(file);
           if (file.bs.isEmpty()) {
             // tell server this is finished
             let msg = new WsMessage(
@@ -148,11 +139,11 @@ class Uploader {
           }
         }
       } else if (resp.status === "Resend" && this.#files[resp.file_hash].isUploader) {
-        giveWorkerMsg(file.worker_id, {
-          req: file.req,
-          f: file.f,
-          slice_idx: [resp.slice_idx[0], resp.slice_idx[1]]
-        });
+        for (let i = resp.slice_idx[0]; i < resp.slice_idx[1]; i++) {
+          file.resend.push(i);
+        }
+        file.credits += resp.credits;
+        this.sendWithCredits(file);
       } else if (resp.status === "Fatalerr") {
         this.notifyWrapper(true, `upload ${resp.name} error`, this.#files[resp.file_hash].isUploader);
         delete (this.#files[resp.file_hash]);
@@ -165,41 +156,34 @@ class Uploader {
     } else if (ws_message.msg.is(WsMessageClass.PleaseSend)) {
       let file_hash = ws_message.msg.content;
       let file = this.#files[file_hash];
-      if (file.isUploader) {
-        if (file.bs_updated) this.bsSendAllLeft(file);
-        else this.bsSendOne(file);
-        file.bs_updated = false;
+      if (file && file.isUploader && file.window) {
+        // the server stalled, nothing is in flight and the window is whole again.
+        // sent slices not answered are sent again
+        file.credits = file.window;
+        file.resend = file.bs.toArray().filter(i => i < file.next);
+        this.sendWithCredits(file);
       }
     }
   }
 
-  bsSendAllLeft(file) {
-    let ones = file.bs.toArray();
-    let lastNotSendIdx = ones[0]; // [1,2,3,5,6,7,11,12]
-    for (let i = 1; i < ones.length; i++) {
-      if (ones[i] - ones[i - 1] > 1) {
-        giveWorkerMsg(file.worker_id, {
-          req: file.req,
-          f: file.f,
-          slice_idx: [lastNotSendIdx, ones[i - 1] + 1]
-        });
-        lastNotSendIdx = ones[i];
+  // resent slices first, then the ones never sent, one credit each
+  sendWithCredits(file) {
+    while (file.credits > 0) {
+      let i;
+      if (file.resend.length > 0) {
+        i = file.resend.shift();
+      } else if (file.next < file.upload.nr_slice_all) {
+        i = file.next++;
+      } else {
+        break;
       }
+      file.credits--;
+      giveWorkerMsg(file.worker_id, {
+        req: file.req,
+        f: file.f,
+        slice_idx: [i, i + 1]
+      });
     }
-    giveWorkerMsg(file.worker_id, {
-      req: file.req,
-      f: file.f,
-      slice_idx: [lastNotSendIdx, ones[ones.length - 1] + 1]
-    });
-  }
-
-  bsSendOne(file) {
-    let leastNotSend = file.bs.lsb();
-    giveWorkerMsg(file.worker_id, {
-      req: file.req,
-      f: file.f,
-      slice_idx: [leastNotSend, leastNotSend + 1]
-    });
   }
 
   focusRow(tr) {
@@ -288,7 +272,11 @@ class Uploader {
       req: request,
       worker_id: -1,
       bs: bs,
-      bs_updated: false,
+      // flow control, see sendWithCredits
+      window: 0,
+      credits: 0,
+      next: 0,
+      resend: [],
       // uploader
 
       upload: null,
@@ -84,20 +84,45 @@ function handleCommand(cmd) {
   };
 */
 
-function uploadSlice(file, hashval, slice_index, start, end) {
+const CRC32C_TABLE = (() => {
+  let table = new Uint32Array(256);
+  for (let i = 0; i < 256; i++) {
+    let c = i;
+    for (let k = 0; k < 8; k++) {
+      c = c & 1 ? (c >>> 1) ^ 0x82F63B78 : c >>> 1;
+    }
+    table[i] = c >>> 0;
+  }
+  return table;
+})();
+
+function crc32c(bytes) {
+  let crc = 0xFFFFFFFF;
+  for (let i = 0; i < bytes.length; i++) {
+    crc = CRC32C_TABLE[(crc ^ bytes[i]) & 0xFF] ^ (crc >>> 8);
+  }
+  return (crc ^ 0xFFFFFFFF) >>> 0;
+}
+
+// a v2 SliceFrame, see file.rs of the server
+const SLICE_FRAME_HEADER = 56;
+async function uploadSlice(file, hashval, slice_index, start, end) {
   if (data.socket != null && data.socket.readyState != WebSocket.OPEN) { 
     return;
   }
-  const hashvalBlob = new Uint8Array(hashval.match(/[\da-f]{2}/gi).map(byte => parseInt(byte, 16)));
-  const view = new DataView(new ArrayBuffer(4));
-  view.setUint32(0, slice_index, true); // true express little-endian
-  const sliceIndexBlob = new Blob([new Uint8Array(view.buffer)]);
-  const fileSliceBlob = file.slice(start, end); 
-  let blobToSend = new Blob([hashvalBlob, sliceIndexBlob, fileSliceBlob]);
-  data.socket.send(blobToSend)
+  const slice = new Uint8Array(await file.slice(start, end).arrayBuffer());
+  const header = new Uint8Array(SLICE_FRAME_HEADER);
+  const view = new DataView(header.buffer);
+  header.set(new TextEncoder().encode("PSLC"), 0);
+  header[4] = 2; // version, 5..8 is reserved
+  header.set(hashval.match(/[\da-f]{2}/gi).map(byte => parseInt(byte, 16)), 8);
+  view.setBigUint64(40, BigInt(slice_index), true); // true express little-endian
+  view.setUint32(48, slice.length, true);
+  view.setUint32(52, crc32c(slice), true);
+  data.socket.send(new Blob([header, slice]));
 }
 
-function uploadAll(file) {
+async function uploadAll(file) {
   let slice_size = file.req.slice_size;
   let size = file.req.size;
   let i = 0;
@@ -108,12 +133,12 @@ function uploadAll(file) {
     if (i == n - 1) {
       sendsize = size - i*slice_size;
     }
-    uploadSlice(file.f, file.req.file_hash, i, i*slice_size, i*slice_size + sendsize);
+    await uploadSlice(file.f, file.req.file_hash, i, i*slice_size, i*slice_size + sendsize);
     i++;
   }
 }
 
-self.onmessage = function(workerMessageIn) {
+self.onmessage = async function(workerMessageIn) {
   let msg = workerMessageIn.data;
   if (typeof msg === 'string') {
     handleCommand(msg);
@@ -133,9 +158,9 @@ self.onmessage = function(workerMessageIn) {
     for (let j = file.slice_idx[0]; j < file.slice_idx[1]; j++) {
       let start = file.req.slice_size*j;
       let end = start + Math.min(file.req.slice_size, file.req.size - start);
-      uploadSlice(file.f, file.req.file_hash, j, start, end);
+      await uploadSlice(file.f, file.req.file_hash, j, start, end);
     }
   } else {
-    uploadAll(file);
+    await uploadAll(file);
   }
 }
\ No newline at end of file
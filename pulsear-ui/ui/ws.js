@@ -265,9 +265,30 @@ class WsDispatchType {
   }
 }
 
+// see WsHandshake of the server, it answers with the agreed one
+const WS_PROTOCOL_VERSION = 2;
+const WS_MIN_PROTOCOL_VERSION = 1;
+function wsHandshake() {
+  return {
+    version: WS_PROTOCOL_VERSION,
+    min_version: WS_MIN_PROTOCOL_VERSION,
+    capabilities: {
+      max_slice_size: Uploader.SLICE_SIZE,
+      encodings: ["raw"],
+      resume: false
+    }
+  };
+}
+
 class WsMessageClass {
   static Establish = new WsMessageClass(0, null);
+  static withEstablish = handshake => {
+    return new WsMessageClass(0, handshake);
+  };
   static Reconnect = new WsMessageClass(10, null);
+  static withReconnect = handshake => {
+    return new WsMessageClass(10, handshake);
+  };
   static Leave = new WsMessageClass(7, null);
   static FileSendable = new WsMessageClass(1, null);
   static withFileSendable = e => {
@@ -305,6 +326,20 @@ class WsMessageClass {
   static withPleaseSend = file_hash => {
     return new WsMessageClass(11, file_hash);
   };
+  static ListSessions = new WsMessageClass(12, null);
+  static Sessions = new WsMessageClass(13, null);
+  static withSessions = sessions => {
+    return new WsMessageClass(13, sessions);
+  };
+  static TerminateSession = new WsMessageClass(14, null);
+  static withTerminateSession = target => {
+    return new WsMessageClass(14, target);
+  };
+  // content is { code, message }, code is stable, e.g. "quota_exceeded"
+  static Error = new WsMessageClass(15, null);
+  static withError = e => {
+    return new WsMessageClass(15, e);
+  };
   #value
   #content
 
@@ -325,10 +360,10 @@ class WsMessageClass {
     let out_obj;
     switch (this.#value) {
       case 0:
-        out_obj = "Establish";
+        out_obj = { Establish: this.#content };
         break;
       case 10:
-        out_obj = "Reconnect";
+        out_obj = { Reconnect: this.#content };
         break;
       case 1:
         out_obj = { FileSendable: this.#content };
@@ -360,6 +395,18 @@ class WsMessageClass {
       case 11:
         out_obj = { PleaseSend: this.#content };
         break;
+      case 12:
+        out_obj = "ListSessions";
+        break;
+      case 13:
+        out_obj = { Sessions: this.#content };
+        break;
+      case 14:
+        out_obj = { TerminateSession: this.#content };
+        break;
+      case 15:
+        out_obj = { Error: this.#content };
+        break;
     }
     return out_obj;
   }
@@ -370,12 +417,12 @@ class WsMessageClass {
   }
 
   static fromObj(obj) {
-    if (obj === "Establish") {
-      return WsMessageClass.Establish;
+    if (typeof obj === 'object' && obj !== null && obj.Establish != null) {
+      return WsMessageClass.withEstablish(obj.Establish);
     } else if (obj === "Leave") {
       return WsMessageClass.Leave;
-    } else if (obj === "Reconnect") {
-      return WsMessageClass.Reconnect;
+    } else if (typeof obj === 'object' && obj !== null && obj.Reconnect != null) {
+      return WsMessageClass.withReconnect(obj.Reconnect);
     } else if (typeof obj === 'object' && obj !== null && obj.FileRequest) {
       return WsMessageClass.withFileRequest(obj.FileRequest);
     } else if (typeof obj === 'object' && obj !== null && obj.FileResponse) {
@@ -388,12 +435,20 @@ class WsMessageClass {
       return WsMessageClass.withNotify(obj.Notify);
     } else if (typeof obj === 'object' && obj !== null && obj.Errjson) {
       return WsMessageClass.withErrjson(obj.Errjson);
+    } else if (typeof obj === 'object' && obj !== null && obj.Error != null) {
+      return WsMessageClass.withError(obj.Error);
     } else if (typeof obj === 'object' && obj !== null && obj.CreateWsWorker != null) {
       return WsMessageClass.withCreateWsWorker(obj.CreateWsWorker)
     } else if (typeof obj === 'object' && obj !== null && obj.HeartBeat != null) {
       return WsMessageClass.withHeartBeat(obj.HeartBeat)
     } else if (typeof obj === 'object' && obj !== null && obj.PleaseSend != null) {
       return WsMessageClass.withPleaseSend(obj.PleaseSend)
+    } else if (obj === "ListSessions") {
+      return WsMessageClass.ListSessions;
+    } else if (typeof obj === 'object' && obj !== null && obj.Sessions != null) {
+      return WsMessageClass.withSessions(obj.Sessions)
+    } else if (typeof obj === 'object' && obj !== null && obj.TerminateSession != null) {
+      return WsMessageClass.withTerminateSession(obj.TerminateSession)
     } else {
       throw new Error("Invalid object for WsMessageClass");
     }
@@ -596,7 +651,8 @@ function registerWsWorker() {
   const { location } = window;
 
   const proto = location.protocol.startsWith('https') ? 'wss' : 'ws';
-  const wsUri = `${proto}://${location.host}/ws`;
+  // server authenticate the upgrade by session token
+  const wsUri = `${proto}://${location.host}/ws?token=${encodeURIComponent(data.userCtx.token)}`;
 
   for (let i = 0; i < data.localConfig.userconfig.web_worker_num; i++) {
     data.ws.workers[i] = {
@@ -614,7 +670,8 @@ let wsmain_tm = new HigherTimeout(() => {
 function registerWsMain(reconnect) {
   const { location } = window;
   const proto = location.protocol.startsWith('https') ? 'wss' : 'ws';
-  const wsUri = `${proto}://${location.host}/ws`;
+  // server authenticate the upgrade by session token
+  const wsUri = `${proto}://${location.host}/ws?token=${encodeURIComponent(data.userCtx.token)}`;
   let hb_interval = null;
 
   data.ws.socket = new WebSocket(wsUri);
@@ -623,7 +680,7 @@ function registerWsMain(reconnect) {
     wsmain_tm.reset();
     let msg = new WsMessage(
       WsSender.withUser(data.userCtx.username, data.userCtx.user_ctx_hash),
-      reconnect ? WsMessageClass.Reconnect : WsMessageClass.Establish,
+      reconnect ? WsMessageClass.withReconnect(wsHandshake()) : WsMessageClass.withEstablish(wsHandshake()),
       WsDispatchType.Server
     );
     console.log(reconnect ? 'Trying to reconnect' : 'Trying to connect', msg.asObj());
@@ -634,7 +691,6 @@ function registerWsMain(reconnect) {
       let msg = new WsMessage(
         WsSender.withUser(data.userCtx.username, data.userCtx.user_ctx_hash),
         WsMessageClass.withHeartBeat({
-          config: data.localConfig.userconfig,
           dashboard: {
             online_user: 0,
             online_client: 0,
@@ -677,9 +733,14 @@ function handleWsMessage(evt) {
   if (ws_message.msg.is(WsMessageClass.Notify)) {
     onWsNotify(ws_message);
   }
+  if (ws_message.msg.is(WsMessageClass.Error)) {
+    console.error('ws request failed: ', ws_message.msg.content);
+    notify(true, ws_message.msg.content.message);
+  }
   if (ws_message.msg.is(WsMessageClass.Establish) || ws_message.msg.is(WsMessageClass.Reconnect)) {
     console.log('Received', ws_message.msg.is(WsMessageClass.Establish) ? 'Establish' : 'Reconnect', evt.data);
     data.userCtx.user_ctx_hash = ws_message.policy.wsClients[0].user_ctx_hash;
+    data.ws.protocol = ws_message.msg.content;
     data.ws.established = true;
   }
   if (ws_message.msg.is(WsMessageClass.Leave)) {
@@ -688,6 +749,7 @@ function handleWsMessage(evt) {
     data.userCtx.username = "";
     data.userCtx.token = "";
     data.localConfig.userToken = "";
+    data.localConfig.refreshToken = "";
     data.localConfig.username = "";
     window.location = data.prefix;
   }
@@ -700,4 +762,45 @@ function handleWsMessage(evt) {
     let heartbeat = ws_message.msg.content;
     data.dashboard.info = heartbeat.dashboard;
   }
+  if (ws_message.msg.is(WsMessageClass.Sessions)) {
+    onSessions(ws_message.msg.content);
+  }
+}
+
+function listSessions() {
+  let msg = new WsMessage(
+    WsSender.withUser(data.userCtx.username, data.userCtx.user_ctx_hash),
+    WsMessageClass.ListSessions,
+    WsDispatchType.Server
+  );
+  wssend(msg.toJson());
+}
+
+// show sessions of this user, and sign out the chosen ones
+function onSessions(sessions) {
+  let lines = sessions.map((s, i) =>
+    `${i + 1}. ${s.browser} on ${s.device}, ${s.ip}, since ${new Date(s.establish_t).toLocaleString()}` +
+    (s.current ? " (this login)" : ""));
+  let choice = window.prompt(
+    lines.join("\n") + "\n\nEnter a number to sign it out, 'all' for all other sessions");
+  if (choice == null || choice.trim() === "") {
+    return;
+  }
+  let target;
+  if (choice.trim() === "all") {
+    target = "AllOthers";
+  } else {
+    let session = sessions[parseInt(choice) - 1];
+    if (session == null) {
+      notify(true, "no such session");
+      return;
+    }
+    target = { One: session.id };
+  }
+  let msg = new WsMessage(
+    WsSender.withUser(data.userCtx.username, data.userCtx.user_ctx_hash),
+    WsMessageClass.withTerminateSession(target),
+    WsDispatchType.Server
+  );
+  wssend(msg.toJson());
 }
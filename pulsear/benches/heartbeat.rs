@@ -0,0 +1,151 @@
+//! heartbeat round trip of many websocket clients at once, against an embedded
+//! sqlite database. run with `cargo bench --bench heartbeat`, the number of
+//! clients is read from PULSEAR_BENCH_CLIENTS and defaults to 300
+use pulsear::*;
+use std::net::TcpStream;
+use std::sync::{Barrier, Mutex};
+use std::time::{Duration, Instant};
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+const ADDR: &str = "127.0.0.1:9997";
+const ROUNDS: usize = 20;
+
+struct Client {
+  username: String,
+  socket: WebSocket<MaybeTlsStream<TcpStream>>,
+}
+
+impl Client {
+  fn connect(username: String, token: &str) -> Self {
+    let url = format!("ws://{}/ws?token={}", ADDR, token);
+    let (socket, _) = tungstenite::connect(url).expect("ws connect");
+    Self { username, socket }
+  }
+
+  /// time from sending a heartbeat to reading the server's one
+  fn heartbeat(&mut self) -> Duration {
+    let msg = serde_json::json!({
+      "sender": { "User": { "username": self.username, "user_ctx_hash": "" } },
+      "msg": { "HeartBeat": {
+        "dashboard": {
+          "online_user": 0, "online_client": 0, "user_used_storage": 0, "user_max_storage": 0
+        }
+      } },
+      "policy": "Server",
+    });
+    let begin = Instant::now();
+    self.socket.send(Message::text(msg.to_string())).expect("ws send");
+    loop {
+      match self.socket.read().expect("ws read") {
+        Message::Text(t) if t.contains("\"HeartBeat\"") => return begin.elapsed(),
+        _ => (),
+      }
+    }
+  }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+  sorted[((sorted.len() - 1) as f64 * p) as usize]
+}
+
+fn run(clients: Vec<Client>) -> Vec<Client> {
+  let n = clients.len();
+  let barrier = Barrier::new(n);
+  let latencies = Mutex::new(Vec::with_capacity(n * ROUNDS));
+  let clients: Vec<Client> = std::thread::scope(|s| {
+    let handles: Vec<_> = clients
+      .into_iter()
+      .map(|mut c| {
+        let (barrier, latencies) = (&barrier, &latencies);
+        s.spawn(move || {
+          for _ in 0..ROUNDS {
+            barrier.wait();
+            let t = c.heartbeat();
+            latencies.lock().unwrap().push(t);
+          }
+          c
+        })
+      })
+      .collect();
+    handles.into_iter().map(|h| h.join().unwrap()).collect()
+  });
+  let mut latencies = latencies.into_inner().unwrap();
+  latencies.sort();
+  println!(
+    "heartbeat {:>4} clients: p50 {:>8.2?} p99 {:>8.2?} max {:>8.2?}",
+    n,
+    percentile(&latencies, 0.5),
+    percentile(&latencies, 0.99),
+    latencies.last().unwrap()
+  );
+  clients
+}
+
+fn main() {
+  let clients: usize = std::env::var("PULSEAR_BENCH_CLIENTS")
+    .ok()
+    .and_then(|n| n.parse().ok())
+    .unwrap_or(300);
+  let db = std::env::temp_dir().join(format!("pulsear_bench_{}.db", std::process::id()));
+  let config = ServerConfig {
+    loglevel: "error".into(),
+    cwd: format!("{}/..", env!("CARGO_MANIFEST_DIR")),
+    inner_addr: ADDR.into(),
+    worker_num: 4,
+    https: false,
+    file_worker_num: 4,
+    sql_url: format!("sqlite://{}", db.display()),
+    session_ttl: 3600,
+    refresh_ttl: 3600,
+    login_throttle: LoginThrottleConfig::default(),
+    rate_limit: RateLimitConfig {
+      enabled: false,
+      ..Default::default()
+    },
+    registration: RegistrationMode::Open,
+    auth_providers: default_auth_providers(),
+    upload_window: default_upload_window(),
+    file_writes: FileWriteConfig::default(),
+    websocket: WsConfig::default(),
+  };
+  let server = std::sync::Arc::new(Server::from(config.clone()).expect("server"));
+
+  let sqlhandler = server.sqlhandler.clone();
+  let tokens: Vec<(String, String)> = (0..clients)
+    .map(|i| {
+      let username = format!("bench{}", i);
+      let user = sqlhandler
+        .add_user(&User {
+          id: 0,
+          username: username.clone(),
+          token: password_token(&username, "bench"),
+          config: UserConfig::default(),
+          usertype: UserType::default(),
+        })
+        .unwrap()
+        .unwrap();
+      let issued = issue_session(&sqlhandler, &user, "bench", &config).unwrap();
+      (username, issued.token)
+    })
+    .collect();
+
+  let started = server.clone();
+  std::thread::spawn(move || actix_web::rt::System::new().block_on(start(started, false)));
+  std::thread::sleep(Duration::from_millis(500));
+
+  let mut connected: Vec<Client> = tokens
+    .into_iter()
+    .map(|(username, token)| Client::connect(username, &token))
+    .collect();
+  let rest = connected.split_off(1);
+  let one = run(connected);
+  for mut c in run(one.into_iter().chain(rest).collect()) {
+    // wait for the server to answer the close, such that it does not see an eof
+    let _ = c.socket.close(None);
+    while c.socket.read().is_ok() {}
+  }
+
+  for suffix in ["", "-wal", "-shm"] {
+    let _ = std::fs::remove_file(format!("{}{}", db.display(), suffix));
+  }
+}
@@ -0,0 +1,83 @@
+CREATE TABLE `session` (
+    `id` int NOT NULL AUTO_INCREMENT COMMENT 'Primary Key',
+    `user_id` int NOT NULL COMMENT 'User foreign key',
+    `token` varchar(64) NOT NULL COMMENT 'sha256 of the session token',
+    `refresh_token` varchar(64) NOT NULL COMMENT 'sha256 of the refresh token',
+    `user_agent` varchar(255) DEFAULT NULL COMMENT 'User agent when session created',
+    `create_t` bigint NOT NULL COMMENT 'Create time in milliseconds',
+    `expire_t` bigint NOT NULL COMMENT 'Session token expire time in milliseconds',
+    `refresh_expire_t` bigint NOT NULL COMMENT 'Refresh token expire time in milliseconds',
+    `revoked` tinyint(1) NOT NULL DEFAULT 0 COMMENT 'Revoked by logout or refresh',
+    PRIMARY KEY (`id`),
+    UNIQUE KEY `token` (`token`),
+    UNIQUE KEY `refresh_token` (`refresh_token`),
+    KEY `user_id` (`user_id`),
+    FOREIGN KEY (`user_id`) REFERENCES `user` (`id`)
+) COMMENT '';
+
+CREATE TABLE `user_totp` (
+    `id` int NOT NULL AUTO_INCREMENT COMMENT 'Primary Key',
+    `user_id` int NOT NULL COMMENT 'User foreign key',
+    `secret` varchar(64) NOT NULL COMMENT 'Base32 totp secret',
+    `enabled` tinyint(1) NOT NULL DEFAULT 0 COMMENT 'Set after the first code is confirmed',
+    `recovery_codes` TEXT DEFAULT NULL COMMENT 'Json list of sha256 of unused recovery codes',
+    `last_used_step` bigint NOT NULL DEFAULT 0 COMMENT 'Last accepted time step',
+    PRIMARY KEY (`id`),
+    UNIQUE KEY `user_id` (`user_id`),
+    FOREIGN KEY (`user_id`) REFERENCES `user` (`id`)
+) COMMENT '';
+
+CREATE TABLE `role_policy` (
+    `usertype` varchar(16) NOT NULL COMMENT 'Master, Manager, Member, User, Visiter',
+    `require_totp` tinyint(1) NOT NULL DEFAULT 0 COMMENT 'Users of this type must enroll totp',
+    PRIMARY KEY (`usertype`)
+) COMMENT '';
+
+CREATE TABLE `login_attempt` (
+    `attempt_key` varchar(128) NOT NULL COMMENT 'ip:<addr>, user:<username> or signup:<addr>',
+    `failures` int NOT NULL DEFAULT 0 COMMENT 'Failures since last success',
+    `last_failure_t` bigint NOT NULL DEFAULT 0 COMMENT 'Last failure time in milliseconds',
+    `lock_until_t` bigint NOT NULL DEFAULT 0 COMMENT 'Locked until this time in milliseconds',
+    PRIMARY KEY (`attempt_key`)
+) COMMENT '';
+
+CREATE TABLE `invitation` (
+    `id` int NOT NULL AUTO_INCREMENT COMMENT 'Primary Key',
+    `code` varchar(64) NOT NULL COMMENT 'sha256 of the invitation code',
+    `usertype` varchar(16) NOT NULL COMMENT 'Type of users created with this code',
+    `quota` int NOT NULL COMMENT 'How many users can be created with this code',
+    `used` int NOT NULL DEFAULT 0 COMMENT 'How many users have been created',
+    `creator_id` int NOT NULL COMMENT 'User foreign key of the manager who created it',
+    `create_t` bigint NOT NULL COMMENT 'Create time in milliseconds',
+    `expire_t` bigint NOT NULL DEFAULT 0 COMMENT 'Expire time in milliseconds, 0 means never',
+    PRIMARY KEY (`id`),
+    UNIQUE KEY `code` (`code`),
+    FOREIGN KEY (`creator_id`) REFERENCES `user` (`id`)
+) COMMENT '';
+
+CREATE TABLE `api_key` (
+    `id` int NOT NULL AUTO_INCREMENT COMMENT 'Primary Key',
+    `user_id` int NOT NULL COMMENT 'User foreign key',
+    `name` varchar(64) NOT NULL COMMENT 'Name given by the owner',
+    `key_hash` varchar(64) NOT NULL COMMENT 'sha256 of the api key',
+    `prefix` varchar(16) NOT NULL COMMENT 'Beginning of the key, shown to the owner',
+    `scopes` varchar(255) NOT NULL COMMENT 'Json list of Read, Upload, Delete, Share',
+    `create_t` bigint NOT NULL COMMENT 'Create time in milliseconds',
+    `expire_t` bigint NOT NULL DEFAULT 0 COMMENT 'Expire time in milliseconds, 0 means never',
+    `last_used_t` bigint NOT NULL DEFAULT 0 COMMENT 'Last used time in milliseconds',
+    PRIMARY KEY (`id`),
+    UNIQUE KEY `key_hash` (`key_hash`),
+    KEY `user_id` (`user_id`),
+    FOREIGN KEY (`user_id`) REFERENCES `user` (`id`)
+) COMMENT '';
+
+ALTER TABLE `user_file_action`
+    MODIFY `action` varchar(32) DEFAULT NULL COMMENT 'upload, download, delete, share, login',
+    ADD `ip` varchar(64) DEFAULT NULL COMMENT 'Client ip of the action' AFTER `action`,
+    ADD `session_id` int DEFAULT NULL COMMENT 'Session of the action, if done with a login' AFTER `ip`,
+    ADD `api_key_id` int DEFAULT NULL COMMENT 'Api key of the action, if done with a key' AFTER `session_id`,
+    ADD `create_t` bigint NOT NULL DEFAULT 0 COMMENT 'Create time in milliseconds' AFTER `api_key_id`,
+    ADD KEY `create_t` (`create_t`);
+
+ALTER TABLE `user`
+    MODIFY `type` varchar(16) NOT NULL COMMENT 'Master, Manager, Member, User, Visiter, decides user rights';
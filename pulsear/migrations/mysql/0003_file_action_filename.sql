@@ -0,0 +1,2 @@
+ALTER TABLE `user_file_action`
+    MODIFY `filename` varchar(255) DEFAULT NULL COMMENT 'Name of the file';
@@ -0,0 +1,104 @@
+-- the schema of mysql migration 3. there are no triggers, every statement
+-- on user sets the times which before_user_insert and before_user_update set
+
+CREATE TABLE "user" (
+    id SERIAL PRIMARY KEY,
+    username varchar(16) NOT NULL UNIQUE,
+    token varchar(255) NOT NULL UNIQUE,
+    register_time timestamp NOT NULL,
+    last_updated_time timestamp NOT NULL,
+    last_login_time timestamp NOT NULL,
+    -- Master, Manager, Member, User, Visiter, decides user rights
+    type varchar(16) NOT NULL
+);
+
+CREATE TABLE user_config (
+    id SERIAL PRIMARY KEY,
+    user_id int NOT NULL REFERENCES "user" (id),
+    theme varchar(16) DEFAULT NULL,
+    web_worker_num int DEFAULT 4,
+    -- map path to its config
+    filelist_config TEXT DEFAULT NULL
+);
+CREATE INDEX user_config_user_id ON user_config (user_id);
+
+CREATE TABLE user_file_action (
+    id SERIAL PRIMARY KEY,
+    user_id int NOT NULL REFERENCES "user" (id),
+    filename varchar(255) DEFAULT NULL,
+    filepath varchar(255) DEFAULT NULL,
+    -- upload, download, delete, share, login
+    action varchar(32) DEFAULT NULL,
+    ip varchar(64) DEFAULT NULL,
+    session_id int DEFAULT NULL,
+    api_key_id int DEFAULT NULL,
+    -- milliseconds
+    create_t bigint NOT NULL DEFAULT 0,
+    create_time timestamp NOT NULL
+);
+CREATE INDEX user_file_action_user_id ON user_file_action (user_id);
+CREATE INDEX user_file_action_create_t ON user_file_action (create_t);
+
+CREATE TABLE session (
+    id SERIAL PRIMARY KEY,
+    user_id int NOT NULL REFERENCES "user" (id),
+    -- sha256 of the session and refresh token
+    token varchar(64) NOT NULL UNIQUE,
+    refresh_token varchar(64) NOT NULL UNIQUE,
+    user_agent varchar(255) DEFAULT NULL,
+    create_t bigint NOT NULL,
+    expire_t bigint NOT NULL,
+    refresh_expire_t bigint NOT NULL,
+    revoked boolean NOT NULL DEFAULT false
+);
+CREATE INDEX session_user_id ON session (user_id);
+
+CREATE TABLE user_totp (
+    id SERIAL PRIMARY KEY,
+    user_id int NOT NULL UNIQUE REFERENCES "user" (id),
+    secret varchar(64) NOT NULL,
+    enabled boolean NOT NULL DEFAULT false,
+    -- json list of sha256 of unused recovery codes
+    recovery_codes TEXT DEFAULT NULL,
+    last_used_step bigint NOT NULL DEFAULT 0
+);
+
+CREATE TABLE role_policy (
+    usertype varchar(16) NOT NULL PRIMARY KEY,
+    require_totp boolean NOT NULL DEFAULT false
+);
+
+CREATE TABLE login_attempt (
+    -- ip:<addr>, user:<username> or signup:<addr>
+    attempt_key varchar(128) NOT NULL PRIMARY KEY,
+    failures int NOT NULL DEFAULT 0,
+    last_failure_t bigint NOT NULL DEFAULT 0,
+    lock_until_t bigint NOT NULL DEFAULT 0
+);
+
+CREATE TABLE invitation (
+    id SERIAL PRIMARY KEY,
+    -- sha256 of the invitation code
+    code varchar(64) NOT NULL UNIQUE,
+    usertype varchar(16) NOT NULL,
+    quota int NOT NULL,
+    used int NOT NULL DEFAULT 0,
+    creator_id int NOT NULL REFERENCES "user" (id),
+    create_t bigint NOT NULL,
+    expire_t bigint NOT NULL DEFAULT 0
+);
+
+CREATE TABLE api_key (
+    id SERIAL PRIMARY KEY,
+    user_id int NOT NULL REFERENCES "user" (id),
+    name varchar(64) NOT NULL,
+    -- sha256 of the api key
+    key_hash varchar(64) NOT NULL UNIQUE,
+    prefix varchar(16) NOT NULL,
+    -- json list of Read, Upload, Delete, Share
+    scopes varchar(255) NOT NULL,
+    create_t bigint NOT NULL,
+    expire_t bigint NOT NULL DEFAULT 0,
+    last_used_t bigint NOT NULL DEFAULT 0
+);
+CREATE INDEX api_key_user_id ON api_key (user_id);
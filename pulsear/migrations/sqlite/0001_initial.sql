@@ -0,0 +1,110 @@
+-- the schema of mysql migration 3, times are set by column defaults and triggers
+
+CREATE TABLE `user` (
+    `id` INTEGER PRIMARY KEY AUTOINCREMENT,
+    `username` varchar(16) NOT NULL UNIQUE,
+    `token` varchar(255) NOT NULL UNIQUE,
+    `register_time` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    `last_updated_time` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    `last_login_time` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- Master, Manager, Member, User, Visiter, decides user rights
+    `type` varchar(16) NOT NULL
+);
+
+CREATE TABLE `user_config` (
+    `id` INTEGER PRIMARY KEY AUTOINCREMENT,
+    `user_id` int NOT NULL REFERENCES `user` (`id`),
+    `theme` varchar(16) DEFAULT NULL,
+    `web_worker_num` int DEFAULT 4,
+    -- map path to its config
+    `filelist_config` TEXT DEFAULT NULL
+);
+CREATE INDEX `user_config_user_id` ON `user_config` (`user_id`);
+
+CREATE TABLE `user_file_action` (
+    `id` INTEGER PRIMARY KEY AUTOINCREMENT,
+    `user_id` int NOT NULL REFERENCES `user` (`id`),
+    `filename` varchar(255) DEFAULT NULL,
+    `filepath` varchar(255) DEFAULT NULL,
+    -- upload, download, delete, share, login
+    `action` varchar(32) DEFAULT NULL,
+    `ip` varchar(64) DEFAULT NULL,
+    `session_id` int DEFAULT NULL,
+    `api_key_id` int DEFAULT NULL,
+    -- milliseconds
+    `create_t` bigint NOT NULL DEFAULT 0,
+    `create_time` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX `user_file_action_user_id` ON `user_file_action` (`user_id`);
+CREATE INDEX `user_file_action_create_t` ON `user_file_action` (`create_t`);
+
+CREATE TABLE `session` (
+    `id` INTEGER PRIMARY KEY AUTOINCREMENT,
+    `user_id` int NOT NULL REFERENCES `user` (`id`),
+    -- sha256 of the session and refresh token
+    `token` varchar(64) NOT NULL UNIQUE,
+    `refresh_token` varchar(64) NOT NULL UNIQUE,
+    `user_agent` varchar(255) DEFAULT NULL,
+    `create_t` bigint NOT NULL,
+    `expire_t` bigint NOT NULL,
+    `refresh_expire_t` bigint NOT NULL,
+    `revoked` tinyint(1) NOT NULL DEFAULT 0
+);
+CREATE INDEX `session_user_id` ON `session` (`user_id`);
+
+CREATE TABLE `user_totp` (
+    `id` INTEGER PRIMARY KEY AUTOINCREMENT,
+    `user_id` int NOT NULL UNIQUE REFERENCES `user` (`id`),
+    `secret` varchar(64) NOT NULL,
+    `enabled` tinyint(1) NOT NULL DEFAULT 0,
+    -- json list of sha256 of unused recovery codes
+    `recovery_codes` TEXT DEFAULT NULL,
+    `last_used_step` bigint NOT NULL DEFAULT 0
+);
+
+CREATE TABLE `role_policy` (
+    `usertype` varchar(16) NOT NULL PRIMARY KEY,
+    `require_totp` tinyint(1) NOT NULL DEFAULT 0
+);
+
+CREATE TABLE `login_attempt` (
+    -- ip:<addr>, user:<username> or signup:<addr>
+    `attempt_key` varchar(128) NOT NULL PRIMARY KEY,
+    `failures` int NOT NULL DEFAULT 0,
+    `last_failure_t` bigint NOT NULL DEFAULT 0,
+    `lock_until_t` bigint NOT NULL DEFAULT 0
+);
+
+CREATE TABLE `invitation` (
+    `id` INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- sha256 of the invitation code
+    `code` varchar(64) NOT NULL UNIQUE,
+    `usertype` varchar(16) NOT NULL,
+    `quota` int NOT NULL,
+    `used` int NOT NULL DEFAULT 0,
+    `creator_id` int NOT NULL REFERENCES `user` (`id`),
+    `create_t` bigint NOT NULL,
+    `expire_t` bigint NOT NULL DEFAULT 0
+);
+
+CREATE TABLE `api_key` (
+    `id` INTEGER PRIMARY KEY AUTOINCREMENT,
+    `user_id` int NOT NULL REFERENCES `user` (`id`),
+    `name` varchar(64) NOT NULL,
+    -- sha256 of the api key
+    `key_hash` varchar(64) NOT NULL UNIQUE,
+    `prefix` varchar(16) NOT NULL,
+    -- json list of Read, Upload, Delete, Share
+    `scopes` varchar(255) NOT NULL,
+    `create_t` bigint NOT NULL,
+    `expire_t` bigint NOT NULL DEFAULT 0,
+    `last_used_t` bigint NOT NULL DEFAULT 0
+);
+CREATE INDEX `api_key_user_id` ON `api_key` (`user_id`);
+
+-- recursive triggers are off, so updating the row again does not fire it
+CREATE TRIGGER `after_user_update` AFTER UPDATE ON `user`
+FOR EACH ROW
+BEGIN
+    UPDATE `user` SET last_updated_time = CURRENT_TIMESTAMP WHERE id = NEW.id;
+END;
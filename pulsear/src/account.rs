@@ -0,0 +1,260 @@
+use crate::*;
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct ChangePasswordRequest {
+  pub old_password: String,
+  pub new_password: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct RenameRequest {
+  pub new_username: String,
+  pub password: String,
+}
+
+/// the user config besides file list views, which are set per path
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct PreferencesRequest {
+  pub theme: String,
+  pub web_worker_num: i32,
+}
+
+/// what happens to the files, links and history of a deleted account
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone, Copy, PartialEq)]
+pub enum AccountDeleteMode {
+  // removed from disk and database
+  Purge,
+  // moved to inner/archive/<username>_<time>, then removed from database
+  Archive,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct DeleteAccountRequest {
+  pub password: String,
+  pub mode: AccountDeleteMode,
+}
+
+/// a download link of an archived account
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct ArchivedLink {
+  pub code: String,
+  pub filename: String,
+}
+
+/// written as account.json next to the archived files
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct AccountArchive {
+  pub username: String,
+  pub usertype: UserType,
+  pub archive_t: u64,
+  pub links: Vec<ArchivedLink>,
+  pub file_actions: Vec<FileAction>,
+}
+
+pub fn user_storage_dir(username: &str) -> std::path::PathBuf {
+  std::path::PathBuf::from("inner/storage").join(username)
+}
+
+fn check_password(user: &User, password: &str) -> Result<(), Err> {
+  if password_token(&user.username, password) != user.token {
+    return Err(ApiError::err(ErrorCode::Forbidden, "password not true"));
+  }
+  Ok(())
+}
+
+pub fn check_password_len(password: &str) -> Result<(), Err> {
+  if !(4..=16).contains(&password.chars().count()) {
+    return Err(ApiError::err(ErrorCode::InvalidRequest, "password length must be 4~16"));
+  }
+  Ok(())
+}
+
+pub fn do_set_preferences(sqlhandler: &SqlHandler, username: &str, param: &PreferencesRequest)
+  -> Result<(), Err> {
+  if !["dark", "light"].contains(&param.theme.as_str()) {
+    return Err(ApiError::err(ErrorCode::InvalidRequest, format!("unknown theme: {}", param.theme)));
+  }
+  if !(1..=16).contains(&param.web_worker_num) {
+    return Err(ApiError::err(ErrorCode::InvalidRequest, "web_worker_num must be 1~16"));
+  }
+  let mut config = match sqlhandler.get_user_by_name(username)? {
+    Some(u) => u.config,
+    None => return Err(ApiError::err(ErrorCode::NotFound, "user not exists")),
+  };
+  config.theme = param.theme.clone();
+  config.web_worker_num = param.web_worker_num;
+  sqlhandler.update_user_config_by_name(username, &config)
+}
+
+/// other sessions of the user are revoked and their websockets closed,
+/// api keys are kept
+pub fn do_change_password(
+  auth: &AuthUser,
+  param: &ChangePasswordRequest,
+  data: &web::Data<Arc<Server>>,
+) -> Result<(), Err> {
+  let session_id = match &auth.credential {
+    Credential::Session(s) => s.id,
+    Credential::ApiKey(_) => return Err(ApiError::err(ErrorCode::Forbidden, "need a login session")),
+  };
+  check_password(&auth.user, &param.old_password)?;
+  check_password_len(&param.new_password)?;
+  let sqlhandler = data.sqlhandler.clone();
+  sqlhandler.update_user_token(auth.user.id, &password_token(&auth.user.username, &param.new_password))?;
+  sqlhandler.revoke_user_sessions_except(auth.user.id, session_id)?;
+  data.close_user_ctxs(&auth.user.username, Some(&auth.token_hash), "password changed");
+  log::info!("user {} changed password", auth.user.username);
+  Ok(())
+}
+
+/// files and download links move to the new name, every session has to login again
+pub fn do_rename(
+  auth: &AuthUser,
+  param: &RenameRequest,
+  data: &web::Data<Arc<Server>>,
+) -> Result<(), Err> {
+  auth.require_session()?;
+  check_password(&auth.user, &param.password)?;
+  check_signup_username(&param.new_username)?;
+  let sqlhandler = data.sqlhandler.clone();
+  if sqlhandler.get_user_by_name(&param.new_username)?.is_some() {
+    return Err(ApiError::err(ErrorCode::Conflict, "user exists"));
+  }
+  let old_dir = user_storage_dir(&auth.user.username);
+  let new_dir = user_storage_dir(&param.new_username);
+  if new_dir.exists() {
+    return Err(ApiError::err(ErrorCode::Conflict, "storage of new username exists"));
+  }
+  if old_dir.exists() {
+    std::fs::rename(&old_dir, &new_dir)?;
+  }
+  let token = password_token(&param.new_username, &param.password);
+  if let Err(e) = sqlhandler.update_username(auth.user.id, &param.new_username, &token) {
+    if new_dir.exists() {
+      std::fs::rename(&new_dir, &old_dir)?;
+    }
+    return Err(e);
+  }
+  data.file_handler.rename_user_codes(&auth.user.username, &param.new_username);
+  sqlhandler.revoke_user_sessions(auth.user.id)?;
+  data.close_user_ctxs(&auth.user.username, None, "username changed");
+  log::info!("user {} renamed to {}", auth.user.username, param.new_username);
+  Ok(())
+}
+
+/// remove the account, its files, links and file actions are purged or archived
+/// according to the mode
+pub fn do_delete_account(
+  auth: &AuthUser,
+  param: &DeleteAccountRequest,
+  data: &web::Data<Arc<Server>>,
+) -> Result<(), Err> {
+  auth.require_session()?;
+  check_password(&auth.user, &param.password)?;
+  let sqlhandler = data.sqlhandler.clone();
+  let username = &auth.user.username;
+  let storage = user_storage_dir(username);
+  let now = Time::now().milli();
+  let archive = std::path::PathBuf::from("inner/archive").join(format!("{}_{}", username, now));
+  let file_actions = match param.mode {
+    AccountDeleteMode::Archive => sqlhandler.get_user_file_actions(auth.user.id)?,
+    AccountDeleteMode::Purge => vec![],
+  };
+  // the files are moved aside first and back if the rows stay, such that
+  // a failed delete loses nothing and a new user of the name finds no files
+  let (aside, moved) = match param.mode {
+    AccountDeleteMode::Purge => {
+      let trash = std::path::PathBuf::from("inner/trash");
+      (trash.clone(), trash.join(format!("{}_{}", username, now)))
+    }
+    AccountDeleteMode::Archive => (archive.clone(), archive.join("files")),
+  };
+  if storage.exists() {
+    std::fs::create_dir_all(&aside)?;
+    std::fs::rename(&storage, &moved)?;
+  }
+  if let Err(e) = sqlhandler.delete_user_by_name(username) {
+    if moved.exists() {
+      std::fs::rename(&moved, &storage)?;
+    }
+    return Err(e);
+  }
+  data.close_user_ctxs(username, None, "account deleted");
+  // the account is gone from here on, what is left over is only logged
+  if let Err(e) = sqlhandler.delete_login_attempt(&LoginAttempt::user_key(username)) {
+    log::warn!("login attempts of deleted user {} stay: {}", username, e);
+  }
+  let links = data.file_handler.remove_user_codes(username);
+  match param.mode {
+    AccountDeleteMode::Purge => {
+      if moved.exists() {
+        if let Err(e) = std::fs::remove_dir_all(&moved) {
+          log::warn!("files of deleted user {} stay in {}: {}", username, moved.display(), e);
+        }
+      }
+    }
+    AccountDeleteMode::Archive => {
+      let account = AccountArchive {
+        username: username.clone(),
+        usertype: auth.user.usertype.clone(),
+        archive_t: now,
+        links: links
+          .into_iter()
+          .map(|(code, filename)| ArchivedLink { code, filename })
+          .collect(),
+        file_actions,
+      };
+      let written = std::fs::create_dir_all(&archive).map_err(Err::from).and_then(|_| {
+        Ok(std::fs::write(archive.join("account.json"), serde_json::to_string_pretty(&account)?)?)
+      });
+      match written {
+        Ok(()) => log::info!("user {} archived to {}", username, archive.display()),
+        Err(e) => log::warn!("account of deleted user {} not archived: {}", username, e),
+      }
+    }
+  }
+  log::info!("user {} deleted own account with {:?}", username, param.mode);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn delete_account() {
+    let server = crate::auth::tests::TestServer::new(&String::from("error"), &String::from("127.0.0.1:0"));
+    let data = web::Data::new(server.server.clone());
+    let sqlhandler = &server.server.sqlhandler;
+    let username = "leaving0";
+    let user = sqlhandler.add_user(&User {
+      id: 0,
+      username: username.into(),
+      token: password_token(username, username),
+      config: UserConfig::default(),
+      usertype: UserType::default(),
+    }).unwrap().unwrap();
+    let issued = issue_session(sqlhandler, &user, "", &server.server.r_config()).unwrap();
+    let storage = user_storage_dir(username);
+    std::fs::create_dir_all(&storage).unwrap();
+    std::fs::write(storage.join("a.txt"), b"a").unwrap();
+    let user_key = LoginAttempt::user_key(username);
+    record_login_failure(sqlhandler, std::slice::from_ref(&user_key), &LoginThrottleConfig::default()).unwrap();
+    let auth = AuthUser {
+      user,
+      credential: Credential::Session(issued.session),
+      token_hash: hash_session_token(&issued.token),
+    };
+    let param = |password: &str| DeleteAccountRequest {
+      password: password.into(),
+      mode: AccountDeleteMode::Purge,
+    };
+
+    assert!(do_delete_account(&auth, &param("wrong"), &data).is_err());
+    assert!(storage.join("a.txt").exists());
+    do_delete_account(&auth, &param(username), &data).unwrap();
+    assert!(sqlhandler.get_user_by_name(username).unwrap().is_none());
+    assert!(sqlhandler.get_login_attempt(&user_key).unwrap().is_none());
+    assert!(!storage.exists());
+  }
+}
@@ -1,72 +1,79 @@
 use crate::*;
+use actix_web::ResponseError;
 
 pub async fn ws(
+  auth: AuthUser,
   req: HttpRequest,
   stream: web::Payload,
   data: web::Data<Arc<Server>>,
 ) -> Result<HttpResponse, actix_web::Error> {
-  log::info!("ws request from a user: {:?}", req);
-  ws::start(
-    WsSession {
-      server: data.get_ref().clone(),
-      hb_t: Time::now(),
-      user_ctx: UserCtx {
-        establish_t: Time::now(),
-        user_agent: req
-          .headers()
-          .get("user-agent")
-          .unwrap()
-          .to_str()
-          .unwrap()
-          .to_string(),
-        username: String::new(),
-        token: String::new(),
-        session: None,
-      },
+  // not the request itself, its query holds the token
+  log::info!("ws request to {} from {}", req.path(), auth.user.username);
+  auth.require_scope(ApiScope::Read)?;
+  let scopes = auth.scopes();
+  let client = ClientInfo::of(&req);
+  let max_frame_size = data.r_config().websocket.max_frame_size;
+  let session = WsSession {
+    server: data.get_ref().clone(),
+    hb_t: Time::now(),
+    scopes,
+    usertype: auth.user.usertype,
+    protocol: None,
+    fragments: None,
+    user_ctx: UserCtx {
+      establish_t: Time::now(),
+      user_agent: client.user_agent,
+      ip: client.ip,
+      username: auth.user.username,
+      token: auth.token_hash,
+      session: None,
     },
-    &req,
-    stream,
-  )
+  };
+  ws::WsResponseBuilder::new(session, &req, stream)
+    .frame_size(max_frame_size)
+    .start()
 }
 
 #[post("/download_raw")]
-pub async fn download_raw(param: web::Json<DownloadRequest>, data: web::Data<Arc<Server>>) 
-  -> Result<NamedFile, Err> {
-  let sqlhandler = SqlHandler::new(data.dbpool.clone());
-  match sqlhandler.get_user_by_name(&param.username)? {
-    Some(u) => {
-      assert_eq!(&u.username, &param.username);
-      assert_eq!(&u.token, &param.token);
-    }
-    None => {
-      return Err(Box::from("user not exists"));
-    }
-  };
+pub async fn download_raw(
+  auth: AuthUser,
+  req: HttpRequest,
+  param: web::Json<DownloadRequest>,
+  data: web::Data<Arc<Server>>
+) -> Result<NamedFile, actix_web::Error> {
+  auth.require_scope(ApiScope::Read)?;
+  check_file_name(&param.name).map_err(|e| ApiError::of(e, ErrorCode::InvalidName))?;
   let storage = std::path::PathBuf::from("inner/storage");
-  let userfile_path = storage.join(&param.username).join(&param.name);
-  Ok(NamedFile::open(userfile_path)?)
+  let userfile_path = storage.join(&auth.user.username).join(&param.name);
+  let file = NamedFile::open(userfile_path)?;
+  let actor = AuditActor::from_auth(&auth, &req);
+  let name = param.into_inner().name;
+  data.sqlhandler.detach(move |sql| record_action(sql, &actor, FileActionKind::Download, &name));
+  Ok(file)
 }
 
 #[post("/get_download_url")]
-pub async fn get_download_url(param: web::Json<DownloadRequest>, data: web::Data<Arc<Server>>) 
-  -> Result<HttpResponse, Err> {
-  let sqlhandler = SqlHandler::new(data.dbpool.clone());
-  match sqlhandler.get_user_by_name(&param.username)? {
-    Some(u) => {
-      assert_eq!(&u.username, &param.username);
-      assert_eq!(&u.token, &param.token);
-    }
-    None => {
-      return Err(Box::from("user not exists"));
-    }
-  };
-  let code = data.file_handler.gen_download_code(param.into_inner());
+pub async fn get_download_url(
+  auth: AuthUser, 
+  req: HttpRequest,
+  param: web::Json<DownloadRequest>, 
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  auth.require(Capability::CreatePublicLink)?;
+  check_file_name(&param.name)?;
+  let actor = AuditActor::from_auth(&auth, &req);
+  let name = param.name.clone();
+  data.sqlhandler.detach(move |sql| record_action(sql, &actor, FileActionKind::Share, &name));
+  let code = data.file_handler.gen_download_code(&auth.user.username, param.into_inner());
   Ok(HttpResponse::Ok().body(code))
 }
 
 #[get("/download/{username}/{code}")]
-pub async fn download_by_url(p: web::Path<(String, String)>, data: web::Data<Arc<Server>>) 
-  -> Result<NamedFile, Err> {
+pub async fn download_by_url(
+  req: HttpRequest,
+  p: web::Path<(String, String)>,
+  data: web::Data<Arc<Server>>
+) -> Result<NamedFile, ApiError> {
   log::info!("download by url: download/{}/{}", p.as_ref().0, p.as_ref().1);
   let param: (String, String) = p.into_inner();
   let username = param.0;
@@ -74,70 +81,126 @@ pub async fn download_by_url(p: web::Path<(String, String)>, data: web::Data<Arc
   let filename = match data.file_handler.from_download_code(&code) {
     Some(p) => {
       if username != p.0 {
-        return Err(Box::from("unexpected"));
+        return Err(ApiError::new(ErrorCode::NotFound, "download link not valid"));
       }
       p.1
     },
     None => {
-      return Err(Box::from("unexpected"));
+      return Err(ApiError::new(ErrorCode::NotFound, "download link not valid"));
     }
   };
   let storage = std::path::PathBuf::from("inner/storage");
   let userfile_path = storage.join(&username).join(&filename);
-  Ok(NamedFile::open(userfile_path)?)
+  let file = NamedFile::open(userfile_path)?;
+  // the row belongs to the owner, the downloader is anonymous
+  let ip = peer_ip(&req);
+  data.sqlhandler.detach(move |sql| match sql.get_user_by_name(&username) {
+    Ok(Some(owner)) => {
+      let actor = AuditActor {
+        user_id: owner.id,
+        ip,
+        session_id: None,
+        api_key_id: None,
+      };
+      record_action(sql, &actor, FileActionKind::Download, &filename);
+    }
+    Ok(None) => (),
+    Err(e) => log::error!("record download of {} error: {}", username, e),
+  });
+  Ok(file)
 }
 
 #[post("/delete_file")]
-pub async fn delete_file(param: web::Json<DeleteFileRequest>, data: web::Data<Arc<Server>>) 
-  -> Result<HttpResponse, Err> {
-  log::info!("user try delete file: {}", serde_json::to_string(&param).unwrap());
-  let sqlhandler = SqlHandler::new(data.dbpool.clone());
-  match sqlhandler.get_user_by_name(&param.username)? {
-    Some(u) => {
-      assert_eq!(&u.username, &param.username);
-      assert_eq!(&u.token, &param.token);
-    }
-    None => {
-      return Err(Box::from("user not exists"));
-    }
-  }
-  data.file_handler.delete_file(param.0)?;
+pub async fn delete_file(
+  auth: AuthUser, 
+  req: HttpRequest,
+  param: web::Json<DeleteFileRequest>, 
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  auth.require_scope(ApiScope::Delete)?;
+  log::info!("user {} try delete file: {}", auth.user.username, serde_json::to_string(&param).unwrap());
+  let name = param.name.clone();
+  data.file_handler.delete_file(&auth.user.username, param.0)?;
+  let actor = AuditActor::from_auth(&auth, &req);
+  data.sqlhandler.detach(move |sql| record_action(sql, &actor, FileActionKind::Delete, &name));
   Ok(HttpResponse::Ok().body(""))
 }
 
 
+/// a page of files, sorted and with the columns of the view of the path.
+/// with `Accept: application/x-ndjson` all files from the cursor on are streamed
+/// instead, one json per line: first {path, config}, then each file
 #[post("/files")]
-pub async fn get_file_list(param: web::Json<FileListRequest>, data: web::Data<Arc<Server>>) -> HttpResponse {
-  log::info!("user try get file list: {}", serde_json::to_string(&param).unwrap());
-  let resp: HttpResponse;
-  match do_get_file_list(param, data) {
-    Ok(response) => resp = response,
-    Err(e) => {
-      resp = HttpResponse::BadRequest().body(e.to_string());
-    }
+pub async fn get_file_list(
+  auth: AuthUser, 
+  req: HttpRequest, 
+  param: web::Json<FileListRequest>
+) -> HttpResponse {
+  if let Err(e) = auth.require_scope(ApiScope::Read) {
+    return e.error_response();
+  }
+  log::info!("user {} try get file list: {}", auth.user.username, serde_json::to_string(&param).unwrap());
+  let ndjson = req
+    .headers()
+    .get(actix_web::http::header::ACCEPT)
+    .and_then(|v| v.to_str().ok())
+    .is_some_and(|v| v.contains("application/x-ndjson"));
+  let param = param.into_inner();
+  let path = param.path.clone();
+  let limit = param.limit;
+  let (view, entries) = match blocking(move || file_list_entries(&auth.user, &param)).await {
+    Ok(r) => r,
+    Err(e) => return ApiError::of(e, ErrorCode::InvalidRequest).error_response(),
+  };
+  if ndjson {
+    return HttpResponse::Ok()
+      .content_type("application/x-ndjson")
+      .streaming(stream_file_list(path, view, entries));
   }
+  let resp = match blocking(move || file_list_page(path, view, entries, limit)).await {
+    Ok(list) => HttpResponse::Ok().json(list),
+    Err(e) => ApiError::of(e, ErrorCode::InvalidRequest).error_response(),
+  };
   log::debug!("Server get file resp with {:?}", resp);
   resp 
 }
 
-#[post("/file")]
-pub async fn get_file_elem(param: web::Json<FileElemRequest>, data: web::Data<Arc<Server>>) 
-  -> Result<HttpResponse, Err> {
-  log::info!("user try get file elem: {}", serde_json::to_string(&param).unwrap());
-  let sqlhandler = SqlHandler::new(data.dbpool.clone());
-  let user = match sqlhandler.get_user_by_name(&param.username)? {
-    Some(u) => {
-      assert_eq!(&u.username, &param.username);
-      assert_eq!(&u.token, &param.token);
-      u
-    }
-    None => {
-      return Err(Box::from("user not exists"));
-    }
-  };
+#[get("/files/view")]
+pub async fn get_file_list_view(auth: AuthUser, query: web::Query<FileListViewQuery>) -> HttpResponse {
+  if let Err(e) = auth.require_scope(ApiScope::Read) {
+    return e.error_response();
+  }
+  HttpResponse::Ok().json(FileListView {
+    config: auth.user.config.filelist_config.get(&query.path),
+    path: query.into_inner().path,
+  })
+}
+
+#[post("/files/view")]
+pub async fn set_file_list_view(
+  auth: AuthUser,
+  param: web::Json<FileListView>,
+  data: web::Data<Arc<Server>>
+) -> HttpResponse {
+  if let Err(e) = auth.require_session() {
+    return e.error_response();
+  }
+  let view = param.into_inner();
+  let (username, saved) = (auth.user.username, view.clone());
+  match data.sqlhandler.run(move |sql| save_file_list_view(sql, &username, &saved)).await {
+    Ok(()) => HttpResponse::Ok().json(view),
+    Err(e) => ApiError::of(e, ErrorCode::InvalidRequest).error_response(),
+  }
+}
 
+#[post("/file")]
+pub async fn get_file_elem(auth: AuthUser, param: web::Json<FileElemRequest>) 
+  -> Result<HttpResponse, ApiError> {
+  auth.require_scope(ApiScope::Read)?;
+  check_file_name(&param.name)?;
+  log::info!("user {} try get file elem: {}", auth.user.username, serde_json::to_string(&param).unwrap());
   let storage = std::path::PathBuf::from("inner/storage");
-  let userfile = storage.join(&user.username).join(&param.name);
+  let userfile = storage.join(&auth.user.username).join(&param.name);
   let file = std::fs::File::open(userfile)?;
   Ok(HttpResponse::Ok().body(serde_json::to_string(
     &FileListElem::from_name_and_metadata(param.into_inner().name, file.metadata()?)?
@@ -146,36 +209,466 @@ pub async fn get_file_elem(param: web::Json<FileElemRequest>, data: web::Data<Ar
 
 /// login, if username does not exist, signup and login.
 #[post("/login")]
-pub async fn login(param: web::Json<LoginRequest>, data: web::Data<Arc<Server>>) -> HttpResponse {
-  let resp: HttpResponse;
-  match do_login(&param, &data) {
-    Ok(response) => resp = HttpResponse::Ok().json(response),
-    Err(e) => {
-      // TODO: add more http status code
-      let mut response = LoginResponse::default();
-      response.code = ResponseCode::Err(e.to_string());
-      resp = HttpResponse::Ok().json(response)
-    }
-  }
+pub async fn login(req: HttpRequest, param: web::Json<LoginRequest>, data: web::Data<Arc<Server>>) 
+  -> HttpResponse {
+  let client = ClientInfo::of(&req);
+  let (c, d) = (client.clone(), data.clone());
+  let resp = match blocking(move || do_login(&c, &param, &d)).await {
+    Ok(response) => login_ok_response(&client, response, &data),
+    Err(e) => login_err_response(e),
+  };
   log::debug!("Server login resp with {:?}", resp);
   resp
 }
 
+/// set the session cookie and record the login, if a session is issued
+fn login_ok_response(client: &ClientInfo, response: LoginResponse, data: &web::Data<Arc<Server>>)
+  -> HttpResponse {
+  let mut resp = HttpResponse::Ok();
+  // a pending totp step has no token yet, the cookie of a session is kept
+  if !response.token.is_empty() {
+    let (ip, token) = (client.ip.clone(), response.token.clone());
+    data.sqlhandler.detach(move |sql| record_login(sql, &ip, &token));
+    resp.cookie(session_cookie(&response.token, &data.r_config()));
+  }
+  resp.json(response)
+}
+
+/// a failed login, signup, totp step or refresh, with the status of its error
+fn login_err_response(e: Err) -> HttpResponse {
+  let e = ApiError::of(e, ErrorCode::InvalidRequest);
+  HttpResponse::build(e.code.status()).json(LoginResponse {
+    code: ResponseCode::Err(e),
+    ..Default::default()
+  })
+}
+
+#[post("/signup")]
+pub async fn signup(req: HttpRequest, param: web::Json<SignupRequest>, data: web::Data<Arc<Server>>) 
+  -> HttpResponse {
+  let client = ClientInfo::of(&req);
+  let (c, d) = (client.clone(), data.clone());
+  match blocking(move || do_signup(&c, &param, &d)).await {
+    Ok(response) => login_ok_response(&client, response, &data),
+    Err(e) => login_err_response(e),
+  }
+}
+
+#[post("/login/totp")]
+pub async fn login_totp(
+  req: HttpRequest,
+  param: web::Json<TotpLoginRequest>,
+  data: web::Data<Arc<Server>>
+) -> HttpResponse {
+  let client = ClientInfo::of(&req);
+  let (c, d) = (client.clone(), data.clone());
+  let resp = match blocking(move || do_login_totp(&c, &param, &d)).await {
+    Ok(response) => login_ok_response(&client, response, &data),
+    Err(e) => login_err_response(e),
+  };
+  log::debug!("Server login totp resp with {:?}", resp);
+  resp
+}
+
+#[post("/refresh")]
+pub async fn refresh(param: web::Json<RefreshRequest>, data: web::Data<Arc<Server>>) -> HttpResponse {
+  let d = data.clone();
+  let resp = match blocking(move || do_refresh(&param, &d)).await {
+    Ok(response) => HttpResponse::Ok()
+      .cookie(session_cookie(&response.token, &data.r_config()))
+      .json(response),
+    Err(e) => login_err_response(e),
+  };
+  log::debug!("Server refresh resp with {:?}", resp);
+  resp
+}
+
 #[post("/logout")]
-pub async fn logout(param: web::Json<LogoutRequest>, data: web::Data<Arc<Server>>) -> HttpResponse {
-  let resp: HttpResponse;
-  match do_logout(&param, &data) {
-    Ok(response) => resp = HttpResponse::Ok().json(response),
+pub async fn logout(auth: AuthUser, param: web::Json<LogoutRequest>, data: web::Data<Arc<Server>>) 
+  -> HttpResponse {
+  let d = data.clone();
+  let resp = match blocking(move || do_logout(&auth, &param, &d)).await {
+    Ok(response) => HttpResponse::Ok().cookie(removed_session_cookie(&data)).json(response),
     Err(e) => {
-      let mut response = LoginResponse::default();
-      response.code = ResponseCode::Err(e.to_string());
-      resp = HttpResponse::Ok().json(response)
+      let e = ApiError::of(e, ErrorCode::InvalidRequest);
+      HttpResponse::build(e.code.status()).json(LogoutResponse {
+        basic_info: StreamBasicInfo {
+          time_stamp: Time::now().milli(),
+        },
+        code: ResponseCode::Err(e),
+      })
     }
-  }
+  };
   log::debug!("Server logout resp with {:?}", resp);
   resp
 }
 
+fn removed_session_cookie<'a>(data: &web::Data<Arc<Server>>) -> actix_web::cookie::Cookie<'a> {
+  let mut cookie = session_cookie("", &data.r_config());
+  cookie.make_removal();
+  cookie
+}
+
+#[post("/account/password")]
+pub async fn change_password(
+  auth: AuthUser,
+  param: web::Json<ChangePasswordRequest>,
+  data: web::Data<Arc<Server>>
+) -> HttpResponse {
+  let d = data.clone();
+  match blocking(move || do_change_password(&auth, &param, &d)).await {
+    Ok(()) => HttpResponse::Ok().body(""),
+    Err(e) => ApiError::of(e, ErrorCode::InvalidRequest).error_response(),
+  }
+}
+
+#[post("/account/preferences")]
+pub async fn set_preferences(
+  auth: AuthUser,
+  param: web::Json<PreferencesRequest>,
+  data: web::Data<Arc<Server>>
+) -> HttpResponse {
+  if let Err(e) = auth.require_session() {
+    return e.error_response();
+  }
+  let username = auth.user.username;
+  match data.sqlhandler.run(move |sql| do_set_preferences(sql, &username, &param)).await {
+    Ok(()) => HttpResponse::Ok().body(""),
+    Err(e) => ApiError::of(e, ErrorCode::InvalidRequest).error_response(),
+  }
+}
+
+/// every session of the user ends, it has to login with the new name
+#[post("/account/rename")]
+pub async fn rename_account(
+  auth: AuthUser,
+  param: web::Json<RenameRequest>,
+  data: web::Data<Arc<Server>>
+) -> HttpResponse {
+  let d = data.clone();
+  match blocking(move || do_rename(&auth, &param, &d)).await {
+    Ok(()) => HttpResponse::Ok().cookie(removed_session_cookie(&data)).body(""),
+    Err(e) => ApiError::of(e, ErrorCode::InvalidRequest).error_response(),
+  }
+}
+
+#[post("/account/delete")]
+pub async fn delete_account(
+  auth: AuthUser,
+  param: web::Json<DeleteAccountRequest>,
+  data: web::Data<Arc<Server>>
+) -> HttpResponse {
+  let d = data.clone();
+  match blocking(move || do_delete_account(&auth, &param, &d)).await {
+    Ok(()) => HttpResponse::Ok().cookie(removed_session_cookie(&data)).body(""),
+    Err(e) => ApiError::of(e, ErrorCode::InvalidRequest).error_response(),
+  }
+}
+
+#[get("/sessions")]
+pub async fn get_sessions(auth: AuthUser, data: web::Data<Arc<Server>>) -> HttpResponse {
+  if let Err(e) = auth.require_session() {
+    return e.error_response();
+  }
+  HttpResponse::Ok().json(list_sessions(&data, &auth.user.username, &auth.token_hash))
+}
+
+#[post("/sessions/terminate")]
+pub async fn terminate_session(
+  auth: AuthUser,
+  param: web::Json<TerminateSessionRequest>,
+  data: web::Data<Arc<Server>>
+) -> HttpResponse {
+  if let Err(e) = auth.require_session() {
+    return e.error_response();
+  }
+  let d = data.clone();
+  let terminated = blocking(move || {
+    terminate_sessions(&d, &auth.user.username, &auth.token_hash, &param.target)
+  });
+  match terminated.await {
+    Ok(terminated) => HttpResponse::Ok().json(TerminateSessionResponse { terminated }),
+    Err(e) => ApiError::of(e, ErrorCode::NotFound).error_response(),
+  }
+}
+
+/// history of the caller
+#[get("/audit")]
+pub async fn get_own_audit(
+  auth: AuthUser,
+  query: web::Query<AuditQuery>,
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  auth.require_session()?;
+  let filter = query.filter(Some(auth.user.id));
+  Ok(HttpResponse::Ok().json(data.sqlhandler.run(move |sql| sql.query_file_actions(&filter)).await?))
+}
+
+/// history of everyone, or of one user with the username query
+#[get("/audit/all")]
+pub async fn get_all_audit(
+  auth: AuthUser,
+  query: web::Query<AuditQuery>,
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  auth.require(Capability::ManageUsers)?;
+  let sqlhandler = data.sqlhandler.clone();
+  let user_id = match query.username.clone() {
+    Some(name) => match sqlhandler.run(move |sql| sql.get_user_by_name(&name)).await? {
+      Some(u) => Some(u.id),
+      None => return Err(ApiError::new(ErrorCode::NotFound, "user not exists")),
+    },
+    None => None,
+  };
+  let filter = query.filter(user_id);
+  Ok(HttpResponse::Ok().json(sqlhandler.run(move |sql| sql.query_file_actions(&filter)).await?))
+}
+
+#[get("/users")]
+pub async fn get_users(auth: AuthUser, data: web::Data<Arc<Server>>) -> Result<HttpResponse, ApiError> {
+  auth.require(Capability::ManageUsers)?;
+  let users: Vec<UserInfo> = data
+    .sqlhandler
+    .run(|sql| sql.get_users())
+    .await?
+    .into_iter()
+    .map(|u| UserInfo {
+      username: u.username,
+      usertype: u.usertype,
+    })
+    .collect();
+  Ok(HttpResponse::Ok().json(users))
+}
+
+/// a manager can only change users below itself, to a type not above itself
+#[post("/users/type")]
+pub async fn set_user_type(
+  auth: AuthUser,
+  param: web::Json<SetUserTypeRequest>,
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  auth.require(Capability::ManageUsers)?;
+  let sqlhandler = data.sqlhandler.clone();
+  let username = param.username.clone();
+  let target = match sqlhandler.run(move |sql| sql.get_user_by_name(&username)).await? {
+    Some(u) => u,
+    None => return Err(ApiError::new(ErrorCode::NotFound, "user not exists")),
+  };
+  let rank = auth.user.usertype.rank();
+  if (target.usertype.rank() <= rank && auth.user.usertype != UserType::Master)
+    || param.usertype.rank() < rank {
+    return Err(ApiError::new(ErrorCode::Forbidden, format!(
+      "{:?} can not make {} {:?}", auth.user.usertype, param.username, param.usertype
+    )));
+  }
+  log::info!("{} set type of {} to {:?}", auth.user.username, param.username, param.usertype);
+  sqlhandler.run(move |sql| sql.update_user_type_by_name(&param.username, &param.usertype)).await?;
+  Ok(HttpResponse::Ok().body(""))
+}
+
+/// the code is only returned here, a manager can not invite above itself
+#[post("/invitations")]
+pub async fn create_invitation(
+  auth: AuthUser,
+  param: web::Json<CreateInvitationRequest>,
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  auth.require(Capability::ManageUsers)?;
+  if param.usertype.rank() < auth.user.usertype.rank() {
+    return Err(ApiError::new(ErrorCode::Forbidden, format!(
+      "{:?} can not invite {:?}", auth.user.usertype, param.usertype
+    )));
+  }
+  if param.quota == 0 {
+    return Err(ApiError::new(ErrorCode::InvalidRequest, "quota must be positive"));
+  }
+  let invitation = data.sqlhandler.run(move |sql| do_create_invitation(&auth, &param, sql));
+  Ok(HttpResponse::Ok().json(invitation.await?))
+}
+
+#[get("/invitations")]
+pub async fn get_invitations(auth: AuthUser, data: web::Data<Arc<Server>>) 
+  -> Result<HttpResponse, ApiError> {
+  auth.require(Capability::ManageUsers)?;
+  Ok(HttpResponse::Ok().json(data.sqlhandler.run(|sql| sql.get_invitations()).await?))
+}
+
+#[post("/invitations/delete")]
+pub async fn delete_invitation(
+  auth: AuthUser,
+  param: web::Json<DeleteInvitationRequest>,
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  auth.require(Capability::ManageUsers)?;
+  log::info!("{} deleted invitation {}", auth.user.username, param.id);
+  let id = param.id;
+  data.sqlhandler.run(move |sql| sql.delete_invitation(id)).await?;
+  Ok(HttpResponse::Ok().body(""))
+}
+
+#[get("/api_keys")]
+pub async fn get_api_keys(auth: AuthUser, data: web::Data<Arc<Server>>) 
+  -> Result<HttpResponse, ApiError> {
+  auth.require_session()?;
+  let user_id = auth.user.id;
+  Ok(HttpResponse::Ok().json(data.sqlhandler.run(move |sql| sql.get_user_api_keys(user_id)).await?))
+}
+
+/// the key is only returned here
+#[post("/api_keys")]
+pub async fn create_api_key(
+  auth: AuthUser,
+  param: web::Json<CreateApiKeyRequest>,
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  auth.require_session()?;
+  match data.sqlhandler.run(move |sql| do_create_api_key(&auth.user, &param, sql)).await {
+    Ok(resp) => Ok(HttpResponse::Ok().json(resp)),
+    Err(e) => Err(ApiError::of(e, ErrorCode::InvalidRequest)),
+  }
+}
+
+#[post("/api_keys/revoke")]
+pub async fn revoke_api_key(
+  auth: AuthUser,
+  param: web::Json<RevokeApiKeyRequest>,
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  auth.require_session()?;
+  blocking(move || do_revoke_api_key(&data, &auth.user, param.id)).await?;
+  Ok(HttpResponse::Ok().body(""))
+}
+
+/// generate a new secret, it is not used for login until confirmed
+#[post("/totp/enroll")]
+pub async fn totp_enroll(auth: EnrollingUser, data: web::Data<Arc<Server>>) 
+  -> Result<HttpResponse, ApiError> {
+  let user = &auth.0.user;
+  let (sqlhandler, user_id) = (data.sqlhandler.clone(), user.id);
+  if let Some(t) = sqlhandler.run(move |sql| sql.get_user_totp(user_id)).await? {
+    if t.enabled {
+      return Err(ApiError::new(ErrorCode::Conflict, "totp already enabled"));
+    }
+  }
+  let secret = gen_totp_secret();
+  let totp = UserTotp {
+    user_id,
+    secret: secret.clone(),
+    enabled: false,
+    recovery_codes: vec![],
+    last_used_step: 0,
+  };
+  sqlhandler.run(move |sql| sql.save_user_totp(&totp)).await?;
+  Ok(HttpResponse::Ok().json(TotpEnrollResponse {
+    uri: totp_provisioning_uri(&user.username, &secret),
+    secret,
+  }))
+}
+
+fn new_recovery_codes(totp: &mut UserTotp) -> RecoveryCodesResponse {
+  let codes = gen_recovery_codes();
+  totp.recovery_codes = codes.iter().map(|c| sha256::digest(c.as_str())).collect();
+  RecoveryCodesResponse {
+    recovery_codes: codes,
+  }
+}
+
+/// enable totp with the first code from the app, recovery codes are returned once
+#[post("/totp/confirm")]
+pub async fn totp_confirm(
+  auth: EnrollingUser, 
+  param: web::Json<TotpCodeRequest>, 
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  let user = &auth.0.user;
+  let (sqlhandler, user_id) = (data.sqlhandler.clone(), user.id);
+  let mut totp = match sqlhandler.run(move |sql| sql.get_user_totp(user_id)).await? {
+    Some(t) if !t.enabled => t,
+    Some(_) => return Err(ApiError::new(ErrorCode::Conflict, "totp already enabled")),
+    None => return Err(ApiError::new(ErrorCode::InvalidRequest, "totp not enrolled")),
+  };
+  let (username, code, throttle) = (user.username.clone(), param.code.clone(), data.r_config().login_throttle);
+  let mut totp = sqlhandler.run(move |sql| {
+    check_user_totp(sql, &username, &mut totp, &code, &throttle)?;
+    Ok(totp)
+  }).await?;
+  totp.enabled = true;
+  let resp = new_recovery_codes(&mut totp);
+  sqlhandler.run(move |sql| sql.save_user_totp(&totp)).await?;
+  log::info!("user {} enabled totp", user.username);
+  Ok(HttpResponse::Ok().json(resp))
+}
+
+#[post("/totp/disable")]
+pub async fn totp_disable(
+  auth: AuthUser, 
+  param: web::Json<TotpCodeRequest>, 
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  auth.require_session()?;
+  let (sqlhandler, user_id, usertype) = (data.sqlhandler.clone(), auth.user.id, auth.user.usertype.clone());
+  if sqlhandler.run(move |sql| sql.role_requires_totp(&usertype)).await? {
+    return Err(ApiError::new(ErrorCode::Forbidden, format!("{:?} must use totp", auth.user.usertype)));
+  }
+  let mut totp = match sqlhandler.run(move |sql| sql.get_user_totp(user_id)).await? {
+    Some(t) if t.enabled => t,
+    _ => return Err(ApiError::new(ErrorCode::InvalidRequest, "totp not enabled")),
+  };
+  let (username, code, throttle) = (auth.user.username.clone(), param.code.clone(), data.r_config().login_throttle);
+  sqlhandler.run(move |sql| {
+    check_user_totp(sql, &username, &mut totp, &code, &throttle)?;
+    sql.delete_user_totp(user_id)
+  }).await?;
+  log::info!("user {} disabled totp", auth.user.username);
+  Ok(HttpResponse::Ok().body(""))
+}
+
+/// replace all recovery codes, the old ones can not be used any more
+#[post("/totp/recovery_codes")]
+pub async fn totp_recovery_codes(
+  auth: AuthUser, 
+  param: web::Json<TotpCodeRequest>, 
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  auth.require_session()?;
+  let (sqlhandler, user_id) = (data.sqlhandler.clone(), auth.user.id);
+  let mut totp = match sqlhandler.run(move |sql| sql.get_user_totp(user_id)).await? {
+    Some(t) if t.enabled => t,
+    _ => return Err(ApiError::new(ErrorCode::InvalidRequest, "totp not enabled")),
+  };
+  let (username, code, throttle) = (auth.user.username.clone(), param.code.clone(), data.r_config().login_throttle);
+  let mut totp = sqlhandler.run(move |sql| {
+    check_user_totp(sql, &username, &mut totp, &code, &throttle)?;
+    Ok(totp)
+  }).await?;
+  let resp = new_recovery_codes(&mut totp);
+  sqlhandler.run(move |sql| sql.save_user_totp(&totp)).await?;
+  Ok(HttpResponse::Ok().json(resp))
+}
+
+#[get("/role_policies")]
+pub async fn get_role_policies(auth: AuthUser, data: web::Data<Arc<Server>>) 
+  -> Result<HttpResponse, ApiError> {
+  auth.require(Capability::ManageUsers)?;
+  Ok(HttpResponse::Ok().json(data.sqlhandler.run(|sql| sql.get_role_policies()).await?))
+}
+
+#[post("/role_policies")]
+pub async fn set_role_policy(
+  auth: AuthUser, 
+  param: web::Json<RolePolicy>, 
+  data: web::Data<Arc<Server>>
+) -> Result<HttpResponse, ApiError> {
+  auth.require(Capability::ManageUsers)?;
+  if param.usertype.rank() < auth.user.usertype.rank() {
+    return Err(ApiError::new(ErrorCode::Forbidden, format!(
+      "{:?} can not change policy of {:?}", auth.user.usertype, param.usertype
+    )));
+  }
+  log::info!("{} set role policy {:?}", auth.user.username, param);
+  data.sqlhandler.run(move |sql| sql.set_role_policy(&param)).await?;
+  Ok(HttpResponse::Ok().body(""))
+}
 
 pub async fn index() -> HttpResponse {
   let html_str = match std::fs::read_to_string("pulsear-ui/ui/index.html") {
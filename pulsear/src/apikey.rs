@@ -0,0 +1,218 @@
+use crate::*;
+
+/// what a personal api key may do, on top of the rights of its owner
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ApiScope {
+  // list, stat and download own files, open a websocket
+  Read,
+  Upload,
+  Delete,
+  // create public download links
+  Share,
+}
+
+impl ApiScope {
+  /// the scope a key needs for a capability, None if only sessions may use it
+  pub fn for_capability(capability: Capability) -> Option<ApiScope> {
+    match capability {
+      Capability::Upload => Some(ApiScope::Upload),
+      Capability::Share | Capability::CreatePublicLink => Some(ApiScope::Share),
+      Capability::ViewDashboard => Some(ApiScope::Read),
+      Capability::ManageUsers | Capability::Broadcast => None,
+    }
+  }
+
+  /// a stored list that does not parse is an error, not a key without scopes
+  pub fn from_str_list(s: &str) -> Result<Vec<ApiScope>, Err> {
+    Ok(serde_json::from_str(s)?)
+  }
+}
+
+/// keys start with this such that they are told apart from session tokens
+pub const API_KEY_PREFIX: &str = "pk_";
+// chars of a key shown in listings to recognize it
+const API_KEY_SHOWN_LEN: usize = 11;
+
+pub fn gen_api_key() -> String {
+  format!("{}{}", API_KEY_PREFIX, random_token())
+}
+
+pub fn is_api_key(token: &str) -> bool {
+  token.starts_with(API_KEY_PREFIX)
+}
+
+/// a personal api key, stored in api_key table. only the sha256 of the key is kept
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone)]
+pub struct ApiKey {
+  pub id: i32,
+  pub user_id: i32,
+  pub name: String,
+  #[serde(skip)]
+  pub key_hash: String,
+  // beginning of the key, such that the owner can recognize it
+  pub prefix: String,
+  pub scopes: Vec<ApiScope>,
+  pub create_t: u64,
+  // 0 means never expire
+  pub expire_t: u64,
+  // 0 means never used
+  pub last_used_t: u64,
+}
+
+impl ApiKey {
+  pub fn is_valid(&self, now: u64) -> bool {
+    self.expire_t == 0 || self.expire_t > now
+  }
+
+  pub fn allows(&self, scope: ApiScope) -> bool {
+    self.scopes.contains(&scope)
+  }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct CreateApiKeyRequest {
+  pub name: String,
+  pub scopes: Vec<ApiScope>,
+  // lifetime in seconds, never expire if absent
+  #[serde(default)]
+  pub ttl: Option<u64>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct CreateApiKeyResponse {
+  // the plain key, only shown once
+  pub key: String,
+  pub api_key: ApiKey,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct RevokeApiKeyRequest {
+  pub id: i32,
+}
+
+pub fn do_create_api_key(
+  user: &User,
+  param: &CreateApiKeyRequest,
+  sqlhandler: &SqlHandler,
+) -> Result<CreateApiKeyResponse, Err> {
+  let name = param.name.trim();
+  if name.is_empty() || name.chars().count() > 64 {
+    return Err(ApiError::err(ErrorCode::InvalidName, "api key name length must be 1~64"));
+  }
+  if param.scopes.is_empty() {
+    return Err(ApiError::err(ErrorCode::InvalidRequest, "api key needs at least one scope"));
+  }
+  let mut scopes = param.scopes.clone();
+  scopes.sort();
+  scopes.dedup();
+  let now = Time::now().milli();
+  let key = gen_api_key();
+  let api_key = sqlhandler.add_api_key(&ApiKey {
+    id: 0,
+    user_id: user.id,
+    name: name.to_string(),
+    key_hash: hash_session_token(&key),
+    prefix: key[..API_KEY_SHOWN_LEN].to_string(),
+    scopes,
+    create_t: now,
+    expire_t: expire_t_of_ttl(now, param.ttl)?,
+    last_used_t: 0,
+  })?;
+  log::info!("{} created api key {} {:?}", user.username, api_key.id, api_key.scopes);
+  Ok(CreateApiKeyResponse { key, api_key })
+}
+
+/// only the owner can revoke its key, the sockets opened with it are closed
+pub fn do_revoke_api_key(server: &Server, user: &User, id: i32) -> Result<(), Err> {
+  let key_hash = match server.sqlhandler.get_user_api_keys(user.id)?.into_iter().find(|k| k.id == id) {
+    Some(k) => k.key_hash,
+    None => return Err(ApiError::err(ErrorCode::NotFound, "api key not exists")),
+  };
+  if !server.sqlhandler.delete_api_key(user.id, id)? {
+    return Err(ApiError::err(ErrorCode::NotFound, "api key not exists"));
+  }
+  server.close_token_ctxs(&user.username, &key_hash, "api key revoked");
+  log::info!("{} revoked api key {}", user.username, id);
+  Ok(())
+}
+
+/// find the owner of an api key, the key must not be expired.
+/// last used time of the key is updated
+pub fn authenticate_api_key(sqlhandler: &SqlHandler, key: &str) -> Result<(User, ApiKey), Err> {
+  let mut api_key = match sqlhandler.get_api_key_by_hash(&hash_session_token(key))? {
+    Some(k) => k,
+    None => return Err(ApiError::err(ErrorCode::Unauthorized, "api key not exists")),
+  };
+  let now = Time::now().milli();
+  if !api_key.is_valid(now) {
+    return Err(ApiError::err(ErrorCode::Unauthorized, "api key expired"));
+  }
+  let user = match sqlhandler.get_user_by_id(api_key.user_id)? {
+    Some(u) => u,
+    None => return Err(ApiError::err(ErrorCode::Unauthorized, "user not exists")),
+  };
+  sqlhandler.touch_api_key(api_key.id, now)?;
+  api_key.last_used_t = now;
+  Ok((user, api_key))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn api_key_scope() {
+    let key = gen_api_key();
+    assert!(is_api_key(&key));
+    assert!(!is_api_key(&random_token()));
+    let mut api_key = ApiKey {
+      id: 1,
+      user_id: 1,
+      name: "ci".into(),
+      key_hash: hash_session_token(&key),
+      prefix: key[..API_KEY_SHOWN_LEN].to_string(),
+      scopes: vec![ApiScope::Read, ApiScope::Upload],
+      create_t: 0,
+      expire_t: 0,
+      last_used_t: 0,
+    };
+    assert!(api_key.is_valid(100));
+    assert!(api_key.allows(ApiScope::Upload));
+    assert!(!api_key.allows(ApiScope::Delete));
+    api_key.expire_t = 100;
+    assert!(!api_key.is_valid(100));
+    assert_eq!(ApiScope::for_capability(Capability::CreatePublicLink), Some(ApiScope::Share));
+    assert_eq!(ApiScope::for_capability(Capability::ManageUsers), None);
+    assert_eq!(
+      ApiScope::from_str_list(&serde_json::to_string(&api_key.scopes).unwrap()).unwrap(),
+      api_key.scopes
+    );
+    assert!(ApiScope::from_str_list("[\"Admin\"]").is_err());
+  }
+
+  #[test]
+  fn create_api_key() {
+    let db = crate::sql::tests::TempDb::default();
+    let sqlhandler = SqlHandler::connect(&db.url()).unwrap();
+    sqlhandler.migrate().unwrap();
+    let user = sqlhandler.add_user(&User {
+      id: 0,
+      username: "keyowner0".into(),
+      token: password_token("keyowner0", "keyowner0"),
+      config: UserConfig::default(),
+      usertype: UserType::default(),
+    }).unwrap().unwrap();
+    let param = CreateApiKeyRequest {
+      name: "ci".into(),
+      scopes: vec![ApiScope::Upload, ApiScope::Read, ApiScope::Upload],
+      ttl: None,
+    };
+    let created = do_create_api_key(&user, &param, &sqlhandler).unwrap();
+    assert_eq!(created.api_key.scopes, vec![ApiScope::Read, ApiScope::Upload]);
+    let stored = sqlhandler.get_api_key_by_hash(&created.api_key.key_hash).unwrap().unwrap();
+    assert_eq!(stored.scopes, created.api_key.scopes);
+    let huge = CreateApiKeyRequest { ttl: Some(u64::MAX), ..param };
+    let e = do_create_api_key(&user, &huge, &sqlhandler).err().unwrap();
+    assert_eq!(ApiError::of(e, ErrorCode::Internal).code, ErrorCode::InvalidRequest);
+  }
+}
@@ -0,0 +1,169 @@
+use crate::*;
+
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileActionKind {
+  Upload,
+  Download,
+  Delete,
+  Share,
+  Login,
+}
+
+impl FileActionKind {
+  /// a stored action that does not parse is an error, not a download
+  pub fn from(s: &str) -> Result<FileActionKind, Err> {
+    Ok(serde_json::from_value(serde_json::Value::String(s.to_string()))?)
+  }
+}
+
+impl fmt::Display for FileActionKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", serde_json::to_string(self).unwrap().trim_matches('"'))
+  }
+}
+
+/// one row of user_file_action, the audit trail of a user
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone)]
+pub struct FileAction {
+  pub id: i32,
+  // the owner of the file, or who logged in
+  pub user_id: i32,
+  pub filename: String,
+  pub filepath: String,
+  pub action: FileActionKind,
+  pub ip: String,
+  pub session_id: Option<i32>,
+  pub api_key_id: Option<i32>,
+  pub create_t: u64,
+}
+
+#[derive(Default)]
+pub struct FileActionFilter {
+  pub user_id: Option<i32>,
+  pub action: Option<FileActionKind>,
+  pub since: Option<u64>,
+  pub until: Option<u64>,
+  // rows older than this id, for paging
+  pub before_id: Option<i32>,
+  pub limit: Option<u32>,
+}
+
+pub const AUDIT_DEFAULT_LIMIT: u32 = 100;
+pub const AUDIT_MAX_LIMIT: u32 = 1000;
+
+/// query string of the audit api, username is only used by managers
+#[derive(serde::Deserialize, serde::Serialize, Default)]
+pub struct AuditQuery {
+  pub username: Option<String>,
+  pub action: Option<FileActionKind>,
+  pub since: Option<u64>,
+  pub until: Option<u64>,
+  pub before_id: Option<i32>,
+  pub limit: Option<u32>,
+}
+
+impl AuditQuery {
+  pub fn filter(&self, user_id: Option<i32>) -> FileActionFilter {
+    FileActionFilter {
+      user_id,
+      action: self.action,
+      since: self.since,
+      until: self.until,
+      before_id: self.before_id,
+      limit: Some(self.limit.unwrap_or(AUDIT_DEFAULT_LIMIT).min(AUDIT_MAX_LIMIT)),
+    }
+  }
+}
+
+/// who did an action, from where
+pub struct AuditActor {
+  pub user_id: i32,
+  pub ip: String,
+  pub session_id: Option<i32>,
+  pub api_key_id: Option<i32>,
+}
+
+impl AuditActor {
+  pub fn from_auth(auth: &AuthUser, req: &HttpRequest) -> Self {
+    let (session_id, api_key_id) = match &auth.credential {
+      Credential::Session(s) => (Some(s.id), None),
+      Credential::ApiKey(k) => (None, Some(k.id)),
+    };
+    Self {
+      user_id: auth.user.id,
+      ip: peer_ip(req),
+      session_id,
+      api_key_id,
+    }
+  }
+
+  /// token is the sha256 kept in UserCtx
+  pub fn from_token_hash(sqlhandler: &SqlHandler, user_id: i32, token: &str, ip: &str)
+    -> Result<Self, Err> {
+    let session_id = sqlhandler.get_session_by_token(token)?.map(|s| s.id);
+    let api_key_id = match session_id {
+      Some(_) => None,
+      None => sqlhandler.get_api_key_by_hash(token)?.map(|k| k.id),
+    };
+    Ok(Self {
+      user_id,
+      ip: ip.to_string(),
+      session_id,
+      api_key_id,
+    })
+  }
+}
+
+/// audit failures are logged but never fail the action itself
+pub fn record_action(sqlhandler: &SqlHandler, actor: &AuditActor, action: FileActionKind, filename: &str) {
+  let row = FileAction {
+    id: 0,
+    user_id: actor.user_id,
+    filename: filename.to_string(),
+    filepath: filename.to_string(),
+    action,
+    ip: actor.ip.clone(),
+    session_id: actor.session_id,
+    api_key_id: actor.api_key_id,
+    create_t: Time::now().milli(),
+  };
+  if let Err(e) = sqlhandler.add_file_action(&row) {
+    log::error!("record {} of user {} error: {}", action, actor.user_id, e);
+  }
+}
+
+/// record a login which issued the token
+pub fn record_login(sqlhandler: &SqlHandler, ip: &str, token: &str) {
+  match authenticate_token(sqlhandler, token) {
+    Ok((user, session)) => record_action(
+      sqlhandler,
+      &AuditActor {
+        user_id: user.id,
+        ip: ip.to_string(),
+        session_id: Some(session.id),
+        api_key_id: None,
+      },
+      FileActionKind::Login,
+      "",
+    ),
+    Err(e) => log::error!("record login error: {}", e),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn audit_query() {
+    assert_eq!(FileActionKind::Upload.to_string(), "upload");
+    assert_eq!(FileActionKind::from("login").unwrap(), FileActionKind::Login);
+    assert!(FileActionKind::from("rename").is_err());
+    let query: AuditQuery = serde_json::from_str(r#"{"action":"share","limit":5000}"#).unwrap();
+    let filter = query.filter(Some(3));
+    assert_eq!(filter.action, Some(FileActionKind::Share));
+    assert_eq!(filter.limit, Some(AUDIT_MAX_LIMIT));
+    assert_eq!(AuditQuery::default().filter(None).limit, Some(AUDIT_DEFAULT_LIMIT));
+  }
+}
@@ -4,7 +4,7 @@ use crate::*;
 pub enum ResponseCode {
   #[default]
   Ok,
-  Err(String),
+  Err(ApiError),
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Default)]
@@ -18,7 +18,7 @@ pub struct LoginRequest {
   pub login_info: LoginInfo,
 }
 
-#[derive(Default, std::fmt::Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Default, std::fmt::Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 pub enum UserType {
   Master,
   Manager,
@@ -40,25 +40,72 @@ impl ToString for UserType {
   } 
 }
 
+impl UserType {
+  /// smaller is higher, master is 0
+  pub fn rank(&self) -> u8 {
+    match self {
+      UserType::Master => 0,
+      UserType::Manager => 1,
+      UserType::Member => 2,
+      UserType::User => 3,
+      UserType::Visiter => 4,
+    }
+  }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct UserInfo {
+  pub username: String,
+  pub usertype: UserType,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct SetUserTypeRequest {
+  pub username: String,
+  pub usertype: UserType,
+}
+
+/// what a user is allowed to do, decided by its UserType
+#[derive(std::fmt::Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum Capability {
+  Upload,
+  Share,
+  CreatePublicLink,
+  ManageUsers,
+  Broadcast,
+  ViewDashboard,
+}
+
 pub struct UserRight {
-  pub max_storage: u64
+  pub max_storage: u64,
+  pub capabilities: Vec<Capability>,
 }
 
 impl UserRight {
   pub fn from(t: UserType) -> Self {
-    let  max_storage: u64;
+    use Capability::*;
     let one_g = 1024*1024*1024;
-    match t {
-      UserType::User => max_storage = one_g,
-      UserType::Visiter => max_storage = 0,
-      UserType::Manager => max_storage = 100*one_g,
-      UserType::Master => max_storage = 1000*one_g,
-      UserType::Member => max_storage = 10*one_g,
-    }
+    let (max_storage, capabilities) = match t {
+      UserType::Master => 
+        (1000*one_g, vec![Upload, Share, CreatePublicLink, ManageUsers, Broadcast, ViewDashboard]),
+      UserType::Manager => 
+        (100*one_g, vec![Upload, Share, CreatePublicLink, ManageUsers, Broadcast, ViewDashboard]),
+      UserType::Member => 
+        (10*one_g, vec![Upload, Share, CreatePublicLink]),
+      UserType::User => 
+        (one_g, vec![Upload, Share, CreatePublicLink]),
+      UserType::Visiter => 
+        (0, vec![]),
+    };
     Self {
-      max_storage
+      max_storage,
+      capabilities
     }
   }
+
+  pub fn can(&self, capability: Capability) -> bool {
+    self.capabilities.contains(&capability)
+  }
 }
 
 #[derive(std::fmt::Debug)]
@@ -80,15 +127,71 @@ impl PartialEq for User {
 }
 impl Eq for User {}
 
+/// paths with a view of their own a user may keep
+pub const FILE_LIST_MAX_PATHS: usize = 64;
+
 // map a path to its config
 #[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, PartialEq, Clone)]
 pub struct FileListConfig(HashMap<String, FileListPathConfig>);
 
+impl FileListConfig {
+  /// the view of a path, a path never configured looks like "/"
+  pub fn get(&self, path: &str) -> FileListPathConfig {
+    match self.0.get(path).or_else(|| self.0.get("/")) {
+      Some(c) => c.clone(),
+      None => FileListPathConfig::default(),
+    }
+  }
+
+  /// a path already set can always change, a new one only below the cap
+  pub fn set(&mut self, path: String, config: FileListPathConfig) -> Result<(), Err> {
+    if !self.0.contains_key(&path) && self.0.len() >= FILE_LIST_MAX_PATHS {
+      return Err(ApiError::err(ErrorCode::InvalidRequest,
+        format!("at most {} paths have a view", FILE_LIST_MAX_PATHS)));
+    }
+    self.0.insert(path, config);
+    Ok(())
+  }
+}
+
 #[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone)]
 pub struct FileListPathConfig {
-  order_by: String,
-  order_asc: bool,
-  columns: Vec<String>
+  pub order_by: String,
+  pub order_asc: bool,
+  // shown columns in order, of FileListElem::columns
+  pub columns: Vec<String>
+}
+
+impl Default for FileListPathConfig {
+  fn default() -> Self {
+    let columns = FileListElem::columns();
+    Self {
+      order_by: columns[0].clone(),
+      columns,
+      order_asc: true,
+    }
+  }
+}
+
+impl FileListPathConfig {
+  pub fn check(&self) -> Result<(), Err> {
+    let known = FileListElem::columns();
+    if self.columns.is_empty() {
+      return Err(ApiError::err(ErrorCode::InvalidRequest, "need at least one column"));
+    }
+    for (i, column) in self.columns.iter().enumerate() {
+      if !known.contains(column) {
+        return Err(ApiError::err(ErrorCode::InvalidRequest, format!("unknown column: {}", column)));
+      }
+      if self.columns[..i].contains(column) {
+        return Err(ApiError::err(ErrorCode::InvalidRequest, format!("duplicate column: {}", column)));
+      }
+    }
+    if !known.contains(&self.order_by) {
+      return Err(ApiError::err(ErrorCode::InvalidRequest, format!("unknown column: {}", self.order_by)));
+    }
+    Ok(())
+  }
 }
 
 impl PartialEq for FileListPathConfig {
@@ -119,17 +222,12 @@ impl Eq for UserConfig {}
 
 impl Default for UserConfig {
   fn default() -> Self {
-    let columns = FileListElem::columns();
     Self {
       id: 0,
       theme: "dark".to_string(),
       web_worker_num: 4,
       filelist_config: FileListConfig(HashMap::from([
-        (String::from("/"), FileListPathConfig {
-          order_by: columns[0].clone(),
-          columns,
-          order_asc: true,
-        }),
+        (String::from("/"), FileListPathConfig::default()),
       ]))
     }
   }
@@ -138,16 +236,60 @@ impl Default for UserConfig {
 #[derive(serde::Deserialize, serde::Serialize, Default)]
 pub struct LoginResponse {
   pub basic_info: StreamBasicInfo,
+  // session token, not the user credential
   pub token: String,
+  // empty when login with a session token, the client should keep its own
+  pub refresh_token: String,
+  // session token expire time in milliseconds
+  pub expire_t: u64,
+  // not empty when the password is right but a totp code is needed, 
+  // send it back with the code to /login/totp. token is empty then
+  pub totp_ticket: String,
+  // the user type requires totp, the session can only be used to enroll
+  pub totp_enroll_required: bool,
   pub config: UserConfig,
   pub code: ResponseCode,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
-pub struct LogoutRequest {
+pub struct RefreshRequest {
   pub basic_info: StreamBasicInfo,
-  pub username: String,
+  pub refresh_token: String,
+}
+
+/// a login session, issued by server and stored in session table.
+/// the token itself only leaves server once, in LoginResponse
+#[derive(std::fmt::Debug, Clone)]
+pub struct Session {
+  pub id: i32,
+  pub user_id: i32,
+  pub user_agent: String,
+  pub create_t: u64,
+  pub expire_t: u64,
+  pub refresh_expire_t: u64,
+  pub revoked: bool,
+}
+
+impl Session {
+  pub fn is_valid(&self, now: u64) -> bool {
+    !self.revoked && now < self.expire_t
+  }
+
+  pub fn is_refreshable(&self, now: u64) -> bool {
+    !self.revoked && now < self.refresh_expire_t
+  }
+}
+
+/// a newly created session with its plain tokens
+pub struct IssuedSession {
+  pub session: Session,
   pub token: String,
+  pub refresh_token: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct LogoutRequest {
+  pub basic_info: StreamBasicInfo,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -168,83 +310,457 @@ pub struct LoginInfo {
   pub choice: LoginChoice,
 }
 
-fn get_user_token(param: &web::Json<LoginRequest>) -> String {
-  match &(param.login_info).choice {
-    LoginChoice::Token(token) => token.clone(),
-    LoginChoice::Password(password) => 
-      HashGenerator::new(format!("{}{}", &param.login_info.username, password)).token()
+/// the stored token of a user is derived from its name and password
+pub fn password_token(username: &str, password: &str) -> String {
+  HashGenerator::new(format!("{}{}", username, password)).token()
+}
+
+
+/// session tokens are stored hashed, such that a leaked table can not be used to login
+pub fn hash_session_token(token: &str) -> String {
+  sha256::digest(token)
+}
+
+pub fn issue_session(
+  sqlhandler: &SqlHandler,
+  user: &User,
+  user_agent: &str,
+  config: &ServerConfig,
+) -> Result<IssuedSession, Err> {
+  let now = Time::now().milli();
+  let token = random_token();
+  let refresh_token = random_token();
+  let session = sqlhandler.add_session(
+    &Session {
+      id: 0,
+      user_id: user.id,
+      user_agent: user_agent.to_string(),
+      create_t: now,
+      expire_t: now + config.session_ttl * 1000,
+      refresh_expire_t: now + config.refresh_ttl * 1000,
+      revoked: false,
+    },
+    &hash_session_token(&token),
+    &hash_session_token(&refresh_token),
+  )?;
+  Ok(IssuedSession {
+    session,
+    token,
+    refresh_token,
+  })
+}
+
+/// find the user of a session token, the session must be still valid
+pub fn authenticate_token(
+  sqlhandler: &SqlHandler,
+  token: &str,
+) -> Result<(User, Session), Err> {
+  let session = match sqlhandler.get_session_by_token(&hash_session_token(token))? {
+    Some(s) => s,
+    None => return Err(ApiError::err(ErrorCode::Unauthorized, "session not exists")),
+  };
+  if !session.is_valid(Time::now().milli()) {
+    return Err(ApiError::err(ErrorCode::Unauthorized, "session expired or revoked"));
   }
+  let user = match sqlhandler.get_user_by_id(session.user_id)? {
+    Some(u) => u,
+    None => return Err(ApiError::err(ErrorCode::Unauthorized, "user not exists")),
+  };
+  Ok((user, session))
 }
 
-pub fn do_login(
-  param: &web::Json<LoginRequest>,
-  data: &web::Data<Arc<Server>>,
-) -> Result<LoginResponse, Err> {
-  log::info!("user try login: {}", serde_json::to_string(param).unwrap());
-  let sqlhandler = SqlHandler::new(data.dbpool.clone());
-
-  let token = get_user_token(param);
-  let user: User;
-  {
-    // lock begin
-    user = match sqlhandler.get_user_by_name(&param.login_info.username)? {
-      Some(u) => {
-        if &u.token != &token {
-          return Err(Box::from("password not true or is has been changed"));
-        }
-        u
+/// check the session token belongs to username and is still valid
+pub fn authenticate(
+  sqlhandler: &SqlHandler,
+  username: &String,
+  token: &str,
+) -> Result<(User, Session), Err> {
+  let (user, session) = authenticate_token(sqlhandler, token)?;
+  if &user.username != username {
+    return Err(ApiError::err(ErrorCode::Unauthorized, "session not belongs to user"));
+  }
+  Ok((user, session))
+}
+
+/// cookie name of the session token, set on login for browser clients
+pub const TOKEN_COOKIE: &str = "pulsear_token";
+
+pub fn session_cookie<'a>(token: &str, config: &ServerConfig) -> actix_web::cookie::Cookie<'a> {
+  actix_web::cookie::Cookie::build(TOKEN_COOKIE, token.to_string())
+    .path("/")
+    .http_only(true)
+    .secure(config.https)
+    .same_site(actix_web::cookie::SameSite::Strict)
+    .max_age(actix_web::cookie::time::Duration::seconds(config.session_ttl as i64))
+    .finish()
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+  Unauthorized(String),
+  Forbidden(String),
+}
+
+impl fmt::Display for AuthError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      AuthError::Unauthorized(e) => write!(f, "unauthorized: {}", e),
+      AuthError::Forbidden(e) => write!(f, "forbidden: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for AuthError {}
+
+impl actix_web::ResponseError for AuthError {
+  fn status_code(&self) -> actix_web::http::StatusCode {
+    match self {
+      AuthError::Unauthorized(_) => actix_web::http::StatusCode::UNAUTHORIZED,
+      AuthError::Forbidden(_) => actix_web::http::StatusCode::FORBIDDEN,
+    }
+  }
+
+  fn error_response(&self) -> HttpResponse {
+    let code = match self {
+      AuthError::Unauthorized(_) => ErrorCode::Unauthorized,
+      AuthError::Forbidden(_) => ErrorCode::Forbidden,
+    };
+    HttpResponse::build(self.status_code()).json(ApiError::new(code, self.to_string()))
+  }
+}
+
+/// how a request was authenticated
+pub enum Credential {
+  Session(Session),
+  ApiKey(ApiKey),
+}
+
+/// the authenticated user of a request, resolved from its session token or api key.
+/// token is read from `Authorization: Bearer`, then the session cookie, 
+/// then the `token` query which is used by websocket upgrades
+pub struct AuthUser {
+  pub user: User,
+  pub credential: Credential,
+  pub token_hash: String,
+}
+
+impl AuthUser {
+  fn request_token(req: &HttpRequest) -> Option<String> {
+    if let Some(value) = req.headers().get(actix_web::http::header::AUTHORIZATION) {
+      if let Some(token) = value.to_str().ok().and_then(|v| v.strip_prefix("Bearer ")) {
+        return Some(token.trim().to_string());
       }
-      None => {
-        let u = sqlhandler
-          .add_user(&User {
-            id: 0,
-            username: param.login_info.username.clone(),
-            token: token.clone(),
-            config: UserConfig::default(),
-            usertype: UserType::default()
-          })?
-          .expect("add user error!");
-        assert_eq!(&u.token, &token);
-        assert_eq!(&u.username, &param.login_info.username);
-        assert_eq!(&u.config, &UserConfig::default());
-        u
+    }
+    if let Some(cookie) = req.cookie(TOKEN_COOKIE) {
+      return Some(cookie.value().to_string());
+    }
+    web::Query::<WsQuery>::from_query(req.query_string())
+      .ok()
+      .map(|q| q.into_inner().token)
+  }
+
+  /// resolve a session token or api key, blocks on the database.
+  /// users who must enroll totp are only let through with allow_enroll
+  pub fn from_token(sqlhandler: &SqlHandler, token: &str, allow_enroll: bool) -> Result<Self, AuthError> {
+    let authenticated = if is_api_key(token) {
+      authenticate_api_key(sqlhandler, token).map(|(u, k)| (u, Credential::ApiKey(k)))
+    } else {
+      authenticate_token(sqlhandler, token).map(|(u, s)| (u, Credential::Session(s)))
+    };
+    let (user, credential) = authenticated.map_err(|e| AuthError::Unauthorized(e.to_string()))?;
+    if !allow_enroll {
+      match totp_enroll_required(sqlhandler, &user) {
+        Ok(false) => (),
+        Ok(true) => return Err(AuthError::Forbidden("totp enrollment required".into())),
+        Err(e) => return Err(AuthError::Unauthorized(e.to_string())),
       }
+    }
+    Ok(Self {
+      user,
+      credential,
+      token_hash: hash_session_token(token),
+    })
+  }
+
+  /// the database is read on the blocking pool
+  pub async fn from_http_request(req: &HttpRequest, allow_enroll: bool) -> Result<Self, AuthError> {
+    let sqlhandler = match req.app_data::<web::Data<Arc<Server>>>() {
+      Some(d) => d.sqlhandler.clone(),
+      None => return Err(AuthError::Unauthorized("server not configured".into())),
+    };
+    let token = match Self::request_token(req) {
+      Some(t) if !t.is_empty() => t,
+      _ => return Err(AuthError::Unauthorized("need token".into())),
     };
-  } // lock end
+    let auth = sqlhandler
+      .run(move |sql| Ok(Self::from_token(sql, &token, allow_enroll)))
+      .await
+      .unwrap_or_else(|e| Err(AuthError::Unauthorized(e.to_string())));
+    if let Err(e) = &auth {
+      log::warn!("reject request to {}: {}", req.path(), e);
+    }
+    auth
+  }
+}
+
+impl AuthUser {
+  pub fn right(&self) -> UserRight {
+    UserRight::from(self.user.usertype.clone())
+  }
+
+  /// 403 if the user type of caller does not have the capability,
+  /// or the api key of caller does not have the scope of it
+  pub fn require(&self, capability: Capability) -> Result<(), AuthError> {
+    if !self.right().can(capability) {
+      return Err(AuthError::Forbidden(format!(
+        "{:?} can not {:?}", self.user.usertype, capability
+      )));
+    }
+    if let Credential::ApiKey(_) = &self.credential {
+      match ApiScope::for_capability(capability) {
+        Some(scope) => self.require_scope(scope)?,
+        None => return self.require_session(),
+      }
+    }
+    Ok(())
+  }
 
-  let login_response = LoginResponse {
-    token: user.token,
+  /// sessions have all scopes
+  pub fn require_scope(&self, scope: ApiScope) -> Result<(), AuthError> {
+    match &self.credential {
+      Credential::ApiKey(k) if !k.allows(scope) => Err(AuthError::Forbidden(format!(
+        "api key {} has no {:?} scope", k.prefix, scope
+      ))),
+      _ => Ok(()),
+    }
+  }
+
+  /// account management can not be done with an api key
+  pub fn require_session(&self) -> Result<(), AuthError> {
+    match &self.credential {
+      Credential::Session(_) => Ok(()),
+      Credential::ApiKey(_) => Err(AuthError::Forbidden("need a login session".into())),
+    }
+  }
+
+  /// None for sessions, which are not limited by scopes
+  pub fn scopes(&self) -> Option<Vec<ApiScope>> {
+    match &self.credential {
+      Credential::Session(_) => None,
+      Credential::ApiKey(k) => Some(k.scopes.clone()),
+    }
+  }
+}
+
+impl actix_web::FromRequest for AuthUser {
+  type Error = AuthError;
+  type Future = std::pin::Pin<Box<dyn std::future::Future<Output = Result<Self, Self::Error>>>>;
+
+  fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+    let req = req.clone();
+    Box::pin(async move { Self::from_http_request(&req, false).await })
+  }
+}
+
+/// an AuthUser which may still need to enroll totp, only for totp endpoints
+pub struct EnrollingUser(pub AuthUser);
+
+impl actix_web::FromRequest for EnrollingUser {
+  type Error = AuthError;
+  type Future = std::pin::Pin<Box<dyn std::future::Future<Output = Result<Self, Self::Error>>>>;
+
+  fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+    let req = req.clone();
+    Box::pin(async move {
+      let auth = AuthUser::from_http_request(&req, true).await?;
+      auth.require_session()?;
+      Ok(EnrollingUser(auth))
+    })
+  }
+}
+
+/// the user type requires totp but the user has not enabled it
+pub fn totp_enroll_required(sqlhandler: &SqlHandler, user: &User) -> Result<bool, Err> {
+  if !sqlhandler.role_requires_totp(&user.usertype)? {
+    return Ok(false);
+  }
+  Ok(!matches!(sqlhandler.get_user_totp(user.id)?, Some(t) if t.enabled))
+}
+
+pub fn session_login_response(
+  sqlhandler: &SqlHandler,
+  user: User,
+  user_agent: &str,
+  config: &ServerConfig,
+) -> Result<LoginResponse, Err> {
+  let issued = issue_session(sqlhandler, &user, user_agent, config)?;
+  sqlhandler.user_login(&user.username)?;
+  Ok(LoginResponse {
+    token: issued.token,
+    refresh_token: issued.refresh_token,
+    expire_t: issued.session.expire_t,
+    totp_enroll_required: totp_enroll_required(sqlhandler, &user)?,
     basic_info: StreamBasicInfo {
       time_stamp: Time::now().milli(),
     },
     config: user.config,
-    code: ResponseCode::Ok,
+    ..Default::default()
+  })
+}
+
+pub fn do_login(
+  client: &ClientInfo,
+  param: &web::Json<LoginRequest>,
+  data: &web::Data<Arc<Server>>,
+) -> Result<LoginResponse, Err> {
+  log::info!("user try login: {}", param.login_info.username);
+  let sqlhandler = data.sqlhandler.clone();
+  sqlhandler.delete_expired_sessions(Time::now().milli())?;
+  let config = data.r_config();
+  let ip_key = LoginAttempt::ip_key(&client.ip);
+  let user_key = LoginAttempt::user_key(&param.login_info.username);
+  let attempt_keys = [ip_key.clone(), user_key.clone()];
+  check_login_attempts(&sqlhandler, &attempt_keys)?;
+
+  let password = match &param.login_info.choice {
+    LoginChoice::Password(password) => password,
+    // login with a session token keeps the session
+    LoginChoice::Token(token) => {
+      let (user, session) = match authenticate(&sqlhandler, &param.login_info.username, token) {
+        Ok(authenticated) => authenticated,
+        Err(e) => {
+          record_login_failure(&sqlhandler, &[ip_key], &config.login_throttle)?;
+          return Err(e);
+        }
+      };
+      sqlhandler.user_login(&user.username)?;
+      return Ok(LoginResponse {
+        token: token.clone(),
+        expire_t: session.expire_t,
+        totp_enroll_required: totp_enroll_required(&sqlhandler, &user)?,
+        basic_info: StreamBasicInfo {
+          time_stamp: Time::now().milli(),
+        },
+        config: user.config,
+        ..Default::default()
+      });
+    }
   };
-  sqlhandler.user_login(&user.username)?;
-  Ok(login_response)
+
+  // unknown users are not created here, see do_signup. 
+  // directory providers may create them on their first login
+  let user = match authenticate_password(&sqlhandler, &config, &param.login_info.username, password)? {
+    Some(u) => u,
+    None => {
+      record_login_failure(&sqlhandler, &attempt_keys, &config.login_throttle)?;
+      return Err(ApiError::err(ErrorCode::AuthFailed, "username or password not true"));
+    }
+  };
+  // the ip key is kept, or a guesser with an account of its own resets it between guesses
+  record_login_success(&sqlhandler, &[user_key])?;
+
+  // password is right, but the session is only issued after the totp step
+  if matches!(sqlhandler.get_user_totp(user.id)?, Some(t) if t.enabled) {
+    let ticket = data.w_add_pending_totp(PendingTotpLogin {
+      user_id: user.id,
+      user_agent: client.user_agent.clone(),
+      expire_t: Time::now().milli() + PENDING_TOTP_TTL,
+      attempts: 0,
+    });
+    return Ok(LoginResponse {
+      totp_ticket: ticket,
+      basic_info: StreamBasicInfo {
+        time_stamp: Time::now().milli(),
+      },
+      ..Default::default()
+    });
+  }
+  session_login_response(&sqlhandler, user, &client.user_agent, &config)
 }
 
+/// second step of a login with totp, the code may also be a recovery code
+pub fn do_login_totp(
+  client: &ClientInfo,
+  param: &web::Json<TotpLoginRequest>,
+  data: &web::Data<Arc<Server>>,
+) -> Result<LoginResponse, Err> {
+  let pending = match data.r_pending_totp(&param.ticket) {
+    Some(p) => p,
+    None => return Err(ApiError::err(ErrorCode::AuthFailed, "login ticket expired")),
+  };
+  let sqlhandler = data.sqlhandler.clone();
+  let mut totp = match sqlhandler.get_user_totp(pending.user_id)? {
+    Some(t) if t.enabled => t,
+    _ => return Err(ApiError::err(ErrorCode::InvalidRequest, "totp not enabled")),
+  };
+  let user_key = match sqlhandler.get_user_by_id(pending.user_id)? {
+    Some(u) => LoginAttempt::user_key(&u.username),
+    None => return Err(ApiError::err(ErrorCode::NotFound, "user not exists")),
+  };
+  let attempt_keys = [LoginAttempt::ip_key(&client.ip), user_key];
+  check_login_attempts(&sqlhandler, &attempt_keys)?;
+  if !totp.check(&param.code, Time::now().milli()) {
+    data.w_fail_pending_totp(&param.ticket);
+    record_login_failure(&sqlhandler, &attempt_keys, &data.r_config().login_throttle)?;
+    return Err(ApiError::err(ErrorCode::AuthFailed, "totp code not true"));
+  }
+  sqlhandler.save_user_totp(&totp)?;
+  data.w_remove_pending_totp(&param.ticket);
+  let user = match sqlhandler.get_user_by_id(pending.user_id)? {
+    Some(u) => u,
+    None => return Err(ApiError::err(ErrorCode::NotFound, "user not exists")),
+  };
+  log::info!("user {} passed totp", user.username);
+  session_login_response(&sqlhandler, user, &pending.user_agent, &data.r_config())
+}
+
+/// exchange a refresh token for a new session, the old one is revoked
+pub fn do_refresh(
+  param: &web::Json<RefreshRequest>,
+  data: &web::Data<Arc<Server>>,
+) -> Result<LoginResponse, Err> {
+  let sqlhandler = data.sqlhandler.clone();
+  let old = match sqlhandler.get_session_by_refresh_token(&hash_session_token(&param.refresh_token))? {
+    Some(s) => s,
+    None => return Err(ApiError::err(ErrorCode::Unauthorized, "session not exists")),
+  };
+  if !old.is_refreshable(Time::now().milli()) {
+    return Err(ApiError::err(ErrorCode::Unauthorized, "session expired or revoked"));
+  }
+  let user = match sqlhandler.get_user_by_id(old.user_id)? {
+    Some(u) => u,
+    None => return Err(ApiError::err(ErrorCode::NotFound, "user not exists")),
+  };
+  sqlhandler.revoke_session(old.id)?;
+  let issued = issue_session(&sqlhandler, &user, &old.user_agent, &data.r_config())?;
+  log::info!("user {} refreshed session {} -> {}", user.username, old.id, issued.session.id);
+  Ok(LoginResponse {
+    token: issued.token,
+    refresh_token: issued.refresh_token,
+    expire_t: issued.session.expire_t,
+    totp_enroll_required: totp_enroll_required(&sqlhandler, &user)?,
+    basic_info: StreamBasicInfo {
+      time_stamp: Time::now().milli(),
+    },
+    config: user.config,
+    ..Default::default()
+  })
+}
 
 pub fn do_logout(
-  param: &web::Json<LogoutRequest>,
+  auth: &AuthUser,
+  _param: &web::Json<LogoutRequest>,
   data: &web::Data<Arc<Server>>,
 ) -> Result<LogoutResponse, Err> {
-  log::info!(
-    "user try logout: {}",
-    serde_json::to_string(&param).unwrap()
-  );
-  let sqlhandler = SqlHandler::new(data.dbpool.clone());
-
-  match sqlhandler.get_user_by_name(&param.username)? {
-    Some(u) => {
-      assert_eq!(&u.username, &param.username);
-      assert_eq!(&u.token, &param.token);
-      u
-    }
-    None => {
-      return Err(Box::from("logout user not exists"));
+  log::info!("user try logout: {}", auth.user.username);
+  let sqlhandler = data.sqlhandler.clone();
+  match &auth.credential {
+    Credential::Session(session) => sqlhandler.revoke_session(session.id)?,
+    Credential::ApiKey(_) => {
+      return Err(ApiError::err(ErrorCode::InvalidRequest, "api keys are revoked, not logged out"))
     }
-  };
+  }
+  data.close_token_ctxs(&auth.user.username, &auth.token_hash, "logged out");
 
   let logout_response = LogoutResponse {
     basic_info: StreamBasicInfo {
@@ -256,7 +772,7 @@ pub fn do_logout(
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
   use super::*;
   // mock a client call server's api
   struct ApiClient {
@@ -289,11 +805,20 @@ mod tests {
     }
 
     fn login(&mut self, req: &LoginRequest) -> io::Result<LoginResponse> {
+      self.post_for_login_response("/login", serde_json::to_string(req).unwrap(), "200 OK")
+    }
+
+    fn signup(&mut self, req: &SignupRequest) -> io::Result<LoginResponse> {
+      self.post_for_login_response("/signup", serde_json::to_string(req).unwrap(), "200 OK")
+    }
+
+    fn post_for_login_response(&mut self, path: &str, json_body: String, status: &str) 
+      -> io::Result<LoginResponse> {
       self.stream = TcpStream::connect(self.addr.to_string())?;
 
-      let json_body = serde_json::to_string(req).unwrap();
       let request = format!(
-				"POST /login HTTP/1.1\r\nHost: localhost:9999\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+				"POST {} HTTP/1.1\r\nHost: localhost:9999\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+				path,
 				json_body.len(),
 				json_body
 			);
@@ -301,7 +826,7 @@ mod tests {
       let mut response = String::new();
       let _ = self.stream.read_to_string(&mut response)?;
       log::info!("Server LOGIN RESPONSE {response}");
-      assert!(self.check_response_str(&response), "{response}");
+      assert!(response.starts_with(&format!("HTTP/1.1 {}", status)), "{response}");
 
       let (headers, body) = response.split_once("\r\n\r\n").ok_or(io::Error::new(
         io::ErrorKind::InvalidData,
@@ -334,12 +859,13 @@ mod tests {
       }
     }
 
-    fn logout(&mut self, req: &LogoutRequest) -> io::Result<LogoutResponse> {
+    fn logout(&mut self, req: &LogoutRequest, token: &String) -> io::Result<LogoutResponse> {
       self.stream = TcpStream::connect(self.addr.to_string())?;
 
       let json_body = serde_json::to_string(req).unwrap();
       let request = format!(
-				"POST /logout HTTP/1.1\r\nHost: localhost:9999\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+				"POST /logout HTTP/1.1\r\nHost: localhost:9999\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+				token,
 				json_body.len(),
 				json_body
 			);
@@ -371,6 +897,22 @@ mod tests {
       )
     }
 
+    /// the raw response of a json post with a bearer token
+    fn post_with_token(&mut self, path: &str, token: &str, json_body: String) -> io::Result<String> {
+      self.stream = TcpStream::connect(self.addr.to_string())?;
+      let request = format!(
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        path,
+        token,
+        json_body.len(),
+        json_body
+      );
+      self.stream.write_all(request.as_bytes())?;
+      let mut response = String::new();
+      self.stream.read_to_string(&mut response)?;
+      Ok(response)
+    }
+
     fn check_logout_resp(&self, resp: &LogoutResponse) {
       match &resp.code {
         ResponseCode::Ok => (),
@@ -381,29 +923,41 @@ mod tests {
     }
   }
 
-  struct TestServer {
-    server: Arc<Server>,
+  pub(crate) struct TestServer {
+    pub(crate) server: Arc<Server>,
+    _db: crate::sql::tests::TempDb,
   }
 
   impl TestServer {
-    fn new(loglevel: &String, addr: &String) -> Self {
+    pub(crate) fn new(loglevel: &String, addr: &String) -> Self {
+      let db = crate::sql::tests::TempDb::default();
       let server_config = ServerConfig {
         loglevel: loglevel.clone(),
-        cwd: String::from("/home/wu/repository/pulsear"),
+        // the workspace root, where pulsear-ui lives
+        cwd: format!("{}/..", env!("CARGO_MANIFEST_DIR")),
         inner_addr: addr.clone(),
         worker_num: 4,
-        managers: vec![],
         https: false,
         file_worker_num: 4,
-        sql_url: "mysql://wu:wi@localhost:3307/pulsear".to_string(),
+        sql_url: db.url(),
+        session_ttl: 60,
+        refresh_ttl: 120,
+        login_throttle: LoginThrottleConfig::default(),
+        rate_limit: RateLimitConfig::default(),
+        registration: RegistrationMode::Open,
+        auth_providers: default_auth_providers(),
+        upload_window: default_upload_window(),
+        file_writes: FileWriteConfig::default(),
+        websocket: WsConfig::default(),
       };
-      let server = Arc::new(Server::from(server_config));
+      let server = Arc::new(Server::from(server_config).unwrap());
       return TestServer {
         server: server.clone(),
+        _db: db,
       };
     }
 
-    async fn run(&self) {
+    pub(crate) async fn run(&self) {
       let server_ret = self.server.clone();
       actix_web::rt::spawn(async move { start(server_ret, false).await });
       actix_web::rt::time::sleep(std::time::Duration::from_millis(300)).await;
@@ -417,6 +971,13 @@ mod tests {
       }
     }
 
+    fn session_valid(&self, token: &str) -> bool {
+      match self.server.sqlhandler.get_session_by_token(&hash_session_token(token)) {
+        Ok(Some(session)) => session.is_valid(Time::now().milli()),
+        _ => false,
+      }
+    }
+
     fn current_online_user_num_by_name(&self, username: &String) -> usize {
       match self.current_online_user_ctx_by_name(username) {
         Some(v) => v.len(),
@@ -429,7 +990,7 @@ mod tests {
   use std::net::TcpStream;
   #[actix_web::test]
   async fn indexhtml() -> std::io::Result<()> {
-    let addr = "0.0.0.0:9999";
+    let addr = "0.0.0.0:9998";
     let server = TestServer::new(&String::from("info"), &addr.to_string());
     server.run().await;
     let mut client = ApiClient::new(&addr.to_string())?;
@@ -444,6 +1005,15 @@ mod tests {
     server.run().await;
     let mut client = ApiClient::new(&addr.to_string())?;
     let username = String::from("test0");
+    // the user may exist from an earlier run, login below checks it
+    client.signup(&SignupRequest {
+      basic_info: StreamBasicInfo {
+        time_stamp: Time::now().milli(),
+      },
+      username: username.clone(),
+      password: username.clone(),
+      invite_code: None,
+    })?;
     let login_request = LoginRequest {
       basic_info: StreamBasicInfo {
         time_stamp: Time::now().milli(),
@@ -453,29 +1023,185 @@ mod tests {
         choice: LoginChoice::Password(username.clone()),
       },
     };
-    let resp = client.login(&login_request)?;
-    client.check_login_resp(&resp);
-    assert_eq!(server.current_online_user_num_by_name(&username), 1);
-    let resp = client.login(&login_request)?;
-    client.check_login_resp(&resp);
-    assert_eq!(server.current_online_user_num_by_name(&username), 2);
+    let first = client.login(&login_request)?;
+    client.check_login_resp(&first);
+    let second = client.login(&login_request)?;
+    client.check_login_resp(&second);
+    let wrong = serde_json::json!({
+      "basic_info": { "time_stamp": 0 },
+      "login_info": { "username": username, "choice": { "Password": "wrong" } },
+    });
+    let failed = client.post_for_login_response("/login", wrong.to_string(), "401 Unauthorized")?;
+    match failed.code {
+      ResponseCode::Err(e) => assert_eq!(e.code, ErrorCode::AuthFailed),
+      ResponseCode::Ok => panic!("login with a wrong password"),
+    }
+    // websockets are only counted once they connect, logins are sessions
+    assert_eq!(server.current_online_user_num_by_name(&username), 0);
+    assert!(server.session_valid(&first.token));
+    assert!(server.session_valid(&second.token));
 
     let logout_request = LogoutRequest {
       basic_info: StreamBasicInfo {
         time_stamp: Time::now().milli(),
       },
-      username: username.clone(),
-      token: resp.token.clone(),
     };
-    let resp = client.logout(&logout_request)?;
+    let resp = client.logout(&logout_request, &second.token)?;
     client.check_logout_resp(&resp);
-    assert_eq!(server.current_online_user_num_by_name(&username), 1);
-    let resp = client.logout(&logout_request)?;
+    assert!(!server.session_valid(&second.token));
+    assert!(server.session_valid(&first.token));
+    let resp = client.logout(&logout_request, &first.token)?;
     client.check_logout_resp(&resp);
-    assert_eq!(server.current_online_user_num_by_name(&username), 0);
+    assert!(!server.session_valid(&first.token));
+    Ok(())
+  }
+
+  #[actix_web::test]
+  async fn totp_login_cookie() -> std::io::Result<()> {
+    let addr = "0.0.0.0:9989";
+    let server = TestServer::new(&String::from("error"), &addr.to_string());
+    server.run().await;
+    let sqlhandler = &server.server.sqlhandler;
+    let username = "cookie0";
+    let user = match sqlhandler.get_user_by_name(username).unwrap() {
+      Some(u) => u,
+      None => sqlhandler.add_user(&User {
+        id: 0,
+        username: username.into(),
+        token: password_token(username, username),
+        config: UserConfig::default(),
+        usertype: UserType::default(),
+      }).unwrap().unwrap(),
+    };
+    let mut client = ApiClient::new(&addr.to_string())?;
+    let body = serde_json::json!({
+      "basic_info": { "time_stamp": 0 },
+      "login_info": { "username": username, "choice": { "Password": username } },
+    }).to_string();
+    let response = client.post_with_token("/login", "", body.clone())?;
+    assert!(response.contains("set-cookie: pulsear_token="), "{response}");
+    // the password step of a totp user sets no cookie, an empty one would end the session of it
+    sqlhandler.save_user_totp(&UserTotp {
+      user_id: user.id,
+      secret: gen_totp_secret(),
+      enabled: true,
+      recovery_codes: vec![],
+      last_used_step: 0,
+    }).unwrap();
+    let response = client.post_with_token("/login", "", body)?;
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(!response.contains("set-cookie"), "{response}");
+    Ok(())
+  }
+
+  #[actix_web::test]
+  async fn file_name_paths() -> std::io::Result<()> {
+    let addr = "0.0.0.0:9990";
+    let server = TestServer::new(&String::from("error"), &addr.to_string());
+    server.run().await;
+    let sqlhandler = &server.server.sqlhandler;
+    let username = "pathuser0";
+    let user = match sqlhandler.get_user_by_name(username).unwrap() {
+      Some(u) => u,
+      None => sqlhandler.add_user(&User {
+        id: 0,
+        username: username.into(),
+        token: password_token(username, username),
+        config: UserConfig::default(),
+        usertype: UserType::default(),
+      }).unwrap().unwrap(),
+    };
+    let issued = issue_session(sqlhandler, &user, "", &server.server.r_config()).unwrap();
+    let mut client = ApiClient::new(&addr.to_string())?;
+    // a name never leaves the folder of its owner, nor becomes a link out of it
+    let body = serde_json::json!({ "name": "../bob/secret" }).to_string();
+    for path in ["/download_raw", "/file", "/get_download_url"] {
+      let response = client.post_with_token(path, &issued.token, body.clone())?;
+      assert!(response.starts_with("HTTP/1.1 400"), "{path}: {response}");
+      assert!(response.contains("\"invalid_name\""), "{path}: {response}");
+    }
     Ok(())
   }
 
+  #[test]
+  fn login_throttle_ip() {
+    let server = TestServer::new(&String::from("error"), &String::from("127.0.0.1:0"));
+    let data = web::Data::new(server.server.clone());
+    for username in ["victim0", "guesser0"] {
+      server.server.sqlhandler.add_user(&User {
+        id: 0,
+        username: username.into(),
+        token: password_token(username, username),
+        config: UserConfig::default(),
+        usertype: UserType::default(),
+      }).unwrap();
+    }
+    let client = ClientInfo {
+      ip: "10.0.0.1".into(),
+      user_agent: String::new(),
+    };
+    let login = |username: &str, choice: LoginChoice| {
+      do_login(&client, &web::Json(LoginRequest {
+        basic_info: StreamBasicInfo { time_stamp: 0 },
+        login_info: LoginInfo { username: username.into(), choice },
+      }), &data)
+    };
+    let code = |r: Result<LoginResponse, Err>| ApiError::of(r.err().unwrap(), ErrorCode::Internal).code;
+    let free = LoginThrottleConfig::default().free_attempts;
+    for _ in 0..free {
+      assert_eq!(code(login("victim0", LoginChoice::Password("wrong".into()))), ErrorCode::AuthFailed);
+    }
+    // logging into an own account between guesses does not reset the ip
+    assert!(login("guesser0", LoginChoice::Password("guesser0".into())).is_ok());
+    assert_eq!(code(login("victim0", LoginChoice::Token("wrong".into()))), ErrorCode::Unauthorized);
+    assert_eq!(code(login("guesser0", LoginChoice::Password("guesser0".into()))), ErrorCode::RateLimited);
+    let other = ClientInfo {
+      ip: "10.0.0.2".into(),
+      user_agent: String::new(),
+    };
+    let from_other = do_login(&other, &web::Json(LoginRequest {
+      basic_info: StreamBasicInfo { time_stamp: 0 },
+      login_info: LoginInfo { username: "guesser0".into(), choice: LoginChoice::Password("guesser0".into()) },
+    }), &data);
+    assert!(from_other.is_ok());
+  }
+
+  #[test]
+  fn session_validity() {
+    let mut session = Session {
+      id: 1,
+      user_id: 1,
+      user_agent: String::new(),
+      create_t: 100,
+      expire_t: 200,
+      refresh_expire_t: 300,
+      revoked: false,
+    };
+    assert!(session.is_valid(150));
+    assert!(!session.is_valid(200));
+    assert!(session.is_refreshable(250));
+    assert!(!session.is_refreshable(300));
+    session.revoked = true;
+    assert!(!session.is_valid(150));
+    assert!(!session.is_refreshable(150));
+    assert_ne!(hash_session_token(&random_token()), hash_session_token(&random_token()));
+  }
+
+  #[test]
+  fn capability() {
+    assert!(UserRight::from(UserType::Master).can(Capability::ManageUsers));
+    assert!(UserRight::from(UserType::Manager).can(Capability::Broadcast));
+    assert!(!UserRight::from(UserType::User).can(Capability::ManageUsers));
+    assert!(!UserRight::from(UserType::Member).can(Capability::Broadcast));
+    assert!(UserRight::from(UserType::User).can(Capability::Upload));
+    assert!(!UserRight::from(UserType::Visiter).can(Capability::Upload));
+    // online numbers of the whole server are for who manages it
+    assert!(UserRight::from(UserType::Manager).can(Capability::ViewDashboard));
+    assert!(!UserRight::from(UserType::Member).can(Capability::ViewDashboard));
+    assert!(!UserRight::from(UserType::Visiter).can(Capability::ViewDashboard));
+    assert!(UserType::Master.rank() < UserType::Visiter.rank());
+  }
+
   #[test]
   fn usertype() {
     let t = UserType::Master;
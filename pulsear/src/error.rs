@@ -0,0 +1,151 @@
+use crate::*;
+use actix_web::http::StatusCode;
+
+/// kind of an api error. the serialized names are stable, clients match on them
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+  AuthFailed,    // wrong username, password or totp code
+  Unauthorized,  // no token, or it expired or was revoked
+  Forbidden,
+  NotFound,
+  Conflict,
+  QuotaExceeded,
+  InvalidName,
+  InvalidRequest,
+  RateLimited,
+  Incompatible,  // the client speaks no protocol version the server does
+  Internal,
+}
+
+impl ErrorCode {
+  pub fn status(&self) -> StatusCode {
+    match self {
+      ErrorCode::AuthFailed | ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+      ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+      ErrorCode::NotFound => StatusCode::NOT_FOUND,
+      ErrorCode::Conflict => StatusCode::CONFLICT,
+      ErrorCode::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+      ErrorCode::InvalidName | ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+      ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+      ErrorCode::Incompatible => StatusCode::UPGRADE_REQUIRED,
+      ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+}
+
+/// an error with its code, the body of every failed http request and
+/// the content of a ws Error message
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone, PartialEq)]
+pub struct ApiError {
+  pub code: ErrorCode,
+  pub message: String,
+}
+
+impl ApiError {
+  pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+    Self {
+      code,
+      message: message.into(),
+    }
+  }
+
+  /// boxed, for functions returning Err
+  pub fn err(code: ErrorCode, message: impl Into<String>) -> Err {
+    Box::new(Self::new(code, message))
+  }
+
+  /// the code of a typed error, an untyped one gets otherwise
+  pub fn of(e: Err, otherwise: ErrorCode) -> Self {
+    let e = match e.downcast::<ApiError>() {
+      Ok(e) => return *e,
+      Err(e) => e,
+    };
+    let e = match e.downcast::<AuthError>() {
+      Ok(e) => return (*e).into(),
+      Err(e) => e,
+    };
+    match e.downcast::<std::io::Error>() {
+      Ok(e) if e.kind() == std::io::ErrorKind::NotFound => Self::new(ErrorCode::NotFound, e.to_string()),
+      Ok(e) => Self::internal(e.to_string()),
+      Err(e) if otherwise == ErrorCode::Internal => Self::internal(e.to_string()),
+      Err(e) => Self::new(otherwise, e.to_string()),
+    }
+  }
+
+  /// the cause is only logged, it may tell about the server
+  fn internal(cause: String) -> Self {
+    log::error!("internal error: {}", cause);
+    Self::new(ErrorCode::Internal, "internal error")
+  }
+}
+
+impl fmt::Display for ApiError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<Err> for ApiError {
+  fn from(e: Err) -> Self {
+    Self::of(e, ErrorCode::Internal)
+  }
+}
+
+impl From<std::io::Error> for ApiError {
+  fn from(e: std::io::Error) -> Self {
+    Self::of(Box::new(e), ErrorCode::Internal)
+  }
+}
+
+impl From<serde_json::Error> for ApiError {
+  fn from(e: serde_json::Error) -> Self {
+    Self::internal(e.to_string())
+  }
+}
+
+impl From<AuthError> for ApiError {
+  fn from(e: AuthError) -> Self {
+    match e {
+      AuthError::Unauthorized(m) => Self::new(ErrorCode::Unauthorized, m),
+      AuthError::Forbidden(m) => Self::new(ErrorCode::Forbidden, m),
+    }
+  }
+}
+
+impl actix_web::ResponseError for ApiError {
+  fn status_code(&self) -> StatusCode {
+    self.code.status()
+  }
+
+  fn error_response(&self) -> HttpResponse {
+    HttpResponse::build(self.status_code()).json(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn api_error_of() {
+    let typed = ApiError::of(ApiError::err(ErrorCode::Conflict, "user exists"), ErrorCode::InvalidRequest);
+    assert_eq!(typed, ApiError::new(ErrorCode::Conflict, "user exists"));
+    assert_eq!(typed.to_string(), "user exists");
+    let untyped = ApiError::of(Box::from("bad column"), ErrorCode::InvalidRequest);
+    assert_eq!(untyped.code, ErrorCode::InvalidRequest);
+    assert_eq!(untyped.message, "bad column");
+    let auth = ApiError::of(Box::new(AuthError::Forbidden("no".into())), ErrorCode::InvalidRequest);
+    assert_eq!(auth.code, ErrorCode::Forbidden);
+    let missing = std::fs::File::open("/not/a/pulsear/file").unwrap_err();
+    assert_eq!(ApiError::from(missing).code, ErrorCode::NotFound);
+    // the cause of an internal error is not sent
+    let internal = ApiError::from(Box::<dyn std::error::Error>::from("db password wrong"));
+    assert_eq!(internal.message, "internal error");
+
+    assert_eq!(serde_json::to_string(&ErrorCode::QuotaExceeded).unwrap(), "\"quota_exceeded\"");
+    assert_eq!(ErrorCode::RateLimited.status(), StatusCode::TOO_MANY_REQUESTS);
+  }
+}
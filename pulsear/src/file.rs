@@ -1,9 +1,9 @@
 use crate::*;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
 
-// all thing about a file and its transfer
-// binary package:
-//  bytes:   |   32      |  4        |  slice_size  |
-//  meaning: |  file_hash| slice_idx | file content |
+// all thing about a file and its transfer, a slice of it is sent in a
+// binary package, see SliceFrame
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct FileRequest {
   pub username: String,
@@ -14,6 +14,76 @@ pub struct FileRequest {
   pub file_hash: String,
 }
 
+pub const SLICE_FRAME_MAGIC: &[u8; 4] = b"PSLC";
+pub const SLICE_FRAME_VERSION: u8 = 2;
+pub const SLICE_FRAME_HEADER: usize = 56;
+
+/// a binary package, numbers are little endian.
+/// v2:
+///  bytes:   | 4     | 1       | 3        | 32        | 8         | 4   | 4               | len     |
+///  meaning: | magic | version | reserved | file_hash | slice_idx | len | crc32c of slice | slice   |
+/// v1, sent by clients of protocol 1:
+///  bytes:   | 32        | 4         | slice_size |
+///  meaning: | file_hash | slice_idx | slice      |
+#[derive(std::fmt::Debug)]
+pub struct SliceFrame {
+  pub version: u8,
+  pub file_hash: String,
+  pub index: u64,
+  pub data: bytes::Bytes,
+  // false if the length or checksum does not match, the slice is sent again
+  pub intact: bool,
+}
+
+impl SliceFrame {
+  pub fn parse(mut bytes: bytes::Bytes) -> Result<Self, Err> {
+    let hash_hex = |hash: &[u8]| -> String { hash.iter().map(|b| format!("{:02x}", b)).collect() };
+    if !bytes.starts_with(SLICE_FRAME_MAGIC) {
+      if bytes.len() < 36 {
+        return Err(ApiError::err(ErrorCode::InvalidRequest, "binary frame too short"));
+      }
+      let file_hash = hash_hex(&bytes[0..32]);
+      let index = bytes.slice(32..36).get_u32_le() as u64;
+      return Ok(Self { version: 1, file_hash, index, data: bytes.slice(36..), intact: true });
+    }
+    if bytes.len() < SLICE_FRAME_HEADER {
+      return Err(ApiError::err(ErrorCode::InvalidRequest, "binary frame too short"));
+    }
+    if bytes[4] != SLICE_FRAME_VERSION || bytes[5..8] != [0; 3] {
+      return Err(ApiError::err(ErrorCode::InvalidRequest, format!("unknown binary frame version {}", bytes[4])));
+    }
+    let mut header = bytes.split_to(SLICE_FRAME_HEADER);
+    let file_hash = hash_hex(&header[8..40]);
+    header.advance(40);
+    let index = header.get_u64_le();
+    let len = header.get_u32_le() as usize;
+    let crc = header.get_u32_le();
+    let intact = bytes.len() == len && crc32c::crc32c(&bytes) == crc;
+    Ok(Self { version: SLICE_FRAME_VERSION, file_hash, index, data: bytes, intact })
+  }
+
+  /// a v2 frame of a slice, file_hash is hex
+  pub fn encode(file_hash: &str, index: u64, data: &[u8]) -> Result<bytes::Bytes, Err> {
+    use bytes::BufMut;
+    let hash = (0..file_hash.len())
+      .step_by(2)
+      .map(|i| file_hash.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+      .collect::<Option<Vec<u8>>>()
+      .filter(|h| h.len() == 32)
+      .ok_or_else(|| ApiError::new(ErrorCode::InvalidRequest, "file hash is not a sha256"))?;
+    let mut frame = bytes::BytesMut::with_capacity(SLICE_FRAME_HEADER + data.len());
+    frame.put_slice(SLICE_FRAME_MAGIC);
+    frame.put_u8(SLICE_FRAME_VERSION);
+    frame.put_bytes(0, 3);
+    frame.put_slice(&hash);
+    frame.put_u64_le(index);
+    frame.put_u32_le(data.len() as u32);
+    frame.put_u32_le(crc32c::crc32c(data));
+    frame.put_slice(data);
+    Ok(frame.freeze())
+  }
+}
+
 #[derive(serde::Deserialize, serde::Serialize)]
 pub enum FileResponseStatus {
   Ok,
@@ -28,6 +98,9 @@ pub struct FileResponse {
   pub file_hash: String,
   pub slice_idx: (u64, u64),
   pub status: FileResponseStatus,
+  // slices the uploader may send more, granted as slices are persisted or refused
+  #[serde(default)]
+  pub credits: u64,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -95,123 +168,485 @@ impl FileListElem {
   }
 }
 
+/// one page of the files at a path, ordered and with the columns of the view of the path
 #[derive(serde::Serialize, serde::Deserialize)]
-struct FileList {
-  files: Vec<FileListElem>
+pub struct FileList {
+  pub path: String,
+  pub config: FileListPathConfig,
+  // each file only has the columns of config
+  pub files: Vec<serde_json::Map<String, serde_json::Value>>,
+  // pass as cursor to get the next page, none on the last one
+  pub next_cursor: Option<String>,
+}
+
+fn root_path() -> String {
+  "/".to_string()
 }
 
+/// a page holds this many files unless the request asks for another limit
+pub const FILE_LIST_PAGE: usize = 1000;
+pub const FILE_LIST_MAX_PAGE: usize = 10000;
+
+/// order_by and order_asc override the view of the path for this request only
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct FileListRequest {
-  username: String,
-  token: String
+  #[serde(default = "root_path")]
+  pub path: String,
+  pub cursor: Option<String>,
+  pub limit: Option<usize>,
+  pub order_by: Option<String>,
+  pub order_asc: Option<bool>,
+  #[serde(default)]
+  pub filter: FileListFilter,
+}
+
+/// all set conditions must hold. times are "%Y-%m-%d %H:%M:%S" or a prefix of it
+#[derive(serde::Serialize, serde::Deserialize, Default, Clone)]
+pub struct FileListFilter {
+  // case insensitive part of the name
+  pub name: Option<String>,
+  // case insensitive extension, without the dot
+  #[serde(rename = "type")]
+  pub file_type: Option<String>,
+  pub min_size: Option<u64>,
+  pub max_size: Option<u64>,
+  pub modified_after: Option<String>,
+  pub modified_before: Option<String>,
+}
+
+impl FileListFilter {
+  pub fn needs_metadata(&self) -> bool {
+    self.min_size.is_some() || self.max_size.is_some() 
+      || self.modified_after.is_some() || self.modified_before.is_some()
+  }
+
+  pub fn matches_name(&self, name: &str) -> bool {
+    if let Some(part) = &self.name {
+      if !name.to_lowercase().contains(&part.to_lowercase()) {
+        return false;
+      }
+    }
+    match &self.file_type {
+      Some(t) => match name.rsplit_once('.') {
+        Some((_, ext)) => ext.eq_ignore_ascii_case(t.trim_start_matches('.')),
+        None => false,
+      },
+      None => true,
+    }
+  }
+
+  pub fn matches(&self, file: &FileListElem, size: u64) -> bool {
+    self.matches_name(&file.name)
+      && self.min_size.is_none_or(|min| size >= min)
+      && self.max_size.is_none_or(|max| size <= max)
+      && self.modified_after.as_ref().is_none_or(|t| file.modify_t.as_str() >= t.as_str())
+      && self.modified_before.as_ref().is_none_or(|t| file.modify_t.as_str() < t.as_str())
+  }
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct FileElemRequest {
   pub name: String,
-  pub username: String,
-  pub token: String
 }
 
-pub fn do_get_file_list(param: web::Json<FileListRequest>, data: web::Data<Arc<Server>>) 
-  -> Result<HttpResponse, Err> {
-  let mut list = FileList {
-    files: vec![]
-  };
-  let sqlhandler = SqlHandler::new(data.dbpool.clone());
-  let user = match sqlhandler.get_user_by_name(&param.username)? {
-    Some(u) => {
-      assert_eq!(&u.username, &param.username);
-      assert_eq!(&u.token, &param.token);
-      u
+/// how the file list at a path is shown
+#[derive(serde::Serialize, serde::Deserialize, std::fmt::Debug, Clone)]
+pub struct FileListView {
+  pub path: String,
+  pub config: FileListPathConfig,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FileListViewQuery {
+  #[serde(default = "root_path")]
+  pub path: String,
+}
+
+/// what a listing is ordered by, ties are ordered by name. times are
+/// formatted such that their text order is their time order
+#[derive(serde::Serialize, serde::Deserialize, std::fmt::Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FileSortKey {
+  Name,
+  Bytes(u64),
+  Time(String),
+}
+
+impl FileSortKey {
+  pub fn of(file: &FileListElem, size: u64, order_by: &str) -> Self {
+    match order_by {
+      "size" => Self::Bytes(size),
+      "create_t" => Self::Time(file.create_t.clone()),
+      "access_t" => Self::Time(file.access_t.clone()),
+      "modify_t" => Self::Time(file.modify_t.clone()),
+      _ => Self::Name,
     }
-    None => {
-      return Err(Box::from("user not exists"));
+  }
+}
+
+/// a page ends at this file, the next one starts after it. only valid for
+/// the order it was made in
+#[derive(serde::Serialize, serde::Deserialize, std::fmt::Debug, PartialEq)]
+pub struct FileListCursor {
+  pub order_by: String,
+  pub order_asc: bool,
+  pub key: FileSortKey,
+  pub name: String,
+}
+
+impl FileListCursor {
+  pub fn at(file: &FileListElem, size: u64, view: &FileListPathConfig) -> Self {
+    Self {
+      order_by: view.order_by.clone(),
+      order_asc: view.order_asc,
+      key: FileSortKey::of(file, size, &view.order_by),
+      name: file.name.clone(),
     }
+  }
+
+  /// opaque to clients
+  pub fn encode(&self) -> String {
+    serde_json::to_string(self).unwrap_or_default()
+  }
+
+  pub fn decode(cursor: &str, view: &FileListPathConfig) -> Result<Self, Err> {
+    let cursor: Self = serde_json::from_str(cursor)
+      .map_err(|_| ApiError::new(ErrorCode::InvalidRequest, "bad cursor"))?;
+    if cursor.order_by != view.order_by || cursor.order_asc != view.order_asc {
+      return Err(ApiError::err(ErrorCode::InvalidRequest, "cursor is of another order"));
+    }
+    Ok(cursor)
+  }
+
+  /// whether a file with key and name comes after the cursor
+  pub fn is_before(&self, key: &FileSortKey, name: &str) -> bool {
+    let ord = (key, name).cmp(&(&self.key, self.name.as_str()));
+    if self.order_asc { ord.is_gt() } else { ord.is_lt() }
+  }
+}
+
+/// order files by a column of the view, size by bytes
+pub fn sort_file_list(files: &mut [(FileListElem, u64)], view: &FileListPathConfig) {
+  files.sort_by_cached_key(|(f, size)| (FileSortKey::of(f, *size, &view.order_by), f.name.clone()));
+  if !view.order_asc {
+    files.reverse();
+  }
+}
+
+/// keep the columns of the view only
+pub fn file_list_columns(file: &FileListElem, view: &FileListPathConfig) 
+  -> Result<serde_json::Map<String, serde_json::Value>, Err> {
+  let mut all = match serde_json::to_value(file)? {
+    serde_json::Value::Object(o) => o,
+    _ => return Err(Box::from("unexpected")),
   };
+  Ok(view.columns.iter().filter_map(|c| all.remove_entry(c)).collect())
+}
+
+/// the view of the path with the order of the request
+pub fn file_list_request_view(user: &User, param: &FileListRequest) -> Result<FileListPathConfig, Err> {
+  let mut view = user.config.filelist_config.get(&param.path);
+  if let Some(order_by) = &param.order_by {
+    view.order_by = order_by.clone();
+  }
+  if let Some(order_asc) = param.order_asc {
+    view.order_asc = order_asc;
+  }
+  view.check()?;
+  Ok(view)
+}
 
+/// none if the file was removed since its folder was read
+fn stat_file(folder: &std::path::Path, name: String) -> Result<Option<(FileListElem, u64)>, Err> {
+  match std::fs::metadata(folder.join(&name)) {
+    Ok(metadata) => {
+      let size = metadata.size();
+      Ok(Some((FileListElem::from_name_and_metadata(name, metadata)?, size)))
+    }
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(Box::new(e)),
+  }
+}
+
+pub type FileListEntries = Box<dyn Iterator<Item = Result<(FileListElem, u64), Err>> + Send>;
+
+/// the files of folder after the cursor, filtered and in the order of view.
+/// when neither the order nor the filter need more than the name, files are
+/// only stat'ed as they are taken, such that a page of a large folder is cheap
+pub fn list_folder(
+  folder: std::path::PathBuf, 
+  view: &FileListPathConfig, 
+  cursor: Option<&str>, 
+  filter: &FileListFilter
+) -> Result<FileListEntries, Err> {
+  let cursor = cursor.map(|c| FileListCursor::decode(c, view)).transpose()?;
+  let mut names = vec![];
+  for entry in std::fs::read_dir(&folder)? {
+    let Ok(name) = entry?.file_name().into_string() else {
+      continue;
+    };
+    if filter.matches_name(&name) {
+      names.push(name);
+    }
+  }
+  if view.order_by == "name" && !filter.needs_metadata() {
+    names.sort();
+    if !view.order_asc {
+      names.reverse();
+    }
+    if let Some(c) = &cursor {
+      names.retain(|n| c.is_before(&FileSortKey::Name, n));
+    }
+    return Ok(Box::new(names.into_iter().filter_map(move |name| stat_file(&folder, name).transpose())));
+  }
+  let mut files = vec![];
+  for name in names {
+    if let Some((file, size)) = stat_file(&folder, name)? {
+      if filter.matches(&file, size) {
+        files.push((file, size));
+      }
+    }
+  }
+  sort_file_list(&mut files, view);
+  if let Some(c) = &cursor {
+    files.retain(|(f, size)| c.is_before(&FileSortKey::of(f, *size, &view.order_by), &f.name));
+  }
+  Ok(Box::new(files.into_iter().map(Ok)))
+}
+
+/// the view and all files of a request from its cursor on, blocks on the disk
+pub fn file_list_entries(user: &User, param: &FileListRequest) 
+  -> Result<(FileListPathConfig, FileListEntries), Err> {
+  let view = file_list_request_view(user, param)?;
   let storage = std::path::PathBuf::from("inner/storage");
   let userfolder = storage.join(&user.username);
   if !userfolder.exists() {
     std::fs::create_dir_all(&userfolder)?;
   }
-  let read_dir = std::fs::read_dir(userfolder)?;
-  for path in read_dir {
-    let entry = path?;
-    list.files.push(FileListElem::from_name_and_metadata(
-      entry.file_name().into_string().unwrap(), 
-      entry.metadata()?
-    )?);
+  let entries = list_folder(userfolder, &view, param.cursor.as_deref(), &param.filter)?;
+  Ok((view, entries))
+}
+
+/// a page of limit files from entries, with the cursor of the next one
+pub fn file_list_page(
+  path: String, 
+  view: FileListPathConfig, 
+  entries: FileListEntries, 
+  limit: Option<usize>
+) -> Result<FileList, Err> {
+  let limit = limit.unwrap_or(FILE_LIST_PAGE).clamp(1, FILE_LIST_MAX_PAGE);
+  let mut page = entries.take(limit + 1).collect::<Result<Vec<_>, _>>()?;
+  let mut next_cursor = None;
+  if page.len() > limit {
+    page.truncate(limit);
+    next_cursor = page.last().map(|(f, size)| FileListCursor::at(f, *size, &view).encode());
+  }
+  Ok(FileList {
+    path,
+    files: page
+      .iter()
+      .map(|(f, _)| file_list_columns(f, &view))
+      .collect::<Result<_, _>>()?,
+    config: view,
+    next_cursor,
+  })
+}
+
+/// lines of ndjson, written on the blocking pool while the client reads them.
+/// an error ends the response early, the client sees a broken body
+pub fn stream_file_list(
+  path: String, 
+  view: FileListPathConfig, 
+  entries: FileListEntries
+) -> futures::channel::mpsc::Receiver<Result<bytes::Bytes, std::io::Error>> {
+  use futures::SinkExt;
+  let (mut tx, rx) = futures::channel::mpsc::channel(4);
+  actix_web::rt::task::spawn_blocking(move || {
+    let header = serde_json::json!({ "path": path, "config": view });
+    let mut buf = format!("{}\n", header);
+    let mut failed = None;
+    let mut send = |chunk| futures::executor::block_on(tx.send(chunk)).is_ok();
+    for entry in entries {
+      match entry.and_then(|(f, _)| file_list_columns(&f, &view)) {
+        Ok(line) => {
+          buf.push_str(&serde_json::Value::Object(line).to_string());
+          buf.push('\n');
+        }
+        Err(e) => {
+          failed = Some(std::io::Error::other(e.to_string()));
+          break;
+        }
+      }
+      // false once the client went away
+      if buf.len() >= 32 * 1024 && !send(Ok(bytes::Bytes::from(std::mem::take(&mut buf)))) {
+        return;
+      }
+    }
+    if !buf.is_empty() && !send(Ok(bytes::Bytes::from(buf))) {
+      return;
+    }
+    if let Some(e) = failed {
+      send(Err(e));
+    }
+  });
+  rx
+}
+
+pub fn do_get_file_list(user: &User, param: &FileListRequest) -> Result<FileList, Err> {
+  let (view, entries) = file_list_entries(user, param)?;
+  file_list_page(param.path.clone(), view, entries, param.limit)
+}
+
+/// only the view of one path changes, blocks on the database
+pub fn save_file_list_view(sqlhandler: &SqlHandler, username: &str, view: &FileListView) 
+  -> Result<(), Err> {
+  if !view.path.starts_with('/') {
+    return Err(ApiError::err(ErrorCode::InvalidRequest, "path must start with /"));
   }
-  Ok(HttpResponse::Ok().body(serde_json::to_string(&list).unwrap()))
+  view.config.check()?;
+  let mut config = match sqlhandler.get_user_by_name(username)? {
+    Some(u) => u.config,
+    None => return Err(ApiError::err(ErrorCode::NotFound, "user not exists")),
+  };
+  config.filelist_config.set(view.path.clone(), view.config.clone())?;
+  sqlhandler.update_user_config_by_name(username, &config)
+}
+
+/// a name in the folder of the user, not a path
+pub fn check_file_name(name: &str) -> Result<(), Err> {
+  if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+    return Err(ApiError::err(ErrorCode::InvalidName, format!("invalid file name: {:?}", name)));
+  }
+  Ok(())
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct DownloadRequest {
   pub name: String,
-  pub username: String,
-  pub token: String
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct DeleteFileRequest {
   pub name: String,
-  pub username: String,
-  pub token: String
 }
 
-struct FileWorker {
-  jobs: RwLock<HashMap<String, FileJob>>,
+/// when written slices reach the disk, besides when the os decides
+#[derive(serde::Deserialize, Clone, Copy, std::fmt::Debug, PartialEq, Default)]
+pub enum FsyncPolicy {
+  Never,
+  // after each batch of a worker, before its slices are answered
+  Batch,
+  // once the upload is done
+  #[default]
+  Finish,
 }
 
-impl FileWorker {
-  fn new() -> Self {
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct FileWriteConfig {
+  // slices queued on each file worker, one beyond it is sent again
+  pub queue: usize,
+  // slices a worker writes before it answers them, 1 answers each at once
+  pub batch: usize,
+  pub fsync: FsyncPolicy,
+}
+
+impl Default for FileWriteConfig {
+  fn default() -> Self {
     Self {
-      jobs: RwLock::new(HashMap::new()),
+      queue: 256,
+      batch: 16,
+      fsync: FsyncPolicy::Finish,
     }
   }
+}
+
+/// an upload is told apart by its user and file hash, such that users
+/// never meet the uploads of each other
+type FileJobKey = (String, String);
 
-  fn add_job(&self, file_hash: String, job: FileJob) {
-    self.jobs.write().unwrap().insert(file_hash, job);
+/// a thread writing the queued slices of its jobs
+struct FileWorker {
+  queue: mpsc::SyncSender<(Arc<FileJob>, SliceFrame)>,
+  // jobs not done, a new one goes to the least loaded worker
+  load: AtomicUsize,
+}
+
+impl FileWorker {
+  fn new(id: usize, config: FileWriteConfig) -> Self {
+    let (queue, slices) = mpsc::sync_channel(config.queue.max(1));
+    // it ends once the queue is dropped with its FileHandler
+    std::thread::Builder::new()
+      .name(format!("file-worker-{}", id))
+      .spawn(move || Self::run(slices, config))
+      .expect("spawn file worker");
+    Self {
+      queue,
+      load: AtomicUsize::new(0),
+    }
   }
 
-  fn work(&self, file_hash: String, index: u64, data: bytes::Bytes) {
-    use std::os::unix::prelude::FileExt;
-    let jobs = self.jobs.read().unwrap();
-    let job = jobs.get(&file_hash).unwrap();
-    match job.file.write_at(&data, job.request.slice_size*index) {
-      Ok(sz) => {
-        if sz == data.len() {
+  /// slices come with their job, no lock is held while the disk is written
+  fn run(slices: mpsc::Receiver<(Arc<FileJob>, SliceFrame)>, config: FileWriteConfig) {
+    while let Ok(first) = slices.recv() {
+      let mut batch = vec![first];
+      while batch.len() < config.batch {
+        match slices.try_recv() {
+          Ok(slice) => batch.push(slice),
+          Err(_) => break,
+        }
+      }
+      let mut written: Vec<(Arc<FileJob>, u64, bool)> = batch
+        .into_iter()
+        .map(|(job, frame)| {
+          let ok = job.write(&frame);
+          (job, frame.index, ok)
+        })
+        .collect();
+      if config.fsync == FsyncPolicy::Batch {
+        let mut synced: HashMap<*const FileJob, bool> = HashMap::new();
+        for (job, _, ok) in written.iter_mut() {
+          let sync = *synced.entry(Arc::as_ptr(job)).or_insert_with(|| match job.file.sync_data() {
+            Ok(()) => true,
+            Err(e) => {
+              log::error!("sync {} error: {}", job.request.name, e);
+              false
+            }
+          });
+          *ok = *ok && sync;
+        }
+      }
+      for (job, index, ok) in written {
+        if ok {
           job.on_slice_send(index);
         } else {
-          log::info!("slice sended byte: {}, but need {}", sz, data.len());
           job.on_slice_not_send(index);
         }
       }
-      Err(e) => {
-        log::debug!("slice not send, err: {}", e);
-        job.on_slice_not_send(index);
-      }
     }
   }
-
-  fn done(&self, file_hash: String) {
-    let mut jobs = self.jobs.write().unwrap();
-    let job = jobs.get(&file_hash).unwrap();
-    job.timer.stop_timer();
-    jobs.remove(&file_hash);
-  }
 }
 
 struct FileJob {
   request: FileRequest,
   user_ctx: UserCtx,
   file: std::fs::File,
-  timer: Timer
+  // slices the uploader may send before a response, see FileHandler::window
+  credits: Arc<AtomicU64>,
+  timer: Timer,
+  // the FileWorker writing its slices
+  worker: usize,
+}
+
+impl Drop for FileJob {
+  // once done or dropped, and its queued slices are written
+  fn drop(&mut self) {
+    self.timer.stop_timer();
+  }
 }
 
 impl FileJob {
-  fn new(req: FileRequest, user_ctx: UserCtx) -> Result<Self, Err> {
+  fn new(req: FileRequest, user_ctx: UserCtx, window: u64, worker: usize) -> Result<Self, Err> {
+    check_file_name(&req.name)?;
     let storage = std::path::PathBuf::from("inner/storage");
     let userfolder = storage.join(&req.username);
     if !userfolder.exists() {
@@ -225,32 +660,79 @@ impl FileJob {
         .open(filepath)?;
     let uctx = user_ctx.clone();
     let filehash = req.file_hash.clone();
+    let credits = Arc::new(AtomicU64::new(window));
+    let stalled = credits.clone();
     Ok(Self {
       request: req,
       file: f,
       user_ctx,
+      credits,
+      worker,
+      // nothing is in flight after a while without slices, the uploader
+      // starts again with a whole window
       timer: Timer::new(Duration::from_secs(10), move || {
-        uctx.session.as_ref().unwrap().do_send(WsMessage {
-          sender: WsSender::Server,
-          msg: WsMessageClass::PleaseSend(filehash.clone()),
-          policy: WsDispatchType::Targets(vec![WsClient::new(&uctx)])
-        })
+        stalled.store(window, Ordering::SeqCst);
+        if let Some(session) = &uctx.session {
+          session.do_send(WsMessage {
+            sender: WsSender::Server,
+            msg: WsMessageClass::PleaseSend(filehash.clone()),
+            policy: WsDispatchType::Targets(vec![WsClient::new(&uctx)])
+          })
+        }
       })
     })
   }
 
+  /// a slice may only be queued with a credit of its job
+  fn admit(&self, frame: &SliceFrame) -> Result<(), Err> {
+    let offset = self.request.slice_size.checked_mul(frame.index);
+    if frame.intact && offset.is_none_or(|o| o.saturating_add(frame.data.len() as u64) > self.request.size) {
+      return Err(ApiError::err(ErrorCode::InvalidRequest, format!("slice {} out of file", frame.index)));
+    }
+    let taken = self.credits.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |c| c.checked_sub(1));
+    if taken.is_err() {
+      return Err(ApiError::err(ErrorCode::RateLimited, "no upload credit left, wait for a file response"));
+    }
+    Ok(())
+  }
+
+  /// false if the slice is to be sent again
+  fn write(&self, frame: &SliceFrame) -> bool {
+    use std::os::unix::prelude::FileExt;
+    if !frame.intact {
+      log::warn!("slice {} of {} is corrupt, resend it", frame.index, frame.file_hash);
+      return false;
+    }
+    match self.file.write_at(&frame.data, self.request.slice_size * frame.index) {
+      Ok(sz) if sz == frame.data.len() => true,
+      Ok(sz) => {
+        log::info!("slice sended byte: {}, but need {}", sz, frame.data.len());
+        false
+      }
+      Err(e) => {
+        log::debug!("slice not send, err: {}", e);
+        false
+      }
+    }
+  }
+
   fn on_slice_not_send(&self, index: u64) {
     self.timer.reset_timer();
+    self.credits.fetch_add(1, Ordering::SeqCst);
     let policy = WsDispatchType::Targets(vec![WsClient::new(&self.user_ctx)]);
     // the last index
     let status = FileResponseStatus::Resend;
-    self.user_ctx.session.as_ref().unwrap().do_send(WsMessage {
+    let Some(session) = &self.user_ctx.session else {
+      return;
+    };
+    session.do_send(WsMessage {
       sender: WsSender::Server,
       msg: WsMessageClass::FileResponse(FileResponse {
         name: self.request.name.clone(),
         file_hash: self.request.file_hash.clone(),
         slice_idx: (index, index+1),
-        status
+        status,
+        credits: 1,
       }),
       policy
     })
@@ -258,17 +740,22 @@ impl FileJob {
 
   fn on_slice_send(&self, index: u64) {
     self.timer.reset_timer();
+    self.credits.fetch_add(1, Ordering::SeqCst);
     let status: FileResponseStatus;
     let policy = WsDispatchType::BroadcastSameUser;
     // the last index
     status = FileResponseStatus::Ok;
-    self.user_ctx.session.as_ref().unwrap().do_send(WsMessage {
+    let Some(session) = &self.user_ctx.session else {
+      return;
+    };
+    session.do_send(WsMessage {
       sender: WsSender::Server,
       msg: WsMessageClass::FileResponse(FileResponse {
         name: self.request.name.clone(),
         file_hash: self.request.file_hash.clone(),
         slice_idx: (index, index+1),
-        status
+        status,
+        credits: 1,
       }),
       policy
     });
@@ -276,68 +763,141 @@ impl FileJob {
 }
 
 pub struct FileHandler {
-  worker_num: i32,
+  // credits of a new upload, see ServerConfig::upload_window
+  window: u64,
+  fsync: FsyncPolicy,
   workers: Vec<FileWorker>,
-  // dispatch file to worker
-  worker_dispatch: RwLock<HashMap<String, usize>>,
+  // uploads in progress, only held to find a job, never while it writes
+  jobs: RwLock<HashMap<FileJobKey, Arc<FileJob>>>,
   // download codes: map hash to username, filename
   codes: RwLock<HashMap<String, (String, String)>>,
 }
 
 impl FileHandler {
-  pub fn new(worker_num: i32) -> Self {
-    let mut me = Self { 
-      worker_num,
-      workers: vec![],
-      worker_dispatch: RwLock::new(HashMap::new()),
+  pub fn new(worker_num: i32, window: u64, writes: FileWriteConfig) -> Self {
+    Self { 
+      window: window.max(1),
+      fsync: writes.fsync,
+      workers: (0..worker_num.max(1) as usize).map(|id| FileWorker::new(id, writes.clone())).collect(),
+      jobs: RwLock::new(HashMap::new()),
       codes: RwLock::new(HashMap::new()),
-    };
+    }
+  }
 
-    for _ in 0..worker_num {
-      me.workers.push(FileWorker::new());
+  /// a file of a user is uploaded once at a time, a request of the same hash
+  /// or file takes over the upload, e.g. after a reconnect. blocks on the disk
+  pub fn add(&self, req: FileRequest, user_ctx: UserCtx) -> Result<(), Err> {
+    if req.slice_size == 0 {
+      return Err(ApiError::err(ErrorCode::InvalidRequest, "slice size must be positive"));
     }
-    me
+    let worker = (0..self.workers.len())
+      .min_by_key(|id| self.workers[*id].load.load(Ordering::SeqCst))
+      .unwrap_or_default();
+    // opened before the lock is taken
+    let job = Arc::new(FileJob::new(req.clone(), user_ctx, self.window, worker)?);
+    let mut jobs = self.jobs.write().unwrap();
+    let taken_over: Vec<FileJobKey> = jobs
+      .iter()
+      .filter(|((username, hash), j)| {
+        *username == req.username && (*hash == req.file_hash || j.request.name == req.name)
+      })
+      .map(|(key, _)| key.clone())
+      .collect();
+    for key in taken_over {
+      log::info!("upload of {} {} is taken over", key.0, key.1);
+      self.remove_job(&mut jobs, &key);
+    }
+    log::info!("map file{} to worker_id {}", req.file_hash, worker);
+    self.workers[worker].load.fetch_add(1, Ordering::SeqCst);
+    jobs.insert((req.username, req.file_hash), job);
+    Ok(())
   }
 
-  pub fn add(&self, req: FileRequest, user_ctx: UserCtx) -> bool {
-    let job = match FileJob::new(req.clone(), user_ctx) {
-      Ok(j) => j,
-      Err(_) => {
-        log::error!("add file error");
-        return false;
-      }
-    };
+  /// the file is synced without a lock, other uploads go on meanwhile
+  pub fn done(&self, username: &str, file_hash: &str) -> Result<(), Err> {
+    let job = self.job(username, file_hash)?;
+    if self.fsync != FsyncPolicy::Never {
+      job.file.sync_all()?;
+    }
+    let mut jobs = self.jobs.write().unwrap();
+    let key = (username.to_string(), file_hash.to_string());
+    // unless it was taken over meanwhile
+    if jobs.get(&key).is_some_and(|j| Arc::ptr_eq(j, &job)) {
+      self.remove_job(&mut jobs, &key);
+    }
+    Ok(())
+  }
 
-    let worker_id = (Time::now().milli() % self.worker_num as u64) as usize;
-    log::info!("map file{} to worker_id {}", req.file_hash, worker_id);
-    assert!(self.worker_dispatch.write().unwrap().insert(req.file_hash.clone(), worker_id).is_none());
-    self.workers[worker_id].add_job(req.file_hash, job);
-    true
+  /// uploads of a closed session, they are not finished by anyone
+  pub fn drop_jobs(&self, user_ctx: &UserCtx) -> usize {
+    let mut jobs = self.jobs.write().unwrap();
+    let dropped: Vec<FileJobKey> = jobs
+      .iter()
+      .filter(|(_, j)| j.user_ctx == *user_ctx)
+      .map(|(key, _)| key.clone())
+      .collect();
+    for key in &dropped {
+      log::info!("drop upload of {} {}", key.0, key.1);
+      self.remove_job(&mut jobs, key);
+    }
+    dropped.len()
+  }
+
+  fn remove_job(&self, jobs: &mut HashMap<FileJobKey, Arc<FileJob>>, key: &FileJobKey) {
+    if let Some(job) = jobs.remove(key) {
+      self.workers[job.worker].load.fetch_sub(1, Ordering::SeqCst);
+    }
+  }
+
+  /// the slices an uploader may send before it gets a file response
+  pub fn window(&self) -> u64 {
+    self.window
   }
 
-  pub fn done(&self, file_hash: String) {
-    let worker = &self.workers[*self.worker_dispatch.read().unwrap().get(&file_hash).unwrap()];
-    worker.done(file_hash);
+  fn job(&self, username: &str, file_hash: &str) -> Result<Arc<FileJob>, Err> {
+    let jobs = self.jobs.read().unwrap();
+    match jobs.get(&(username.to_string(), file_hash.to_string())) {
+      Some(job) => Ok(job.clone()),
+      None => Err(ApiError::err(ErrorCode::NotFound, "file not in upload")),
+    }
+  }
+
+  /// a binary package, see SliceFrame for its layout. it takes a credit of
+  /// its upload and is refused without one, then it is written with write
+  pub fn admit(&self, username: &str, bytes: bytes::Bytes) -> Result<SliceFrame, Err> {
+    let frame = SliceFrame::parse(bytes)?;
+    log::debug!("SEND v{} {} {}", frame.version, frame.file_hash, frame.index);
+    self.job(username, &frame.file_hash)?.admit(&frame)?;
+    Ok(frame)
   }
 
-  pub fn send(&self, bytes: bytes::Bytes) {
-    let hashstr: String = bytes.slice(0..32).iter().map(|b| {
-      format!("{:02x}", b).to_string()
-    }).collect();
-    let index: u64 = bytes.slice(32..36).get_u32_le() as u64;
-    log::warn!("SEND {} {}", hashstr, index);
-    let worker = &self.workers[*self.worker_dispatch.read().unwrap().get(&hashstr).unwrap()];
-    worker.work(hashstr, index, bytes.slice(36..));
+  /// queued on the worker of its upload, which answers it once written.
+  /// without room in the queue it is asked again at once
+  pub fn write(&self, username: &str, frame: SliceFrame) -> Result<(), Err> {
+    let job = self.job(username, &frame.file_hash)?;
+    match self.workers[job.worker].queue.try_send((job, frame)) {
+      Ok(()) => Ok(()),
+      Err(mpsc::TrySendError::Full((job, frame))) => {
+        log::warn!("file worker queue is full, resend slice {} of {}", frame.index, frame.file_hash);
+        job.on_slice_not_send(frame.index);
+        Ok(())
+      }
+      Err(mpsc::TrySendError::Disconnected(_)) => {
+        Err(Box::new(ApiError::of(Box::from("file worker stopped"), ErrorCode::Internal)))
+      }
+    }
   }
 
-  pub fn delete_file(&self, req: DeleteFileRequest) -> Result<(), Err> {
-    Ok(std::fs::remove_file(format!("inner/storage/{}/{}", req.username, req.name))?)
+  pub fn delete_file(&self, username: &String, req: DeleteFileRequest) -> Result<(), Err> {
+    check_file_name(&req.name)?;
+    Ok(std::fs::remove_file(format!("inner/storage/{}/{}", username, req.name))?)
   }
 
-  pub fn gen_download_code(&self, req: DownloadRequest) -> String {
-    let code = sha256::digest(serde_json::to_string(&req).unwrap() + format!("{}", Time::now()).as_str());
+  pub fn gen_download_code(&self, username: &String, req: DownloadRequest) -> String {
+    let code = sha256::digest(
+      format!("{}{}{}", username, serde_json::to_string(&req).unwrap(), Time::now()));
     let mut codes = self.codes.write().unwrap();
-    assert!(codes.insert(code.clone(), (req.username, req.name)).is_none());
+    assert!(codes.insert(code.clone(), (username.clone(), req.name)).is_none());
     code
   }
 
@@ -349,6 +909,30 @@ impl FileHandler {
     }
   }
 
+  /// download codes of a user keep working after it is renamed
+  pub fn rename_user_codes(&self, username: &str, new_username: &str) {
+    let mut codes = self.codes.write().unwrap();
+    for (owner, _) in codes.values_mut() {
+      if owner == username {
+        *owner = new_username.to_string();
+      }
+    }
+  }
+
+  /// drop all download codes of a user, returns code and filename of them
+  pub fn remove_user_codes(&self, username: &str) -> Vec<(String, String)> {
+    let mut codes = self.codes.write().unwrap();
+    let removed: Vec<(String, String)> = codes
+      .iter()
+      .filter(|(_, (owner, _))| owner == username)
+      .map(|(code, (_, filename))| (code.clone(), filename.clone()))
+      .collect();
+    for (code, _) in &removed {
+      codes.remove(code);
+    }
+    removed
+  }
+
   pub fn get_user_used_storage(&self, username: &String) -> Result<u64, Err> {
     let storage = std::path::PathBuf::from("inner/storage");
     let userfolder = storage.join(username);
@@ -364,3 +948,216 @@ impl FileHandler {
     Ok(ret)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn file_list_view() {
+    let file = |name: &str, size: u64, modify_t: &str| (FileListElem {
+      name: name.to_string(),
+      size: format!("{}b", size),
+      create_t: String::new(),
+      access_t: String::new(),
+      modify_t: modify_t.to_string(),
+    }, size);
+    let mut files = vec![
+      file("b", 900, "2024-01-02 00:00:00"),
+      file("a", 1000, "2024-01-03 00:00:00"),
+      file("c", 20, "2024-01-01 00:00:00"),
+    ];
+    let names = |files: &Vec<(FileListElem, u64)>| -> Vec<String> {
+      files.iter().map(|f| f.0.name.clone()).collect()
+    };
+    let mut view = FileListPathConfig::default();
+    sort_file_list(&mut files, &view);
+    assert_eq!(names(&files), vec!["a", "b", "c"]);
+    // by bytes, not by the text of size
+    view.order_by = "size".into();
+    view.order_asc = false;
+    sort_file_list(&mut files, &view);
+    assert_eq!(names(&files), vec!["a", "b", "c"]);
+    view.order_by = "modify_t".into();
+    view.order_asc = true;
+    sort_file_list(&mut files, &view);
+    assert_eq!(names(&files), vec!["c", "b", "a"]);
+
+    view.columns = vec!["size".into(), "name".into()];
+    let shown = file_list_columns(&files[0].0, &view).unwrap();
+    assert_eq!(shown.len(), 2);
+    assert_eq!(shown["name"], "c");
+    assert!(view.check().is_ok());
+    view.columns.push("name".into());
+    assert!(view.check().is_err());
+    view.columns = vec!["owner".into()];
+    assert!(view.check().is_err());
+
+    let mut config = UserConfig::default().filelist_config;
+    assert_eq!(config.get("/docs"), config.get("/"));
+    config.set("/docs".into(), view.clone()).unwrap();
+    assert_eq!(config.get("/docs").columns, view.columns);
+    // "/" and "/docs" are set already
+    for i in 2..FILE_LIST_MAX_PATHS {
+      config.set(format!("/{}", i), view.clone()).unwrap();
+    }
+    assert!(config.set("/one-more".into(), view.clone()).is_err());
+    assert!(config.set("/docs".into(), FileListPathConfig::default()).is_ok());
+  }
+
+  #[test]
+  fn slice_frame() {
+    let hash = sha256::digest("slice");
+    let frame = SliceFrame::encode(&hash, u32::MAX as u64 + 1, b"content").unwrap();
+    assert_eq!(frame.len(), SLICE_FRAME_HEADER + 7);
+    let parsed = SliceFrame::parse(frame.clone()).unwrap();
+    assert_eq!((parsed.version, parsed.index, parsed.intact), (2, u32::MAX as u64 + 1, true));
+    assert_eq!((parsed.file_hash, &parsed.data[..]), (hash.clone(), &b"content"[..]));
+
+    let mut flipped = frame.to_vec();
+    *flipped.last_mut().unwrap() ^= 1;
+    assert!(!SliceFrame::parse(flipped.into()).unwrap().intact);
+    assert!(!SliceFrame::parse(frame.slice(..frame.len() - 1)).unwrap().intact);
+    assert!(SliceFrame::parse(frame.slice(..SLICE_FRAME_HEADER - 1)).is_err());
+    let mut version = frame.to_vec();
+    version[4] = 3;
+    assert!(SliceFrame::parse(version.into()).is_err());
+    assert!(SliceFrame::encode("not a hash", 0, b"").is_err());
+
+    let mut v1 = frame[8..40].to_vec();
+    v1.extend_from_slice(&7u32.to_le_bytes());
+    v1.extend_from_slice(b"old");
+    let parsed = SliceFrame::parse(v1.into()).unwrap();
+    assert_eq!((parsed.version, parsed.index, parsed.intact), (1, 7, true));
+    assert_eq!(parsed.file_hash, hash);
+    assert!(SliceFrame::parse(bytes::Bytes::from_static(b"short")).is_err());
+  }
+
+  #[test]
+  fn upload_credits() {
+    let writes = FileWriteConfig {
+      batch: 4,
+      fsync: FsyncPolicy::Batch,
+      ..Default::default()
+    };
+    let handler = FileHandler::new(2, 2, writes);
+    let username = String::from("credit0");
+    let slice_of = |hash: &str, index: u64| SliceFrame::encode(hash, index, &[b'a' + index as u8; 2]).unwrap();
+    let request = |name: &str, hash: &String| FileRequest {
+      username: username.clone(),
+      name: name.into(),
+      size: 8,
+      slice_size: 2,
+      last_modified_t: 0,
+      file_hash: hash.clone(),
+    };
+    let user_ctx = UserCtx {
+      username: username.clone(),
+      token: String::new(),
+      establish_t: Time::now(),
+      user_agent: String::new(),
+      ip: String::new(),
+      session: None,
+    };
+    let (hash, other_hash) = (sha256::digest("credits"), sha256::digest("credits other"));
+    handler.add(request("credits.txt", &hash), user_ctx.clone()).unwrap();
+    let storage = std::env::current_dir().unwrap().join("inner/storage");
+    let folder = storage.join(&username);
+    // one upload of a hash and of a file at a time, a retry takes it over
+    handler.add(request("credits.txt", &hash), user_ctx.clone()).unwrap();
+    assert_eq!(handler.jobs.read().unwrap().len(), 1);
+    // the same content of another user is another upload, gone with its session
+    let other_ctx = UserCtx {
+      username: "credit1".into(),
+      ..user_ctx.clone()
+    };
+    handler.add(FileRequest { username: "credit1".into(), ..request("credits.txt", &hash) }, other_ctx.clone()).unwrap();
+    assert_eq!(handler.jobs.read().unwrap().len(), 2);
+    assert_eq!(handler.drop_jobs(&other_ctx), 1);
+    assert!(handler.admit("credit1", slice_of(&hash, 0)).is_err());
+    handler.add(request("other.txt", &other_hash), user_ctx).unwrap();
+    let loads: Vec<usize> = handler.workers.iter().map(|w| w.load.load(Ordering::SeqCst)).collect();
+    assert_eq!(loads, vec![1, 1]);
+
+    let slice = |index: u64| slice_of(&hash, index);
+    // a credit comes back once a slice is written
+    let admit = |index: u64| -> SliceFrame {
+      for _ in 0..200 {
+        if let Ok(frame) = handler.admit(&username, slice(index)) {
+          return frame;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+      }
+      panic!("no credit for slice {}", index);
+    };
+    let (first, second) = (admit(0), admit(1));
+    let refused = handler.admit(&username, slice(2)).unwrap_err();
+    assert_eq!(ApiError::of(refused, ErrorCode::Internal).code, ErrorCode::RateLimited);
+    assert!(handler.admit(&String::from("other"), slice(2)).is_err());
+    assert!(handler.admit(&username, SliceFrame::encode(&hash, 4, b"ab").unwrap()).is_err());
+    handler.write(&username, first).unwrap();
+    handler.write(&username, second).unwrap();
+    handler.write(&username, admit(2)).unwrap();
+    handler.write(&username, admit(3)).unwrap();
+    // both credits are back once all is written
+    admit(0);
+    admit(1);
+    assert_eq!(std::fs::read(folder.join("credits.txt")).unwrap(), b"aabbccdd");
+    handler.done(&username, &hash).unwrap();
+    handler.done(&username, &other_hash).unwrap();
+    assert!(handler.admit(&username, slice(3)).is_err());
+    assert!(handler.done(&username, &hash).is_err());
+    std::fs::remove_dir_all(folder).unwrap();
+    std::fs::remove_dir_all(storage.join("credit1")).unwrap();
+  }
+
+  #[test]
+  fn file_list_pages() {
+    let folder = std::env::temp_dir().join(format!("pulsear_list_{}", std::process::id()));
+    std::fs::create_dir_all(&folder).unwrap();
+    for (name, size) in [("a.txt", 30), ("B.pdf", 10), ("c.txt", 20), ("d.TXT", 0), ("e", 40)] {
+      std::fs::write(folder.join(name), vec![0u8; size]).unwrap();
+    }
+    let names = |list: &FileList| -> Vec<String> {
+      list.files.iter().map(|f| f["name"].as_str().unwrap().to_string()).collect()
+    };
+    let page = |view: &FileListPathConfig, cursor: Option<String>, filter: &FileListFilter| {
+      let entries = list_folder(folder.clone(), view, cursor.as_deref(), filter).unwrap();
+      file_list_page("/".into(), view.clone(), entries, Some(2)).unwrap()
+    };
+    let mut view = FileListPathConfig::default();
+    let mut filter = FileListFilter::default();
+    let mut all = vec![];
+    let mut cursor = None;
+    loop {
+      let list = page(&view, cursor, &filter);
+      assert!(list.files.len() <= 2);
+      all.extend(names(&list));
+      cursor = list.next_cursor;
+      if cursor.is_none() {
+        break;
+      }
+    }
+    assert_eq!(all, vec!["B.pdf", "a.txt", "c.txt", "d.TXT", "e"]);
+
+    view.order_by = "size".into();
+    view.order_asc = false;
+    let first = page(&view, None, &filter);
+    assert_eq!(names(&first), vec!["e", "a.txt"]);
+    assert_eq!(names(&page(&view, first.next_cursor.clone(), &filter)), vec!["c.txt", "B.pdf"]);
+    // a cursor only holds for its own order, both are mistakes of the client
+    let code = |r: Result<FileListEntries, Err>| ApiError::of(r.err().unwrap(), ErrorCode::Internal).code;
+    let other = list_folder(folder.clone(), &FileListPathConfig::default(), first.next_cursor.as_deref(), &filter);
+    assert_eq!(code(other), ErrorCode::InvalidRequest);
+    assert_eq!(code(list_folder(folder.clone(), &view, Some("bad"), &filter)), ErrorCode::InvalidRequest);
+
+    filter.file_type = Some("txt".into());
+    filter.min_size = Some(10);
+    assert_eq!(names(&page(&view, None, &filter)), vec!["a.txt", "c.txt"]);
+    filter = FileListFilter { name: Some("B".into()), ..Default::default() };
+    assert_eq!(names(&page(&view, None, &filter)), vec!["B.pdf"]);
+    filter = FileListFilter { modified_before: Some("2000".into()), ..Default::default() };
+    assert!(page(&view, None, &filter).files.is_empty());
+    std::fs::remove_dir_all(&folder).unwrap();
+  }
+}
@@ -7,15 +7,57 @@ pub use file::*;
 pub mod auth;
 pub use auth::*;
 
+pub mod error;
+pub use error::*;
+
 pub mod util;
 pub use util::*;
 
 pub mod websocket;
 pub use websocket::*;
 
+pub mod protocol;
+pub use protocol::*;
+
 pub mod sql;
 pub use sql::*;
 
+pub mod sql_mysql;
+pub use sql_mysql::*;
+
+pub mod sql_sqlite;
+pub use sql_sqlite::*;
+
+pub mod sql_postgres;
+pub use sql_postgres::*;
+
+pub mod migrate;
+pub use migrate::*;
+
+pub mod totp;
+pub use totp::*;
+
+pub mod limit;
+pub use limit::*;
+
+pub mod signup;
+pub use signup::*;
+
+pub mod apikey;
+pub use apikey::*;
+
+pub mod account;
+pub use account::*;
+
+pub mod sessions;
+pub use sessions::*;
+
+pub mod provider;
+pub use provider::*;
+
+pub mod audit;
+pub use audit::*;
+
 pub mod server;
 pub use server::*;
 
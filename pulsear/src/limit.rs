@@ -0,0 +1,285 @@
+use crate::*;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::ResponseError;
+use std::sync::Mutex;
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct LoginThrottleConfig {
+  // failures allowed before any lock
+  pub free_attempts: u32,
+  // first lock time in seconds, doubled by each further failure
+  pub base_lock: u64,
+  pub max_lock: u64,
+  // failures are forgotten after this many seconds without a new one
+  pub reset_after: u64,
+}
+
+impl Default for LoginThrottleConfig {
+  fn default() -> Self {
+    Self {
+      free_attempts: 5,
+      base_lock: 2,
+      max_lock: 60 * 60,
+      reset_after: 24 * 60 * 60,
+    }
+  }
+}
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+  pub enabled: bool,
+  // tokens refilled per second for each ip
+  pub requests_per_sec: f64,
+  pub burst: u32,
+}
+
+impl Default for RateLimitConfig {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      requests_per_sec: 20.0,
+      burst: 100,
+    }
+  }
+}
+
+/// failed login record of a key, stored in login_attempt table such that
+/// a restart does not unlock anyone
+#[derive(std::fmt::Debug, Clone, PartialEq)]
+pub struct LoginAttempt {
+  // ip:<addr>, user:<username> or signup:<addr>
+  pub key: String,
+  pub failures: u32,
+  pub last_failure_t: u64,
+  pub lock_until_t: u64,
+}
+
+impl LoginAttempt {
+  pub fn new(key: String) -> Self {
+    Self {
+      key,
+      failures: 0,
+      last_failure_t: 0,
+      lock_until_t: 0,
+    }
+  }
+
+  pub fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+  }
+
+  pub fn user_key(username: &str) -> String {
+    format!("user:{}", username)
+  }
+
+  pub fn signup_key(ip: &str) -> String {
+    format!("signup:{}", ip)
+  }
+
+  /// milliseconds left until unlocked, 0 if not locked
+  pub fn locked_for(&self, now: u64) -> u64 {
+    self.lock_until_t.saturating_sub(now)
+  }
+
+  /// count a failure, lock time doubles for each failure after the free ones
+  pub fn fail(&mut self, now: u64, config: &LoginThrottleConfig) {
+    if now.saturating_sub(self.last_failure_t) > config.reset_after * 1000 {
+      self.failures = 0;
+    }
+    self.failures += 1;
+    self.last_failure_t = now;
+    if self.failures > config.free_attempts {
+      let exp = (self.failures - config.free_attempts - 1).min(32);
+      let lock = config.base_lock.saturating_mul(1u64 << exp).min(config.max_lock);
+      self.lock_until_t = now + lock * 1000;
+    }
+  }
+}
+
+/// check keys before a login or signup, error tells how long to wait
+pub fn check_login_attempts(sqlhandler: &SqlHandler, keys: &[String]) -> Result<(), Err> {
+  let now = Time::now().milli();
+  for key in keys {
+    if let Some(attempt) = sqlhandler.get_login_attempt(key)? {
+      let left = attempt.locked_for(now);
+      if left > 0 {
+        log::warn!("login locked for {}: {}ms left", key, left);
+        return Err(ApiError::err(ErrorCode::RateLimited, format!(
+          "too many attempts, retry after {} seconds", left.div_ceil(1000)
+        )));
+      }
+    }
+  }
+  Ok(())
+}
+
+pub fn record_login_failure(
+  sqlhandler: &SqlHandler,
+  keys: &[String],
+  config: &LoginThrottleConfig
+) -> Result<(), Err> {
+  let now = Time::now().milli();
+  for key in keys {
+    let mut attempt = sqlhandler
+      .get_login_attempt(key)?
+      .unwrap_or_else(|| LoginAttempt::new(key.clone()));
+    attempt.fail(now, config);
+    sqlhandler.save_login_attempt(&attempt)?;
+  }
+  Ok(())
+}
+
+pub fn record_login_success(sqlhandler: &SqlHandler, keys: &[String]) -> Result<(), Err> {
+  for key in keys {
+    sqlhandler.delete_login_attempt(key)?;
+  }
+  Ok(())
+}
+
+pub fn peer_ip(req: &HttpRequest) -> String {
+  match req.peer_addr() {
+    Some(addr) => addr.ip().to_string(),
+    None => String::new(),
+  }
+}
+
+/// where a request comes from, taken out of the request such that it can be
+/// moved into blocking database work
+#[derive(Clone, Default)]
+pub struct ClientInfo {
+  pub ip: String,
+  pub user_agent: String,
+}
+
+impl ClientInfo {
+  pub fn of(req: &HttpRequest) -> Self {
+    Self {
+      ip: peer_ip(req),
+      user_agent: match req.headers().get("user-agent") {
+        Some(ua) => ua.to_str().unwrap_or("").to_string(),
+        None => String::new(),
+      },
+    }
+  }
+}
+
+struct Bucket {
+  tokens: f64,
+  last_t: Instant,
+}
+
+/// token bucket for each ip, kept in memory
+#[derive(Default)]
+pub struct RateLimiter {
+  buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+  pub fn allow(&self, ip: &str, config: &RateLimitConfig) -> bool {
+    self.allow_at(ip, config, Instant::now())
+  }
+
+  fn allow_at(&self, ip: &str, config: &RateLimitConfig, now: Instant) -> bool {
+    if !config.enabled {
+      return true;
+    }
+    let mut buckets = self.buckets.lock().unwrap();
+    if buckets.len() > 100000 {
+      // forget full buckets, they are the same as new ones
+      buckets.retain(|_, b| {
+        b.tokens + now.duration_since(b.last_t).as_secs_f64() * config.requests_per_sec
+          < config.burst as f64
+      });
+    }
+    let bucket = buckets.entry(ip.to_string()).or_insert(Bucket {
+      tokens: config.burst as f64,
+      last_t: now,
+    });
+    let elapsed = now.saturating_duration_since(bucket.last_t).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * config.requests_per_sec).min(config.burst as f64);
+    bucket.last_t = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+/// limit http requests of each ip, static resources are not counted
+pub async fn rate_limit(
+  req: ServiceRequest,
+  next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+  if !req.path().starts_with("/resources/") {
+    if let Some(server) = req.app_data::<web::Data<Arc<Server>>>() {
+      let ip = peer_ip(req.request());
+      if !server.rate_limiter.allow(&ip, &server.r_config().rate_limit) {
+        log::warn!("rate limit {} on {}", ip, req.path());
+        return Ok(req
+          .into_response(ApiError::new(ErrorCode::RateLimited, "too many requests").error_response())
+          .map_into_right_body());
+      }
+    }
+  }
+  next.call(req).await.map(|resp| resp.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn login_backoff() {
+    let config = LoginThrottleConfig {
+      free_attempts: 2,
+      base_lock: 1,
+      max_lock: 5,
+      reset_after: 100,
+    };
+    let mut attempt = LoginAttempt::new(LoginAttempt::user_key("u"));
+    attempt.fail(1000, &config);
+    attempt.fail(1000, &config);
+    assert_eq!(attempt.locked_for(1000), 0);
+    attempt.fail(1000, &config);
+    assert_eq!(attempt.locked_for(1000), 1000);
+    attempt.fail(1000, &config);
+    assert_eq!(attempt.locked_for(1000), 2000);
+    attempt.fail(1000, &config);
+    assert_eq!(attempt.locked_for(1000), 4000);
+    attempt.fail(1000, &config);
+    assert_eq!(attempt.locked_for(1000), 5000);
+    assert_eq!(attempt.locked_for(7000), 0);
+    // long time no failure
+    attempt.fail(1000 + 101 * 1000, &config);
+    assert_eq!(attempt.failures, 1);
+  }
+
+  #[test]
+  fn token_bucket() {
+    let config = RateLimitConfig {
+      enabled: true,
+      requests_per_sec: 2.0,
+      burst: 3,
+    };
+    let limiter = RateLimiter::default();
+    let now = Instant::now();
+    assert!(limiter.allow_at("a", &config, now));
+    assert!(limiter.allow_at("a", &config, now));
+    assert!(limiter.allow_at("a", &config, now));
+    assert!(!limiter.allow_at("a", &config, now));
+    assert!(limiter.allow_at("b", &config, now));
+    assert!(limiter.allow_at("a", &config, now + Duration::from_millis(500)));
+    assert!(!limiter.allow_at("a", &config, now + Duration::from_millis(500)));
+    let disabled = RateLimitConfig {
+      enabled: false,
+      ..config
+    };
+    assert!(limiter.allow_at("a", &disabled, now));
+  }
+}
@@ -3,6 +3,12 @@ use pulsear::*;
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
   let server_config = read_server_config().unwrap();
-  let server = Arc::new(Server::from(server_config));
+  let server = match Server::from(server_config) {
+    Ok(s) => Arc::new(s),
+    Err(e) => {
+      eprintln!("Failed to start server: {}", e);
+      std::process::exit(1);
+    }
+  };
   start(server, true).await
 }
@@ -0,0 +1,144 @@
+use crate::*;
+
+/// one step of the database schema, applied once and recorded in schema_version
+pub struct Migration {
+  pub version: u32,
+  pub name: &'static str,
+  pub sql: &'static str,
+}
+
+impl Migration {
+  pub fn checksum(&self) -> String {
+    sha256::digest(self.sql)
+  }
+}
+
+/// in order of version, never edit a released one, add a new one instead
+pub const MYSQL_MIGRATIONS: &[Migration] = &[
+  Migration {
+    version: 1,
+    name: "initial",
+    sql: include_str!("../migrations/mysql/0001_initial.sql"),
+  },
+  Migration {
+    version: 2,
+    name: "sessions_and_access",
+    sql: include_str!("../migrations/mysql/0002_sessions_and_access.sql"),
+  },
+  Migration {
+    version: 3,
+    name: "file_action_filename",
+    sql: include_str!("../migrations/mysql/0003_file_action_filename.sql"),
+  },
+];
+
+/// versions are counted per backend, their schemas are the same at the latest one
+pub const SQLITE_MIGRATIONS: &[Migration] = &[Migration {
+  version: 1,
+  name: "initial",
+  sql: include_str!("../migrations/sqlite/0001_initial.sql"),
+}];
+
+pub const POSTGRES_MIGRATIONS: &[Migration] = &[Migration {
+  version: 1,
+  name: "initial",
+  sql: include_str!("../migrations/postgres/0001_initial.sql"),
+}];
+
+pub fn latest_schema_version(migrations: &[Migration]) -> u32 {
+  migrations.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// split a script into statements, honoring `DELIMITER` lines like the mysql client,
+/// such that triggers can be written as in a hand applied script
+pub fn split_statements(script: &str) -> Vec<String> {
+  let mut delimiter = ";".to_string();
+  let mut statements = vec![];
+  let mut current = String::new();
+  for line in script.lines() {
+    let trimmed = line.trim();
+    if let Some(d) = trimmed.strip_prefix("DELIMITER ") {
+      delimiter = d.trim().to_string();
+      continue;
+    }
+    if current.trim().is_empty() && (trimmed.is_empty() || trimmed.starts_with("--")) {
+      continue;
+    }
+    current.push_str(line);
+    current.push('\n');
+    if trimmed.ends_with(delimiter.as_str()) {
+      let statement = current.trim_end().strip_suffix(delimiter.as_str()).unwrap_or_default();
+      statements.push(statement.trim().to_string());
+      current.clear();
+    }
+  }
+  if !current.trim().is_empty() {
+    statements.push(current.trim().to_string());
+  }
+  statements
+}
+
+/// the current version of applied, checked against the embedded migrations.
+/// refuses a schema written by a newer pulsear, or one whose applied
+/// migrations differ from the embedded ones
+pub fn check_applied(migrations: &[Migration], applied: &[(u32, String)]) -> Result<u32, Err> {
+  let current = applied.iter().map(|(v, _)| *v).max().unwrap_or(0);
+  let latest = latest_schema_version(migrations);
+  if current > latest {
+    return Err(Box::from(format!(
+      "database schema version {} is newer than supported version {}",
+      current, latest
+    )));
+  }
+  for (version, checksum) in applied {
+    match migrations.iter().find(|m| m.version == *version) {
+      Some(m) if &m.checksum() == checksum => (),
+      Some(m) => {
+        return Err(Box::from(format!(
+          "migration {} {} differs from the applied one",
+          version, m.name
+        )))
+      }
+      None => return Err(Box::from(format!("unknown applied migration {}", version))),
+    }
+  }
+  Ok(current)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn split_migrations() {
+    let statements = split_statements(MYSQL_MIGRATIONS[0].sql);
+    // three tables and three triggers
+    assert_eq!(statements.len(), 6);
+    assert!(statements[0].starts_with("CREATE TABLE `user`"));
+    assert!(statements[5].starts_with("CREATE TRIGGER `before_file_action_insert`"));
+    assert!(statements[5].ends_with("END"));
+    assert!(statements[3].contains("SET NEW.last_login_time = NOW();"));
+    assert_eq!(split_statements("-- note\nSELECT 1;\n\nSELECT 2"), vec!["SELECT 1", "SELECT 2"]);
+    for migrations in [MYSQL_MIGRATIONS, SQLITE_MIGRATIONS, POSTGRES_MIGRATIONS] {
+      for (i, m) in migrations.iter().enumerate() {
+        assert_eq!(m.version as usize, i + 1);
+        assert!(!split_statements(m.sql).is_empty());
+      }
+    }
+  }
+
+  #[test]
+  fn applied_migrations() {
+    let applied = |n: usize| -> Vec<(u32, String)> {
+      MYSQL_MIGRATIONS[..n].iter().map(|m| (m.version, m.checksum())).collect()
+    };
+    assert_eq!(check_applied(MYSQL_MIGRATIONS, &[]).unwrap(), 0);
+    assert_eq!(check_applied(MYSQL_MIGRATIONS, &applied(2)).unwrap(), 2);
+    let mut newer = applied(3);
+    newer.push((latest_schema_version(MYSQL_MIGRATIONS) + 1, String::new()));
+    assert!(check_applied(MYSQL_MIGRATIONS, &newer).is_err());
+    let mut edited = applied(1);
+    edited[0].1 = sha256::digest("edited");
+    assert!(check_applied(MYSQL_MIGRATIONS, &edited).is_err());
+  }
+}
@@ -0,0 +1,139 @@
+use crate::*;
+
+/// the newest and oldest version of the ws protocol the server speaks.
+/// bump the version when a message changes its meaning or layout.
+///  1: binary packages are v1 SliceFrames
+///  2: binary packages are v2 SliceFrames, with length and checksum
+pub const WS_PROTOCOL_VERSION: u32 = 2;
+pub const WS_MIN_PROTOCOL_VERSION: u32 = 1;
+/// the largest slice of a binary package the server takes, less if a
+/// whole package does not fit in WsConfig::max_message_size
+pub const MAX_SLICE_SIZE: u64 = 1 << 20;
+/// encodings of the file content in a binary package, in order of preference
+pub const SLICE_ENCODINGS: &[&str] = &["raw"];
+
+fn default_encodings() -> Vec<String> {
+  vec!["raw".into()]
+}
+
+/// what a peer can do, the server answers with what both can
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone, PartialEq)]
+pub struct WsCapabilities {
+  pub max_slice_size: u64,
+  #[serde(default = "default_encodings")]
+  pub encodings: Vec<String>,
+  // an interrupted upload goes on after a reconnect
+  #[serde(default)]
+  pub resume: bool,
+}
+
+impl WsCapabilities {
+  pub fn server(config: &WsConfig) -> Self {
+    let max_message_slice = config.max_message_size.saturating_sub(SLICE_FRAME_HEADER) as u64;
+    Self {
+      max_slice_size: MAX_SLICE_SIZE.min(max_message_slice),
+      encodings: SLICE_ENCODINGS.iter().map(|e| e.to_string()).collect(),
+      resume: false,
+    }
+  }
+}
+
+/// sent by a client with Establish or Reconnect. version is the newest it
+/// speaks and min_version the oldest, the server answers with the agreed ones
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone, PartialEq)]
+pub struct WsHandshake {
+  pub version: u32,
+  #[serde(default)]
+  pub min_version: u32,
+  pub capabilities: WsCapabilities,
+}
+
+impl WsHandshake {
+  /// what a client of this crate sends
+  pub fn client(max_slice_size: u64) -> Self {
+    Self {
+      version: WS_PROTOCOL_VERSION,
+      min_version: WS_MIN_PROTOCOL_VERSION,
+      capabilities: WsCapabilities {
+        max_slice_size,
+        encodings: default_encodings(),
+        resume: false,
+      },
+    }
+  }
+
+  /// the newest version both speak and the capabilities both have,
+  /// an Incompatible error if there is none
+  pub fn negotiate(&self, server: &WsCapabilities) -> Result<WsHandshake, ApiError> {
+    let version = self.version.min(WS_PROTOCOL_VERSION);
+    if version < WS_MIN_PROTOCOL_VERSION || version < self.min_version {
+      return Err(ApiError::new(ErrorCode::Incompatible, format!(
+        "client speaks protocol {}..={}, server {}..={}",
+        self.min_version, self.version, WS_MIN_PROTOCOL_VERSION, WS_PROTOCOL_VERSION
+      )));
+    }
+    let max_slice_size = self.capabilities.max_slice_size.min(server.max_slice_size);
+    if max_slice_size == 0 {
+      return Err(ApiError::new(ErrorCode::Incompatible, "max slice size must be positive"));
+    }
+    let encodings: Vec<String> =
+      self.capabilities.encodings.iter().filter(|e| server.encodings.contains(e)).cloned().collect();
+    if encodings.is_empty() {
+      return Err(ApiError::new(ErrorCode::Incompatible, format!(
+        "no common slice encoding, server has {:?}", server.encodings
+      )));
+    }
+    Ok(WsHandshake {
+      version,
+      min_version: WS_MIN_PROTOCOL_VERSION,
+      capabilities: WsCapabilities {
+        max_slice_size,
+        encodings,
+        resume: self.capabilities.resume && server.resume,
+      },
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn negotiate_handshake() {
+    let server = WsCapabilities::server(&WsConfig::default());
+    let agreed = WsHandshake::client(40960).negotiate(&server).unwrap();
+    assert_eq!(agreed.version, WS_PROTOCOL_VERSION);
+    assert_eq!(agreed.capabilities.max_slice_size, 40960);
+    assert_eq!(agreed.capabilities.encodings, vec!["raw"]);
+
+    // a newer client is downgraded, its extra capabilities dropped
+    let newer: WsHandshake = serde_json::from_value(serde_json::json!({
+      "version": WS_PROTOCOL_VERSION + 3,
+      "capabilities": { "max_slice_size": u64::MAX, "encodings": ["zstd", "raw"], "resume": true },
+    })).unwrap();
+    let agreed = newer.negotiate(&server).unwrap();
+    assert_eq!(agreed.version, WS_PROTOCOL_VERSION);
+    assert_eq!(agreed.capabilities, server);
+
+    // a slice is no larger than a whole package may be
+    let small = WsCapabilities::server(&WsConfig {
+      max_message_size: SLICE_FRAME_HEADER + 1024,
+      ..Default::default()
+    });
+    assert_eq!(small.max_slice_size, 1024);
+    let agreed = WsHandshake::client(40960).negotiate(&small).unwrap();
+    assert_eq!(agreed.capabilities.max_slice_size, 1024);
+
+    let mut too_new = WsHandshake::client(40960);
+    too_new.min_version = WS_PROTOCOL_VERSION + 1;
+    assert_eq!(too_new.negotiate(&server).unwrap_err().code, ErrorCode::Incompatible);
+    let mut too_old = WsHandshake::client(40960);
+    too_old.version = WS_MIN_PROTOCOL_VERSION - 1;
+    assert_eq!(too_old.negotiate(&server).unwrap_err().code, ErrorCode::Incompatible);
+    let mut no_encoding = WsHandshake::client(40960);
+    no_encoding.capabilities.encodings = vec!["zstd".into()];
+    assert!(no_encoding.negotiate(&server).is_err());
+    assert!(WsHandshake::client(0).negotiate(&server).is_err());
+  }
+}
@@ -0,0 +1,323 @@
+use crate::*;
+
+/// checks the password of a login, tried in the order of ServerConfig.auth_providers
+pub trait AuthProvider {
+  fn name(&self) -> &str;
+
+  /// the local user if the password is right, None if the provider does not know
+  /// the user or the password is wrong. may provision the user on its first login
+  fn authenticate(&self, sqlhandler: &SqlHandler, username: &str, password: &str)
+    -> Result<Option<User>, Err>;
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub enum AuthProviderConfig {
+  Database,
+  Ldap(LdapConfig),
+}
+
+pub fn default_auth_providers() -> Vec<AuthProviderConfig> {
+  vec![AuthProviderConfig::Database]
+}
+
+pub fn build_auth_providers(configs: &[AuthProviderConfig]) -> Vec<Box<dyn AuthProvider>> {
+  configs
+    .iter()
+    .map(|c| -> Box<dyn AuthProvider> {
+      match c {
+        AuthProviderConfig::Database => Box::new(DatabaseProvider),
+        AuthProviderConfig::Ldap(config) => Box::new(LdapProvider::new(
+          config.clone(),
+          Box::new(Ldap3Directory { config: config.clone() }),
+        )),
+      }
+    })
+    .collect()
+}
+
+/// the first provider which accepts the password decides the user
+pub fn authenticate_password(
+  sqlhandler: &SqlHandler,
+  config: &ServerConfig,
+  username: &str,
+  password: &str,
+) -> Result<Option<User>, Err> {
+  for provider in build_auth_providers(&config.auth_providers) {
+    match provider.authenticate(sqlhandler, username, password) {
+      Ok(Some(user)) => {
+        log::info!("user {} authenticated by {}", username, provider.name());
+        return Ok(Some(user));
+      }
+      Ok(None) => (),
+      // one provider being down should not lock out users of the others
+      Err(e) => log::error!("auth provider {} error: {}", provider.name(), e),
+    }
+  }
+  Ok(None)
+}
+
+/// users whose token is derived from the password, see password_token
+pub struct DatabaseProvider;
+
+impl AuthProvider for DatabaseProvider {
+  fn name(&self) -> &str {
+    "database"
+  }
+
+  fn authenticate(&self, sqlhandler: &SqlHandler, username: &str, password: &str)
+    -> Result<Option<User>, Err> {
+    Ok(sqlhandler
+      .get_user_by_name(username)?
+      .filter(|u| u.token == password_token(username, password)))
+  }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct LdapGroupMapping {
+  // cn of the directory group
+  pub group: String,
+  pub usertype: UserType,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct LdapConfig {
+  // ldap://host:389 or ldaps://host:636
+  pub url: String,
+  #[serde(default)]
+  pub starttls: bool,
+  // dn to bind as, {username} is replaced by the escaped username,
+  // e.g. uid={username},ou=people,dc=example,dc=org
+  pub user_dn: String,
+  // where groups are searched, e.g. ou=groups,dc=example,dc=org
+  pub group_base: String,
+  // {dn} is replaced by the escaped dn of the user
+  #[serde(default = "default_group_filter")]
+  pub group_filter: String,
+  // the highest type of all matched groups is used
+  #[serde(default)]
+  pub group_mapping: Vec<LdapGroupMapping>,
+  // type of users in no mapped group, such users are rejected if None
+  #[serde(default)]
+  pub default_usertype: Option<UserType>,
+}
+
+fn default_group_filter() -> String {
+  "(|(member={dn})(uniqueMember={dn}))".to_string()
+}
+
+impl LdapConfig {
+  pub fn user_dn(&self, username: &str) -> String {
+    self.user_dn.replace("{username}", &ldap3::dn_escape(username))
+  }
+
+  pub fn usertype_of(&self, groups: &[String]) -> Option<UserType> {
+    self
+      .group_mapping
+      .iter()
+      .filter(|m| groups.iter().any(|g| g.eq_ignore_ascii_case(&m.group)))
+      .map(|m| m.usertype.clone())
+      .min_by_key(|t| t.rank())
+      .or_else(|| self.default_usertype.clone())
+  }
+}
+
+/// the directory operations the ldap provider needs, such that tests can
+/// use a stand-in directory
+pub trait LdapDirectory {
+  /// whether the dn can bind with the password
+  fn bind(&self, dn: &str, password: &str) -> Result<bool, Err>;
+  /// cn of groups the dn is a member of, searched after binding as the dn
+  fn groups(&self, dn: &str, password: &str) -> Result<Vec<String>, Err>;
+}
+
+pub struct Ldap3Directory {
+  config: LdapConfig,
+}
+
+impl Ldap3Directory {
+  fn bound_conn(&self, dn: &str, password: &str) -> Result<Option<ldap3::LdapConn>, Err> {
+    let settings = ldap3::LdapConnSettings::new()
+      .set_starttls(self.config.starttls)
+      .set_conn_timeout(Duration::from_secs(5));
+    let mut conn = ldap3::LdapConn::with_settings(settings, &self.config.url)?;
+    let result = conn.simple_bind(dn, password)?;
+    // 49 is invalidCredentials
+    if result.rc == 49 {
+      return Ok(None);
+    }
+    result.success()?;
+    Ok(Some(conn))
+  }
+}
+
+impl LdapDirectory for Ldap3Directory {
+  fn bind(&self, dn: &str, password: &str) -> Result<bool, Err> {
+    match self.bound_conn(dn, password)? {
+      Some(mut conn) => {
+        conn.unbind()?;
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+
+  fn groups(&self, dn: &str, password: &str) -> Result<Vec<String>, Err> {
+    let mut conn = match self.bound_conn(dn, password)? {
+      Some(c) => c,
+      None => return Ok(vec![]),
+    };
+    let filter = self.config.group_filter.replace("{dn}", &ldap3::ldap_escape(dn));
+    let (entries, _) = conn
+      .search(&self.config.group_base, ldap3::Scope::Subtree, &filter, vec!["cn"])?
+      .success()?;
+    conn.unbind()?;
+    Ok(entries
+      .into_iter()
+      .filter_map(|e| ldap3::SearchEntry::construct(e).attrs.remove("cn"))
+      .flatten()
+      .collect())
+  }
+}
+
+/// bind as the user, then map its groups to a UserType.
+/// users are created on their first login and their type follows the directory
+pub struct LdapProvider {
+  config: LdapConfig,
+  directory: Box<dyn LdapDirectory>,
+}
+
+impl LdapProvider {
+  pub fn new(config: LdapConfig, directory: Box<dyn LdapDirectory>) -> Self {
+    Self { config, directory }
+  }
+
+  /// the type of a user if the directory accepts the password
+  pub fn directory_usertype(&self, username: &str, password: &str) -> Result<Option<UserType>, Err> {
+    // an empty password is an unauthenticated bind, which always succeeds
+    if password.is_empty() || check_signup_username(username).is_err() {
+      return Ok(None);
+    }
+    let dn = self.config.user_dn(username);
+    if !self.directory.bind(&dn, password)? {
+      return Ok(None);
+    }
+    let groups = self.directory.groups(&dn, password)?;
+    let usertype = self.config.usertype_of(&groups);
+    if usertype.is_none() {
+      log::warn!("ldap user {} is in no mapped group: {:?}", username, groups);
+    }
+    Ok(usertype)
+  }
+}
+
+impl AuthProvider for LdapProvider {
+  fn name(&self) -> &str {
+    "ldap"
+  }
+
+  fn authenticate(&self, sqlhandler: &SqlHandler, username: &str, password: &str)
+    -> Result<Option<User>, Err> {
+    let usertype = match self.directory_usertype(username, password)? {
+      Some(t) => t,
+      None => return Ok(None),
+    };
+    let username = username.to_string();
+    match sqlhandler.get_user_by_name(&username)? {
+      Some(mut user) => {
+        if user.usertype != usertype {
+          log::info!("ldap user {} type {:?} -> {:?}", username, user.usertype, usertype);
+          sqlhandler.update_user_type_by_name(&username, &usertype)?;
+          user.usertype = usertype;
+        }
+        Ok(Some(user))
+      }
+      None => {
+        log::info!("provision ldap user {} as {:?}", username, usertype);
+        // the local token is random, such that only the directory password works
+        sqlhandler.add_user(&User {
+          id: 0,
+          token: password_token(&username, &random_token()),
+          username,
+          config: UserConfig::default(),
+          usertype,
+        })
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// a directory kept in memory: dn -> (password, groups)
+  struct StandInDirectory(HashMap<String, (String, Vec<String>)>);
+
+  impl LdapDirectory for StandInDirectory {
+    fn bind(&self, dn: &str, password: &str) -> Result<bool, Err> {
+      Ok(matches!(self.0.get(dn), Some((p, _)) if p == password))
+    }
+
+    fn groups(&self, dn: &str, password: &str) -> Result<Vec<String>, Err> {
+      match self.0.get(dn) {
+        Some((p, groups)) if p == password => Ok(groups.clone()),
+        _ => Ok(vec![]),
+      }
+    }
+  }
+
+  fn ldap_config(url: &str) -> LdapConfig {
+    LdapConfig {
+      url: url.to_string(),
+      starttls: false,
+      user_dn: "uid={username},ou=people,dc=example,dc=org".into(),
+      group_base: "ou=groups,dc=example,dc=org".into(),
+      group_filter: default_group_filter(),
+      group_mapping: vec![
+        LdapGroupMapping { group: "nas-admins".into(), usertype: UserType::Manager },
+        LdapGroupMapping { group: "staff".into(), usertype: UserType::Member },
+      ],
+      default_usertype: None,
+    }
+  }
+
+  #[test]
+  fn ldap_group_mapping() -> Result<(), Err> {
+    let config = ldap_config("ldap://localhost");
+    let dn = |u: &str| config.user_dn(u);
+    let directory = StandInDirectory(HashMap::from([
+      (dn("alice"), ("secret".to_string(), vec!["staff".to_string(), "nas-admins".to_string()])),
+      (dn("bobby"), ("secret".to_string(), vec!["Staff".to_string()])),
+      (dn("carol"), ("secret".to_string(), vec!["other".to_string()])),
+    ]));
+    let provider = LdapProvider::new(config.clone(), Box::new(directory));
+    assert_eq!(provider.directory_usertype("alice", "secret")?, Some(UserType::Manager));
+    assert_eq!(provider.directory_usertype("bobby", "secret")?, Some(UserType::Member));
+    assert_eq!(provider.directory_usertype("alice", "wrong")?, None);
+    assert_eq!(provider.directory_usertype("alice", "")?, None);
+    // not in a mapped group
+    assert_eq!(provider.directory_usertype("carol", "secret")?, None);
+    assert_eq!(
+      LdapConfig { default_usertype: Some(UserType::Visiter), ..config.clone() }
+        .usertype_of(&["other".to_string()]),
+      Some(UserType::Visiter)
+    );
+    assert_eq!(config.user_dn("a,b"), "uid=a\\2cb,ou=people,dc=example,dc=org");
+    Ok(())
+  }
+
+  /// runs against a real directory when PULSEAR_TEST_LDAP_URL is set, e.g. a local
+  /// openldap with user uid=alice (password secret) in group staff
+  #[test]
+  fn ldap_directory() -> Result<(), Err> {
+    let url = match std::env::var("PULSEAR_TEST_LDAP_URL") {
+      Ok(u) => u,
+      Err(_) => return Ok(()),
+    };
+    let config = ldap_config(&url);
+    let provider = LdapProvider::new(config.clone(), Box::new(Ldap3Directory { config }));
+    assert_eq!(provider.directory_usertype("alice", "secret")?, Some(UserType::Member));
+    assert_eq!(provider.directory_usertype("alice", "wrong")?, None);
+    Ok(())
+  }
+}
@@ -11,9 +11,42 @@ pub struct ServerConfig {
   pub inner_addr: String,
   pub worker_num: i32,
   pub https: bool,
-  pub managers: Vec<String>,
   pub file_worker_num: i32,
-  pub sql_url: String
+  pub sql_url: String,
+  // session token lifetime in seconds
+  #[serde(default = "default_session_ttl")]
+  pub session_ttl: u64,
+  // refresh token lifetime in seconds
+  #[serde(default = "default_refresh_ttl")]
+  pub refresh_ttl: u64,
+  #[serde(default)]
+  pub login_throttle: LoginThrottleConfig,
+  #[serde(default)]
+  pub rate_limit: RateLimitConfig,
+  #[serde(default)]
+  pub registration: RegistrationMode,
+  // tried in order on password login
+  #[serde(default = "default_auth_providers")]
+  pub auth_providers: Vec<AuthProviderConfig>,
+  // slices an upload may have in flight, unwritten ones are held in memory
+  #[serde(default = "default_upload_window")]
+  pub upload_window: u64,
+  #[serde(default)]
+  pub file_writes: FileWriteConfig,
+  #[serde(default)]
+  pub websocket: WsConfig,
+}
+
+fn default_session_ttl() -> u64 {
+  24 * 60 * 60
+}
+
+fn default_refresh_ttl() -> u64 {
+  30 * 24 * 60 * 60
+}
+
+pub fn default_upload_window() -> u64 {
+  64
 }
 
 #[derive(Default, Debug)]
@@ -32,19 +65,34 @@ pub struct Server {
   // map username to a list of UserCtx
   pub user_ctxs: RwLock<HashMap<String, Vec<UserCtx>>>,
   pub file_handler: FileHandler,
-  pub dbpool: mysql::Pool,
+  pub sqlhandler: SqlHandler,
+  // map sha256 of a totp ticket to the login waiting for its code
+  pub pending_totp: RwLock<HashMap<String, PendingTotpLogin>>,
+  pub rate_limiter: RateLimiter,
   info: ServerInfoInner
 }
 
 impl Server {
-  pub fn from(server_config: ServerConfig) -> Self {
-    Self {
-      file_handler: FileHandler::new(server_config.file_worker_num),
+  /// connects the database and migrates it to the embedded schema,
+  /// fails if the schema is incompatible
+  pub fn from(server_config: ServerConfig) -> Result<Self, Err> {
+    let sqlhandler = SqlHandler::connect(&server_config.sql_url)?;
+    sqlhandler.migrate().map_err(|e| -> Err {
+      Box::from(format!("incompatible database schema: {}", e))
+    })?;
+    Ok(Self {
+      file_handler: FileHandler::new(
+        server_config.file_worker_num,
+        server_config.upload_window,
+        server_config.file_writes.clone(),
+      ),
       user_ctxs: RwLock::new(HashMap::new()),
-      dbpool: mysql::Pool::new(server_config.sql_url.as_str()).unwrap(),
+      sqlhandler,
       config: RwLock::new(server_config),
+      pending_totp: RwLock::new(HashMap::new()),
+      rate_limiter: RateLimiter::default(),
       info: ServerInfoInner::default()
-    }
+    })
   }
 
   pub fn r_server_info(&self) -> ServerInfo {
@@ -58,8 +106,40 @@ impl Server {
     self.config.read().unwrap().clone()
   }
 
-  pub fn r_is_manager(&self, username: &String) -> bool {
-    self.r_config().managers.contains(&username)
+  /// returns the ticket for the totp login step
+  pub fn w_add_pending_totp(&self, pending: PendingTotpLogin) -> String {
+    let ticket = random_token();
+    let now = Time::now().milli();
+    let mut pendings = self.pending_totp.write().unwrap();
+    pendings.retain(|_, p| p.expire_t > now);
+    pendings.insert(hash_session_token(&ticket), pending);
+    ticket
+  }
+
+  pub fn r_pending_totp(&self, ticket: &str) -> Option<PendingTotpLogin> {
+    let pendings = self.pending_totp.read().unwrap();
+    let pending = pendings.get(&hash_session_token(ticket))?;
+    if pending.expire_t > Time::now().milli() {
+      Some(pending.clone())
+    } else {
+      None
+    }
+  }
+
+  /// a ticket is dropped after too many wrong codes
+  pub fn w_fail_pending_totp(&self, ticket: &str) {
+    let key = hash_session_token(ticket);
+    let mut pendings = self.pending_totp.write().unwrap();
+    if let Some(pending) = pendings.get_mut(&key) {
+      pending.attempts += 1;
+      if pending.attempts >= PENDING_TOTP_MAX_ATTEMPTS {
+        pendings.remove(&key);
+      }
+    }
+  }
+
+  pub fn w_remove_pending_totp(&self, ticket: &str) {
+    self.pending_totp.write().unwrap().remove(&hash_session_token(ticket));
   }
 
   pub fn r_user_ctxs(&self) -> HashMap<String, Vec<UserCtx>> {
@@ -70,6 +150,30 @@ impl Server {
     self.user_ctxs.read().unwrap().get(username).cloned()
   }
 
+  /// close online sessions of a user, except the one with keep_token
+  pub fn close_user_ctxs(&self, username: &str, keep_token: Option<&str>, reason: &str) {
+    for ctx in self.r_user_ctxs_by_username(&username.to_string()).unwrap_or_default() {
+      if keep_token == Some(ctx.token.as_str()) {
+        continue;
+      }
+      if let Some(addr) = &ctx.session {
+        addr.do_send(WsCloseMessage(reason.to_string()));
+      }
+    }
+  }
+
+  /// close the sockets opened with one token, of a session or an api key
+  pub fn close_token_ctxs(&self, username: &str, token: &str, reason: &str) {
+    for ctx in self.r_user_ctxs_by_username(&username.to_string()).unwrap_or_default() {
+      if ctx.token != token {
+        continue;
+      }
+      if let Some(addr) = &ctx.session {
+        addr.do_send(WsCloseMessage(reason.to_string()));
+      }
+    }
+  }
+
   pub fn r_user_ctxs_exclude_self(&self, user_ctx: &UserCtx) -> Option<Vec<UserCtx>> {
     let mut ctx_vec = self.r_user_ctxs_by_username(&user_ctx.username)?;
     let index = ctx_vec.iter().position(|x| *x == *user_ctx).unwrap();
@@ -157,10 +261,18 @@ fn launch_config_thread(server: Arc<Server>) {
   });
 }
 
+/// like the default Logger, without the query of a request. a websocket is
+/// opened with its token in the query, which must not reach the log
+fn access_log() -> actix_web::middleware::Logger {
+  actix_web::middleware::Logger::new(r#"%a "%{method}xi %U" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+    .custom_request_replace("method", |req| req.method().to_string())
+}
+
 pub async fn start(server: Arc<Server>, use_config_thread: bool) -> std::io::Result<()> {
   let server_config = { server.config.read().unwrap().clone() };
   use std::io::Write;
-  env_logger::builder()
+  // a process may start more than one server, e.g. in tests
+  let _ = env_logger::builder()
     .parse_env(env_logger::Env::new().default_filter_or(server_config.loglevel))
     .format(|buf, record| {
       let module = record.module_path().unwrap_or("");
@@ -187,7 +299,7 @@ pub async fn start(server: Arc<Server>, use_config_thread: bool) -> std::io::Res
         format!("{}", record.args()).green()
       )
     })
-    .init();
+    .try_init();
   // set current cwd to project root such that the static file path work find
   let cwd = std::path::Path::new(&server_config.cwd);
   if std::env::set_current_dir(cwd).is_err() {
@@ -215,20 +327,48 @@ pub async fn start(server: Arc<Server>, use_config_thread: bool) -> std::io::Res
       .unwrap();
     HttpServer::new(move || {
       App::new()
-        .wrap(actix_web::middleware::Logger::default())
+        .wrap(actix_web::middleware::from_fn(rate_limit))
+        .wrap(access_log())
         .app_data(web::Data::new(server.clone()))
         .route("/", web::get().to(index))
         .route("/index.html", web::get().to(index))
         .route("/ws", web::get().to(ws))
         .service(resources)
         .service(login)
+        .service(signup)
+        .service(login_totp)
+        .service(refresh)
         .service(logout)
+        .service(change_password)
+        .service(set_preferences)
+        .service(rename_account)
+        .service(delete_account)
+        .service(get_sessions)
+        .service(terminate_session)
+        .service(get_own_audit)
+        .service(get_all_audit)
         .service(get_file_elem)
         .service(get_file_list)
+        .service(get_file_list_view)
+        .service(set_file_list_view)
         .service(download_raw)
         .service(get_download_url)
         .service(download_by_url)
         .service(delete_file)
+        .service(get_users)
+        .service(set_user_type)
+        .service(create_invitation)
+        .service(get_invitations)
+        .service(delete_invitation)
+        .service(get_api_keys)
+        .service(create_api_key)
+        .service(revoke_api_key)
+        .service(totp_enroll)
+        .service(totp_confirm)
+        .service(totp_disable)
+        .service(totp_recovery_codes)
+        .service(get_role_policies)
+        .service(set_role_policy)
     })
     .bind_openssl(server_config.inner_addr, builder)?
     .workers(server_config.worker_num as usize)
@@ -237,20 +377,48 @@ pub async fn start(server: Arc<Server>, use_config_thread: bool) -> std::io::Res
   } else {
     HttpServer::new(move || {
       App::new()
-        .wrap(actix_web::middleware::Logger::default())
+        .wrap(actix_web::middleware::from_fn(rate_limit))
+        .wrap(access_log())
         .app_data(web::Data::new(server.clone()))
         .route("/", web::get().to(index))
         .route("/index.html", web::get().to(index))
         .route("/ws", web::get().to(ws))
         .service(resources)
         .service(login)
+        .service(signup)
+        .service(login_totp)
+        .service(refresh)
         .service(logout)
+        .service(change_password)
+        .service(set_preferences)
+        .service(rename_account)
+        .service(delete_account)
+        .service(get_sessions)
+        .service(terminate_session)
+        .service(get_own_audit)
+        .service(get_all_audit)
         .service(get_file_elem)
         .service(get_file_list)
+        .service(get_file_list_view)
+        .service(set_file_list_view)
         .service(download_raw)
         .service(get_download_url)
         .service(download_by_url)
         .service(delete_file)
+        .service(get_users)
+        .service(set_user_type)
+        .service(create_invitation)
+        .service(get_invitations)
+        .service(delete_invitation)
+        .service(get_api_keys)
+        .service(create_api_key)
+        .service(revoke_api_key)
+        .service(totp_enroll)
+        .service(totp_confirm)
+        .service(totp_disable)
+        .service(totp_recovery_codes)
+        .service(get_role_policies)
+        .service(set_role_policy)
     })
     .bind(server_config.inner_addr)?
     .workers(server_config.worker_num as usize)
@@ -0,0 +1,144 @@
+use crate::*;
+
+/// a connected websocket of the caller, see Server::user_ctxs
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone, PartialEq)]
+pub struct ActiveSession {
+  // hash of the UserCtx, used to terminate it
+  pub id: String,
+  pub browser: String,
+  pub device: String,
+  pub ip: String,
+  pub establish_t: u64,
+  // opened with the same login as the caller
+  pub current: bool,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone)]
+pub enum TerminateTarget {
+  One(String),
+  // every session not opened with the login of the caller
+  AllOthers,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct TerminateSessionRequest {
+  pub target: TerminateTarget,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct TerminateSessionResponse {
+  pub terminated: usize,
+}
+
+/// browser and device of a user agent, only the common ones are told apart
+pub fn parse_user_agent(ua: &str) -> (String, String) {
+  // order matters, e.g. edge and chrome both claim to be safari
+  let browsers = [
+    ("Edg/", "Edge"),
+    ("OPR/", "Opera"),
+    ("Firefox/", "Firefox"),
+    ("Chrome/", "Chrome"),
+    ("Safari/", "Safari"),
+    ("curl/", "curl"),
+  ];
+  let devices = [
+    ("Android", "Android"),
+    ("iPhone", "iPhone"),
+    ("iPad", "iPad"),
+    ("Windows", "Windows"),
+    ("Mac OS X", "macOS"),
+    ("Linux", "Linux"),
+  ];
+  let find = |table: &[(&str, &str)]| {
+    table
+      .iter()
+      .find(|(pattern, _)| ua.contains(pattern))
+      .map(|(_, name)| name.to_string())
+      .unwrap_or_else(|| "Unknown".to_string())
+  };
+  (find(&browsers), find(&devices))
+}
+
+pub fn list_sessions(server: &Server, username: &String, current_token: &str) -> Vec<ActiveSession> {
+  let mut sessions: Vec<ActiveSession> = server
+    .r_user_ctxs_by_username(username)
+    .unwrap_or_default()
+    .iter()
+    .map(|ctx| {
+      let (browser, device) = parse_user_agent(&ctx.user_agent);
+      ActiveSession {
+        id: ctx.hash(),
+        browser,
+        device,
+        ip: ctx.ip.clone(),
+        establish_t: ctx.establish_t.milli(),
+        current: ctx.token == current_token,
+      }
+    })
+    .collect();
+  sessions.sort_by_key(|s| s.establish_t);
+  sessions
+}
+
+/// revoke the target logins of a user such that they can not connect again,
+/// and close every websocket opened with them. returns how many are closed
+pub fn terminate_sessions(
+  server: &Server,
+  username: &String,
+  current_token: &str,
+  target: &TerminateTarget,
+) -> Result<usize, Err> {
+  let ctxs = server.r_user_ctxs_by_username(username).unwrap_or_default();
+  let mut tokens: Vec<&String> = ctxs
+    .iter()
+    .filter(|ctx| match target {
+      TerminateTarget::One(id) => &ctx.hash() == id,
+      TerminateTarget::AllOthers => ctx.token != current_token,
+    })
+    .map(|ctx| &ctx.token)
+    .collect();
+  tokens.sort();
+  tokens.dedup();
+  if let TerminateTarget::One(id) = target {
+    if tokens.is_empty() {
+      return Err(ApiError::err(ErrorCode::NotFound, format!("session not exists: {}", id)));
+    }
+  }
+  let sqlhandler = server.sqlhandler.clone();
+  for token in &tokens {
+    // api keys are revoked on their own
+    if let Some(session) = sqlhandler.get_session_by_token(token)? {
+      sqlhandler.revoke_session(session.id)?;
+    }
+  }
+  // other tabs and ws workers of a revoked login go with it
+  let targets: Vec<&UserCtx> = ctxs.iter().filter(|ctx| tokens.contains(&&ctx.token)).collect();
+  for ctx in &targets {
+    if let Some(addr) = &ctx.session {
+      addr.do_send(WsCloseMessage("signed out from another session".into()));
+    }
+  }
+  log::info!("user {} terminated {} sessions with {:?}", username, targets.len(), target);
+  Ok(targets.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn user_agent() {
+    let chrome = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+      (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+    assert_eq!(parse_user_agent(chrome), ("Chrome".into(), "Windows".into()));
+    let edge = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 \
+      (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.0.0";
+    assert_eq!(parse_user_agent(edge), ("Edge".into(), "macOS".into()));
+    let safari = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) AppleWebKit/605.1.15 \
+      (KHTML, like Gecko) Version/17.0 Mobile/15E148 Safari/604.1";
+    assert_eq!(parse_user_agent(safari), ("Safari".into(), "iPhone".into()));
+    let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0";
+    assert_eq!(parse_user_agent(firefox), ("Firefox".into(), "Linux".into()));
+    assert_eq!(parse_user_agent(""), ("Unknown".into(), "Unknown".into()));
+  }
+}
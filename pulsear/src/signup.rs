@@ -0,0 +1,266 @@
+use crate::*;
+
+/// who may create an account with the signup endpoint
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone, Default, PartialEq)]
+pub enum RegistrationMode {
+  // anyone, an invitation code is optional
+  #[default]
+  Open,
+  // only with a valid invitation code
+  InviteOnly,
+  // nobody, managers may still change existing users
+  Closed,
+}
+
+pub const USERNAME_MIN_LEN: usize = 4;
+pub const USERNAME_MAX_LEN: usize = 16;
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct SignupRequest {
+  pub basic_info: StreamBasicInfo,
+  pub username: String,
+  pub password: String,
+  #[serde(default)]
+  pub invite_code: Option<String>,
+}
+
+/// an invitation code created by a manager, stored in invitation table.
+/// only the sha256 of the code is kept
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone)]
+pub struct Invitation {
+  pub id: i32,
+  #[serde(skip)]
+  pub code_hash: String,
+  // type of the users created with this code
+  pub usertype: UserType,
+  // how many users can be created with this code
+  pub quota: u32,
+  pub used: u32,
+  pub creator_id: i32,
+  pub create_t: u64,
+  // 0 means never expire
+  pub expire_t: u64,
+}
+
+impl Invitation {
+  pub fn is_usable(&self, now: u64) -> bool {
+    self.used < self.quota && (self.expire_t == 0 || self.expire_t > now)
+  }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct CreateInvitationRequest {
+  pub usertype: UserType,
+  pub quota: u32,
+  // lifetime in seconds, never expire if absent
+  #[serde(default)]
+  pub ttl: Option<u64>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct CreateInvitationResponse {
+  // the plain code, only shown once
+  pub code: String,
+  pub invitation: Invitation,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct DeleteInvitationRequest {
+  pub id: i32,
+}
+
+pub fn check_signup_username(username: &str) -> Result<(), Err> {
+  let len = username.chars().count();
+  if !(USERNAME_MIN_LEN..=USERNAME_MAX_LEN).contains(&len) {
+    return Err(ApiError::err(ErrorCode::InvalidName, format!(
+      "username length must be {}~{}", USERNAME_MIN_LEN, USERNAME_MAX_LEN
+    )));
+  }
+  if username.chars().any(|c| c.is_control() || c == '/' || c == '\\') || username.starts_with('.') {
+    return Err(ApiError::err(ErrorCode::InvalidName, "username contains invalid characters"));
+  }
+  Ok(())
+}
+
+/// the invitation of a code if it can still be used, no use is counted
+fn usable_invitation(sqlhandler: &SqlHandler, code: &str, now: u64) -> Result<Invitation, Err> {
+  let code_hash = hash_session_token(code);
+  match sqlhandler.get_invitations()?.into_iter().find(|i| i.code_hash == code_hash) {
+    Some(invitation) if invitation.is_usable(now) => Ok(invitation),
+    _ => Err(ApiError::err(ErrorCode::Forbidden, "invitation code not valid")),
+  }
+}
+
+/// create an account according to ServerConfig.registration, the new user is logged in
+pub fn do_signup(
+  client: &ClientInfo,
+  param: &web::Json<SignupRequest>,
+  data: &web::Data<Arc<Server>>,
+) -> Result<LoginResponse, Err> {
+  log::info!("user try signup: {}", param.username);
+  let config = data.r_config();
+  let invite_code = param.invite_code.as_deref().filter(|c| !c.is_empty());
+  match config.registration {
+    RegistrationMode::Closed => return Err(ApiError::err(ErrorCode::Forbidden, "registration is closed")),
+    RegistrationMode::InviteOnly if invite_code.is_none() => {
+      return Err(ApiError::err(ErrorCode::Forbidden, "an invitation code is required"))
+    }
+    _ => (),
+  }
+  check_signup_username(&param.username)?;
+  check_password_len(&param.password)?;
+
+  let sqlhandler = data.sqlhandler.clone();
+  // each try from an ip counts, such that one ip can not create accounts without limit
+  let signup_keys = [LoginAttempt::signup_key(&client.ip)];
+  check_login_attempts(&sqlhandler, &signup_keys)?;
+  record_login_failure(&sqlhandler, &signup_keys, &config.login_throttle)?;
+
+  if sqlhandler.get_user_by_name(&param.username)?.is_some() {
+    return Err(ApiError::err(ErrorCode::Conflict, "user exists"));
+  }
+  // a use is only counted once the user exists, a failed signup keeps it
+  let usertype = match invite_code {
+    Some(code) => usable_invitation(&sqlhandler, code, Time::now().milli())?.usertype,
+    None => UserType::default(),
+  };
+  let user = match sqlhandler.add_user(&User {
+    id: 0,
+    username: param.username.clone(),
+    token: password_token(&param.username, &param.password),
+    config: UserConfig::default(),
+    usertype,
+  })? {
+    Some(u) => u,
+    None => return Err(Box::from("add user error")),
+  };
+  if let Some(code) = invite_code {
+    match sqlhandler.use_invitation(&hash_session_token(code), Time::now().milli()) {
+      Ok(Some(invitation)) => log::info!("{} signup with invitation {}", user.username, invitation.id),
+      // used up by another signup since it was checked, the user is taken back
+      used => {
+        sqlhandler.delete_user_by_name(&user.username)?;
+        used?;
+        return Err(ApiError::err(ErrorCode::Forbidden, "invitation code not valid"));
+      }
+    }
+  }
+  session_login_response(&sqlhandler, user, &client.user_agent, &config)
+}
+
+/// managers can not invite users of a type above themselves
+pub fn do_create_invitation(
+  auth: &AuthUser,
+  param: &CreateInvitationRequest,
+  sqlhandler: &SqlHandler,
+) -> Result<CreateInvitationResponse, Err> {
+  let now = Time::now().milli();
+  let code = random_token()[..16].to_string();
+  let invitation = sqlhandler.add_invitation(&Invitation {
+    id: 0,
+    code_hash: hash_session_token(&code),
+    usertype: param.usertype.clone(),
+    quota: param.quota,
+    used: 0,
+    creator_id: auth.user.id,
+    create_t: now,
+    expire_t: expire_t_of_ttl(now, param.ttl)?,
+  })?;
+  log::info!(
+    "{} created invitation {} for {} {:?}",
+    auth.user.username, invitation.id, invitation.quota, invitation.usertype
+  );
+  Ok(CreateInvitationResponse { code, invitation })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn signup_check() {
+    assert!(check_signup_username("abcd").is_ok());
+    assert!(check_signup_username("abc").is_err());
+    assert!(check_signup_username("a/bcd").is_err());
+    assert!(check_signup_username("..abc").is_err());
+    assert!(check_signup_username(&"a".repeat(17)).is_err());
+    let mut invitation = Invitation {
+      id: 1,
+      code_hash: String::new(),
+      usertype: UserType::Member,
+      quota: 1,
+      used: 0,
+      creator_id: 1,
+      create_t: 0,
+      expire_t: 0,
+    };
+    assert!(invitation.is_usable(100));
+    invitation.expire_t = 100;
+    assert!(!invitation.is_usable(100));
+    invitation.expire_t = 0;
+    invitation.used = 1;
+    assert!(!invitation.is_usable(100));
+    let mode: RegistrationMode = serde_json::from_str("\"InviteOnly\"").unwrap();
+    assert_eq!(mode, RegistrationMode::InviteOnly);
+  }
+
+  #[test]
+  fn signup_invitation() {
+    let server = crate::auth::tests::TestServer::new(&String::from("error"), &String::from("127.0.0.1:0"));
+    let data = web::Data::new(server.server.clone());
+    let sqlhandler = &server.server.sqlhandler;
+    sqlhandler.add_user(&User {
+      id: 0,
+      username: "taken0".into(),
+      token: password_token("taken0", "taken0"),
+      config: UserConfig::default(),
+      usertype: UserType::default(),
+    }).unwrap();
+    let invitation = sqlhandler.add_invitation(&Invitation {
+      id: 0,
+      code_hash: hash_session_token("invite0"),
+      usertype: UserType::Manager,
+      quota: 1,
+      used: 0,
+      creator_id: 1,
+      create_t: 0,
+      expire_t: 0,
+    }).unwrap();
+    let client = ClientInfo {
+      ip: "10.0.0.3".into(),
+      user_agent: String::new(),
+    };
+    let signup = |username: &str, password: &str| {
+      do_signup(&client, &web::Json(SignupRequest {
+        basic_info: StreamBasicInfo { time_stamp: 0 },
+        username: username.into(),
+        password: password.into(),
+        invite_code: Some("invite0".into()),
+      }), &data)
+    };
+    let code = |r: Result<LoginResponse, Err>| ApiError::of(r.err().unwrap(), ErrorCode::Internal).code;
+    let used = || sqlhandler.get_invitations().unwrap().iter().find(|i| i.id == invitation.id).unwrap().used;
+
+    // a failed signup does not count a use
+    assert_eq!(code(signup("newbie0", "abc")), ErrorCode::InvalidRequest);
+    assert_eq!(code(signup("taken0", "taken0")), ErrorCode::Conflict);
+    assert_eq!(used(), 0);
+    assert!(signup("newbie0", "newbie0").is_ok());
+    assert_eq!(used(), 1);
+    let user = sqlhandler.get_user_by_name("newbie0").unwrap().unwrap();
+    assert_eq!(user.usertype, UserType::Manager);
+    assert_eq!(code(signup("newbie1", "newbie1")), ErrorCode::Forbidden);
+    assert!(sqlhandler.get_user_by_name("newbie1").unwrap().is_none());
+
+    let creator = sqlhandler.get_user_by_name("taken0").unwrap().unwrap();
+    let issued = issue_session(sqlhandler, &creator, "", &server.server.r_config()).unwrap();
+    let auth = AuthUser {
+      user: creator,
+      credential: Credential::Session(issued.session),
+      token_hash: hash_session_token(&issued.token),
+    };
+    let huge = CreateInvitationRequest { usertype: UserType::User, quota: 1, ttl: Some(u64::MAX) };
+    let e = do_create_invitation(&auth, &huge, sqlhandler).err().unwrap();
+    assert_eq!(ApiError::of(e, ErrorCode::Internal).code, ErrorCode::InvalidRequest);
+  }
+}
@@ -0,0 +1,574 @@
+use crate::*;
+use rusqlite::{params, OptionalExtension};
+
+/// an embedded database in one file, sqlite://path/to/file.db or sqlite::memory:
+pub struct SqliteRepository {
+  dbpool: r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>,
+}
+
+type UserRow = (i32, String, String, Option<String>, i32, i32, Option<String>, String);
+
+const USER_SELECT: &str =
+  r"SELECT user.id, username, token, theme, user_config.id, web_worker_num, filelist_config, type
+    FROM user, user_config
+    WHERE user.id = user_config.user_id";
+
+fn user_from_row(row: UserRow) -> Result<User, Err> {
+  Ok(User {
+    id: row.0,
+    username: row.1,
+    token: row.2,
+    config: UserConfig {
+      id: row.4,
+      theme: row.3.unwrap_or_default(),
+      web_worker_num: row.5,
+      filelist_config: serde_json::from_str(row.6.as_deref().unwrap_or("{}"))?,
+    },
+    usertype: UserType::from(&row.7),
+  })
+}
+
+fn read_user_row(r: &rusqlite::Row) -> rusqlite::Result<UserRow> {
+  Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?, r.get(5)?, r.get(6)?, r.get(7)?))
+}
+
+fn read_invitation_row(r: &rusqlite::Row) -> rusqlite::Result<InvitationRow> {
+  Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?, r.get(5)?, r.get(6)?, r.get(7)?))
+}
+
+fn read_api_key_row(r: &rusqlite::Row) -> rusqlite::Result<ApiKeyRow> {
+  Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?, r.get(5)?, r.get(6)?, r.get(7)?, r.get(8)?))
+}
+
+fn read_file_action_row(r: &rusqlite::Row) -> rusqlite::Result<FileActionRow> {
+  Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?, r.get(5)?, r.get(6)?, r.get(7)?, r.get(8)?))
+}
+
+impl SqliteRepository {
+  pub fn new(sql_url: &str) -> Result<Self, Err> {
+    let (manager, max_size) = if sql_url == "sqlite::memory:" {
+      // every connection would open its own database
+      (r2d2_sqlite::SqliteConnectionManager::memory(), 1)
+    } else {
+      match sql_url.strip_prefix("sqlite://") {
+        Some(path) if !path.is_empty() => (r2d2_sqlite::SqliteConnectionManager::file(path), 8),
+        _ => return Err(Box::from(format!("invalid sqlite url: {}", sql_url))),
+      }
+    };
+    let manager = manager.with_init(|c| {
+      c.busy_timeout(Duration::from_secs(5))?;
+      c.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")
+    });
+    Ok(Self {
+      dbpool: r2d2::Pool::builder().max_size(max_size).build(manager)?,
+    })
+  }
+
+  fn get_user_by(&self, column: &str, value: &dyn rusqlite::ToSql) -> Result<Option<User>, Err> {
+    let dbconn = self.dbpool.get()?;
+    let row = dbconn
+      .query_row(&format!("{} AND {} = ?1", USER_SELECT, column), [value], read_user_row)
+      .optional()?;
+    row.map(user_from_row).transpose()
+  }
+
+  fn get_session_by(&self, column: &str, value: &str) -> Result<Option<Session>, Err> {
+    let dbconn = self.dbpool.get()?;
+    let row: Option<SessionRow> = dbconn
+      .query_row(
+        &format!(
+          r"SELECT id, user_id, user_agent, create_t, expire_t, refresh_expire_t, revoked
+            FROM session
+            WHERE {} = ?1",
+          column
+        ),
+        [value],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?, r.get(5)?, r.get(6)?)),
+      )
+      .optional()?;
+    Ok(row.map(session_from_row))
+  }
+}
+
+impl Repository for SqliteRepository {
+  fn migrate(&self) -> Result<u32, Err> {
+    let mut dbconn = self.dbpool.get()?;
+    dbconn.execute_batch(
+      r"CREATE TABLE IF NOT EXISTS `schema_version` (
+          `version` int NOT NULL PRIMARY KEY,
+          `name` varchar(64) NOT NULL,
+          `checksum` varchar(64) NOT NULL,
+          `applied_t` bigint NOT NULL
+        )",
+    )?;
+    let applied: Vec<(u32, String)> = dbconn
+      .prepare("SELECT version, checksum FROM schema_version ORDER BY version")?
+      .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+      .collect::<Result<_, _>>()?;
+    let current = check_applied(SQLITE_MIGRATIONS, &applied)?;
+    for migration in SQLITE_MIGRATIONS.iter().filter(|m| m.version > current) {
+      log::info!("apply migration {} {}", migration.version, migration.name);
+      // ddl is transactional in sqlite, a failed migration leaves nothing behind
+      let tx = dbconn.transaction()?;
+      tx.execute_batch(migration.sql).map_err(|e| -> Err {
+        Box::from(format!("migration {} {} failed: {}", migration.version, migration.name, e))
+      })?;
+      tx.execute(
+        r"INSERT INTO schema_version(version, name, checksum, applied_t)
+          VALUES (?1, ?2, ?3, ?4)",
+        params![migration.version, migration.name, migration.checksum(), Time::now().milli()],
+      )?;
+      tx.commit()?;
+    }
+    Ok(current)
+  }
+
+  fn get_users(&self) -> Result<Vec<User>, Err> {
+    let dbconn = self.dbpool.get()?;
+    let rows: Vec<UserRow> = dbconn
+      .prepare(USER_SELECT)?
+      .query_map([], read_user_row)?
+      .collect::<Result<_, _>>()?;
+    rows.into_iter().map(user_from_row).collect()
+  }
+
+  fn get_user_by_name(&self, username: &str) -> Result<Option<User>, Err> {
+    self.get_user_by("username", &username)
+  }
+
+  fn get_user_by_id(&self, id: i32) -> Result<Option<User>, Err> {
+    self.get_user_by("user.id", &id)
+  }
+
+  fn add_user(&self, user: &User) -> Result<Option<User>, Err> {
+    let mut dbconn = self.dbpool.get()?;
+    let tx = dbconn.transaction()?;
+    tx.execute(
+      r"INSERT INTO user(username, token, type) VALUES (?1, ?2, ?3)",
+      params![&user.username, &user.token, user.usertype.to_string()],
+    )?;
+    let user_id = tx.last_insert_rowid();
+    tx.execute(
+      r"INSERT INTO user_config(user_id, theme, web_worker_num, filelist_config)
+        VALUES (?1, ?2, ?3, ?4)",
+      params![
+        user_id,
+        &user.config.theme,
+        user.config.web_worker_num,
+        serde_json::to_string(&user.config.filelist_config)?
+      ],
+    )?;
+    tx.commit()?;
+    drop(dbconn);
+    self.get_user_by_name(&user.username)
+  }
+
+  fn delete_user_by_name(&self, username: &str) -> Result<(), Err> {
+    let user = match self.get_user_by_name(username)? {
+      Some(u) => u,
+      None => return Err(Box::from(format!("user does not exist: {}", username))),
+    };
+    log::info!("delete user[{:?}]", user);
+    let mut dbconn = self.dbpool.get()?;
+    let tx = dbconn.transaction()?;
+    for statement in [
+      "DELETE FROM session WHERE user_id = ?1",
+      "DELETE FROM api_key WHERE user_id = ?1",
+      "DELETE FROM invitation WHERE creator_id = ?1",
+      "DELETE FROM user_totp WHERE user_id = ?1",
+      "DELETE FROM user_config WHERE user_id = ?1",
+      "DELETE FROM user_file_action WHERE user_id = ?1",
+      "DELETE FROM user WHERE id = ?1",
+    ] {
+      tx.execute(statement, [user.id])?;
+    }
+    tx.commit()?;
+    Ok(())
+  }
+
+  fn update_user_config_by_name(&self, username: &str, config: &UserConfig) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    let updated = dbconn.execute(
+      r"UPDATE user_config SET theme = ?1, web_worker_num = ?2, filelist_config = ?3
+        WHERE user_id = (SELECT id FROM user WHERE username = ?4)",
+      params![
+        &config.theme,
+        config.web_worker_num,
+        serde_json::to_string(&config.filelist_config)?,
+        username
+      ],
+    )?;
+    if updated == 0 {
+      return Err(Box::from(format!("user does not exist: {}", username)));
+    }
+    Ok(())
+  }
+
+  fn update_user_type_by_name(&self, username: &str, usertype: &UserType) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(
+      r"UPDATE user SET type = ?1 WHERE username = ?2",
+      params![usertype.to_string(), username],
+    )?;
+    Ok(())
+  }
+
+  fn update_user_token(&self, user_id: i32, token: &str) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(r"UPDATE user SET token = ?1 WHERE id = ?2", params![token, user_id])?;
+    Ok(())
+  }
+
+  fn update_username(&self, user_id: i32, username: &str, token: &str) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(
+      r"UPDATE user SET username = ?1, token = ?2 WHERE id = ?3",
+      params![username, token, user_id],
+    )?;
+    Ok(())
+  }
+
+  fn user_login(&self, username: &str) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(
+      r"UPDATE user SET last_login_time = CURRENT_TIMESTAMP WHERE username = ?1",
+      [username],
+    )?;
+    Ok(())
+  }
+
+  fn add_session(&self, session: &Session, token: &str, refresh_token: &str) -> Result<Session, Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(
+      r"INSERT INTO session(user_id, token, refresh_token, user_agent, create_t, expire_t, refresh_expire_t)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+      params![
+        session.user_id,
+        token,
+        refresh_token,
+        &session.user_agent,
+        session.create_t,
+        session.expire_t,
+        session.refresh_expire_t
+      ],
+    )?;
+    Ok(Session {
+      id: dbconn.last_insert_rowid() as i32,
+      revoked: false,
+      ..session.clone()
+    })
+  }
+
+  fn get_session_by_token(&self, token: &str) -> Result<Option<Session>, Err> {
+    self.get_session_by("token", token)
+  }
+
+  fn get_session_by_refresh_token(&self, refresh_token: &str) -> Result<Option<Session>, Err> {
+    self.get_session_by("refresh_token", refresh_token)
+  }
+
+  fn revoke_session(&self, session_id: i32) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(r"UPDATE session SET revoked = 1 WHERE id = ?1", [session_id])?;
+    Ok(())
+  }
+
+  fn revoke_user_sessions(&self, user_id: i32) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(r"UPDATE session SET revoked = 1 WHERE user_id = ?1", [user_id])?;
+    Ok(())
+  }
+
+  fn revoke_user_sessions_except(&self, user_id: i32, keep_id: i32) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(
+      r"UPDATE session SET revoked = 1 WHERE user_id = ?1 AND id != ?2",
+      [user_id, keep_id],
+    )?;
+    Ok(())
+  }
+
+  fn delete_expired_sessions(&self, now: u64) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(
+      r"DELETE FROM session WHERE refresh_expire_t < ?1 OR (revoked = 1 AND expire_t < ?1)",
+      [now],
+    )?;
+    Ok(())
+  }
+
+  fn get_user_totp(&self, user_id: i32) -> Result<Option<UserTotp>, Err> {
+    let dbconn = self.dbpool.get()?;
+    let row: Option<(String, bool, Option<String>, u64)> = dbconn
+      .query_row(
+        r"SELECT secret, enabled, recovery_codes, last_used_step FROM user_totp WHERE user_id = ?1",
+        [user_id],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+      )
+      .optional()?;
+    match row {
+      Some(r) => Ok(Some(UserTotp {
+        user_id,
+        secret: r.0,
+        enabled: r.1,
+        recovery_codes: match r.2 {
+          Some(codes) => serde_json::from_str(&codes)?,
+          None => vec![],
+        },
+        last_used_step: r.3,
+      })),
+      None => Ok(None),
+    }
+  }
+
+  fn save_user_totp(&self, totp: &UserTotp) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(
+      r"INSERT INTO user_totp(user_id, secret, enabled, recovery_codes, last_used_step)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT(user_id) DO UPDATE SET secret = excluded.secret, enabled = excluded.enabled,
+          recovery_codes = excluded.recovery_codes, last_used_step = excluded.last_used_step",
+      params![
+        totp.user_id,
+        &totp.secret,
+        totp.enabled,
+        serde_json::to_string(&totp.recovery_codes)?,
+        totp.last_used_step
+      ],
+    )?;
+    Ok(())
+  }
+
+  fn delete_user_totp(&self, user_id: i32) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(r"DELETE FROM user_totp WHERE user_id = ?1", [user_id])?;
+    Ok(())
+  }
+
+  fn get_role_policies(&self) -> Result<Vec<RolePolicy>, Err> {
+    let dbconn = self.dbpool.get()?;
+    let rows: Vec<(String, bool)> = dbconn
+      .prepare(r"SELECT usertype, require_totp FROM role_policy")?
+      .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+      .collect::<Result<_, _>>()?;
+    Ok(rows
+      .into_iter()
+      .map(|r| RolePolicy {
+        usertype: UserType::from(&r.0),
+        require_totp: r.1,
+      })
+      .collect())
+  }
+
+  fn role_requires_totp(&self, usertype: &UserType) -> Result<bool, Err> {
+    let dbconn = self.dbpool.get()?;
+    let row: Option<bool> = dbconn
+      .query_row(
+        r"SELECT require_totp FROM role_policy WHERE usertype = ?1",
+        [usertype.to_string()],
+        |r| r.get(0),
+      )
+      .optional()?;
+    Ok(row.unwrap_or(false))
+  }
+
+  fn set_role_policy(&self, policy: &RolePolicy) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(
+      r"INSERT INTO role_policy(usertype, require_totp) VALUES (?1, ?2)
+        ON CONFLICT(usertype) DO UPDATE SET require_totp = excluded.require_totp",
+      params![policy.usertype.to_string(), policy.require_totp],
+    )?;
+    Ok(())
+  }
+
+  fn get_login_attempt(&self, key: &str) -> Result<Option<LoginAttempt>, Err> {
+    let dbconn = self.dbpool.get()?;
+    let row: Option<(u32, u64, u64)> = dbconn
+      .query_row(
+        r"SELECT failures, last_failure_t, lock_until_t FROM login_attempt WHERE attempt_key = ?1",
+        [key],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+      )
+      .optional()?;
+    Ok(row.map(|r| LoginAttempt {
+      key: key.to_string(),
+      failures: r.0,
+      last_failure_t: r.1,
+      lock_until_t: r.2,
+    }))
+  }
+
+  fn save_login_attempt(&self, attempt: &LoginAttempt) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(
+      r"INSERT INTO login_attempt(attempt_key, failures, last_failure_t, lock_until_t)
+        VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT(attempt_key) DO UPDATE SET failures = excluded.failures,
+          last_failure_t = excluded.last_failure_t, lock_until_t = excluded.lock_until_t",
+      params![&attempt.key, attempt.failures, attempt.last_failure_t, attempt.lock_until_t],
+    )?;
+    Ok(())
+  }
+
+  fn delete_login_attempt(&self, key: &str) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(r"DELETE FROM login_attempt WHERE attempt_key = ?1", [key])?;
+    Ok(())
+  }
+
+  fn add_invitation(&self, invitation: &Invitation) -> Result<Invitation, Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(
+      r"INSERT INTO invitation(code, usertype, quota, used, creator_id, create_t, expire_t)
+        VALUES (?1, ?2, ?3, 0, ?4, ?5, ?6)",
+      params![
+        &invitation.code_hash,
+        invitation.usertype.to_string(),
+        invitation.quota,
+        invitation.creator_id,
+        invitation.create_t,
+        invitation.expire_t
+      ],
+    )?;
+    Ok(Invitation {
+      id: dbconn.last_insert_rowid() as i32,
+      used: 0,
+      ..invitation.clone()
+    })
+  }
+
+  fn get_invitations(&self) -> Result<Vec<Invitation>, Err> {
+    let dbconn = self.dbpool.get()?;
+    let rows: Vec<InvitationRow> = dbconn
+      .prepare(
+        r"SELECT id, code, usertype, quota, used, creator_id, create_t, expire_t
+          FROM invitation ORDER BY id",
+      )?
+      .query_map([], read_invitation_row)?
+      .collect::<Result<_, _>>()?;
+    Ok(rows.into_iter().map(invitation_from_row).collect())
+  }
+
+  fn use_invitation(&self, code_hash: &str, now: u64) -> Result<Option<Invitation>, Err> {
+    let mut dbconn = self.dbpool.get()?;
+    // takes the write lock up front, as FOR UPDATE does in mysql
+    let tx = dbconn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let row = tx
+      .query_row(
+        r"SELECT id, code, usertype, quota, used, creator_id, create_t, expire_t
+          FROM invitation WHERE code = ?1",
+        [code_hash],
+        read_invitation_row,
+      )
+      .optional()?;
+    let mut invitation = match row.map(invitation_from_row) {
+      Some(i) if i.is_usable(now) => i,
+      _ => return Ok(None),
+    };
+    tx.execute(r"UPDATE invitation SET used = used + 1 WHERE id = ?1", [invitation.id])?;
+    tx.commit()?;
+    invitation.used += 1;
+    Ok(Some(invitation))
+  }
+
+  fn delete_invitation(&self, id: i32) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(r"DELETE FROM invitation WHERE id = ?1", [id])?;
+    Ok(())
+  }
+
+  fn add_api_key(&self, api_key: &ApiKey) -> Result<ApiKey, Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(
+      r"INSERT INTO api_key(user_id, name, key_hash, prefix, scopes, create_t, expire_t, last_used_t)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 0)",
+      params![
+        api_key.user_id,
+        &api_key.name,
+        &api_key.key_hash,
+        &api_key.prefix,
+        serde_json::to_string(&api_key.scopes)?,
+        api_key.create_t,
+        api_key.expire_t
+      ],
+    )?;
+    Ok(ApiKey {
+      id: dbconn.last_insert_rowid() as i32,
+      ..api_key.clone()
+    })
+  }
+
+  fn get_api_key_by_hash(&self, key_hash: &str) -> Result<Option<ApiKey>, Err> {
+    let dbconn = self.dbpool.get()?;
+    let row = dbconn
+      .query_row(
+        r"SELECT id, user_id, name, key_hash, prefix, scopes, create_t, expire_t, last_used_t
+          FROM api_key WHERE key_hash = ?1",
+        [key_hash],
+        read_api_key_row,
+      )
+      .optional()?;
+    row.map(api_key_from_row).transpose()
+  }
+
+  fn get_user_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>, Err> {
+    let dbconn = self.dbpool.get()?;
+    let rows: Vec<ApiKeyRow> = dbconn
+      .prepare(
+        r"SELECT id, user_id, name, key_hash, prefix, scopes, create_t, expire_t, last_used_t
+          FROM api_key WHERE user_id = ?1 ORDER BY id",
+      )?
+      .query_map([user_id], read_api_key_row)?
+      .collect::<Result<_, _>>()?;
+    rows.into_iter().map(api_key_from_row).collect()
+  }
+
+  fn touch_api_key(&self, id: i32, now: u64) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(r"UPDATE api_key SET last_used_t = ?1 WHERE id = ?2", params![now, id])?;
+    Ok(())
+  }
+
+  fn delete_api_key(&self, user_id: i32, id: i32) -> Result<bool, Err> {
+    let dbconn = self.dbpool.get()?;
+    let deleted = dbconn.execute(r"DELETE FROM api_key WHERE id = ?1 AND user_id = ?2", [id, user_id])?;
+    Ok(deleted > 0)
+  }
+
+  fn add_file_action(&self, action: &FileAction) -> Result<(), Err> {
+    let dbconn = self.dbpool.get()?;
+    dbconn.execute(
+      r"INSERT INTO user_file_action(user_id, filename, filepath, action, ip, session_id, api_key_id, create_t)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+      params![
+        action.user_id,
+        &action.filename,
+        &action.filepath,
+        action.action.to_string(),
+        &action.ip,
+        action.session_id,
+        action.api_key_id,
+        action.create_t
+      ],
+    )?;
+    Ok(())
+  }
+
+  fn query_file_actions(&self, filter: &FileActionFilter) -> Result<Vec<FileAction>, Err> {
+    let (query, values) = file_action_query(filter, |n, _| format!("?{}", n));
+    let values: Vec<rusqlite::types::Value> = values
+      .into_iter()
+      .map(|v| match v {
+        SqlParam::Int(i) => i.into(),
+        SqlParam::Text(t) => t.into(),
+      })
+      .collect();
+    let dbconn = self.dbpool.get()?;
+    let rows: Vec<FileActionRow> = dbconn
+      .prepare(&query)?
+      .query_map(rusqlite::params_from_iter(values), read_file_action_row)?
+      .collect::<Result<_, _>>()?;
+    rows.into_iter().map(file_action_from_row).collect()
+  }
+}
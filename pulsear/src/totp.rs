@@ -0,0 +1,283 @@
+use crate::*;
+
+// time based one time password, see rfc 6238 and rfc 4226
+pub const TOTP_PERIOD: u64 = 30;
+pub const TOTP_DIGITS: u32 = 6;
+pub const TOTP_ISSUER: &str = "Pulsear";
+pub const RECOVERY_CODE_NUM: usize = 10;
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// rfc 4648 base32 without padding, which is what authenticator apps expect
+pub fn base32_encode(bytes: &[u8]) -> String {
+  let mut out = String::new();
+  let mut buffer: u32 = 0;
+  let mut bits = 0;
+  for b in bytes {
+    buffer = (buffer << 8) | *b as u32;
+    bits += 8;
+    while bits >= 5 {
+      out.push(BASE32_ALPHABET[((buffer >> (bits - 5)) & 31) as usize] as char);
+      bits -= 5;
+    }
+  }
+  if bits > 0 {
+    out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+  }
+  out
+}
+
+pub fn base32_decode(s: &str) -> Option<Vec<u8>> {
+  let mut out = vec![];
+  let mut buffer: u32 = 0;
+  let mut bits = 0;
+  for c in s.trim_end_matches('=').chars() {
+    if c == ' ' {
+      continue;
+    }
+    let v = BASE32_ALPHABET.iter().position(|a| *a as char == c.to_ascii_uppercase())?;
+    buffer = (buffer << 5) | v as u32;
+    bits += 5;
+    if bits >= 8 {
+      out.push((buffer >> (bits - 8)) as u8);
+      bits -= 8;
+    }
+  }
+  Some(out)
+}
+
+pub fn hotp(secret: &[u8], counter: u64) -> Result<u32, Err> {
+  use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+  let key = PKey::hmac(secret)?;
+  let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+  let hmac = signer.sign_oneshot_to_vec(&counter.to_be_bytes())?;
+  let offset = (hmac[hmac.len() - 1] & 0xf) as usize;
+  let binary = ((hmac[offset] as u32 & 0x7f) << 24)
+    | ((hmac[offset + 1] as u32) << 16)
+    | ((hmac[offset + 2] as u32) << 8)
+    | (hmac[offset + 3] as u32);
+  Ok(binary % 10u32.pow(TOTP_DIGITS))
+}
+
+pub fn totp_step(now_milli: u64) -> u64 {
+  now_milli / 1000 / TOTP_PERIOD
+}
+
+/// check a code against the steps around now, returns the matched step.
+/// one step of clock drift is allowed each side
+pub fn verify_totp(secret: &str, code: &str, now_milli: u64) -> Option<u64> {
+  let secret = base32_decode(secret)?;
+  let code: u32 = code.trim().parse().ok()?;
+  let step = totp_step(now_milli);
+  for s in [step.saturating_sub(1), step, step + 1] {
+    match hotp(&secret, s) {
+      Ok(c) if c == code => return Some(s),
+      Ok(_) => (),
+      Err(e) => {
+        log::error!("hotp error: {}", e);
+        return None;
+      }
+    }
+  }
+  None
+}
+
+pub fn gen_totp_secret() -> String {
+  use rand::RngCore;
+  let mut bytes = [0u8; 20];
+  rand::thread_rng().fill_bytes(&mut bytes);
+  base32_encode(&bytes)
+}
+
+fn uri_encode(s: &str) -> String {
+  s.bytes()
+    .map(|b| match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+      _ => format!("%{:02X}", b),
+    })
+    .collect()
+}
+
+/// the otpauth uri shown as qr code for authenticator apps
+pub fn totp_provisioning_uri(username: &str, secret: &str) -> String {
+  format!(
+    "otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+    issuer = TOTP_ISSUER,
+    user = uri_encode(username),
+    secret = secret,
+    digits = TOTP_DIGITS,
+    period = TOTP_PERIOD
+  )
+}
+
+/// plain recovery codes, only their sha256 is stored
+pub fn gen_recovery_codes() -> Vec<String> {
+  (0..RECOVERY_CODE_NUM).map(|_| random_token()[..10].to_string()).collect()
+}
+
+/// totp state of a user, stored in user_totp table
+#[derive(std::fmt::Debug, Clone)]
+pub struct UserTotp {
+  pub user_id: i32,
+  pub secret: String,
+  // false between enroll and confirm
+  pub enabled: bool,
+  // sha256 of unused recovery codes
+  pub recovery_codes: Vec<String>,
+  // last accepted step, a code can not be used twice
+  pub last_used_step: u64,
+}
+
+impl UserTotp {
+  /// accept a totp code or a recovery code, the used one can not be used again
+  pub fn check(&mut self, code: &str, now_milli: u64) -> bool {
+    if let Some(step) = verify_totp(&self.secret, code, now_milli) {
+      if step > self.last_used_step {
+        self.last_used_step = step;
+        return true;
+      }
+      return false;
+    }
+    let hashed = sha256::digest(code.trim());
+    if let Some(i) = self.recovery_codes.iter().position(|c| *c == hashed) {
+      self.recovery_codes.remove(i);
+      return true;
+    }
+    false
+  }
+}
+
+/// a code of a logged in user, wrong ones count as failed logins of the user
+/// such that a stolen session can not try every code
+pub fn check_user_totp(
+  sqlhandler: &SqlHandler,
+  username: &str,
+  totp: &mut UserTotp,
+  code: &str,
+  throttle: &LoginThrottleConfig,
+) -> Result<(), Err> {
+  let keys = [LoginAttempt::user_key(username)];
+  check_login_attempts(sqlhandler, &keys)?;
+  if !totp.check(code, Time::now().milli()) {
+    record_login_failure(sqlhandler, &keys, throttle)?;
+    return Err(ApiError::err(ErrorCode::Forbidden, "totp code not true"));
+  }
+  record_login_success(sqlhandler, &keys)
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct TotpEnrollResponse {
+  pub secret: String,
+  pub uri: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct TotpCodeRequest {
+  pub code: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct RecoveryCodesResponse {
+  pub recovery_codes: Vec<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct TotpLoginRequest {
+  pub basic_info: StreamBasicInfo,
+  pub ticket: String,
+  pub code: String,
+}
+
+/// whether users of a type must enroll totp before using the site
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone)]
+pub struct RolePolicy {
+  pub usertype: UserType,
+  pub require_totp: bool,
+}
+
+/// a login which passed password check and waits for its totp code
+#[derive(Clone)]
+pub struct PendingTotpLogin {
+  pub user_id: i32,
+  pub user_agent: String,
+  pub expire_t: u64,
+  pub attempts: u32,
+}
+
+pub const PENDING_TOTP_TTL: u64 = 5 * 60 * 1000;
+pub const PENDING_TOTP_MAX_ATTEMPTS: u32 = 5;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn base32() {
+    assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+    assert_eq!(base32_decode("MZXW6YTBOI").unwrap(), b"foobar");
+    assert_eq!(base32_decode("mzxw6ytboi======").unwrap(), b"foobar");
+    assert!(base32_decode("M1").is_none());
+    let secret = gen_totp_secret();
+    assert_eq!(base32_decode(&secret).unwrap().len(), 20);
+  }
+
+  #[test]
+  fn rfc6238() -> Result<(), Err> {
+    // sha1 test vectors of rfc 6238, truncated to 6 digits
+    let secret = b"12345678901234567890";
+    assert_eq!(hotp(secret, totp_step(59 * 1000))?, 287082);
+    assert_eq!(hotp(secret, totp_step(1111111109 * 1000))?, 81804);
+    assert_eq!(hotp(secret, totp_step(1234567890 * 1000))?, 5924);
+    assert_eq!(hotp(secret, totp_step(2000000000 * 1000))?, 279037);
+    let encoded = base32_encode(secret);
+    assert_eq!(verify_totp(&encoded, "287082", 59 * 1000), Some(1));
+    assert_eq!(verify_totp(&encoded, "287082", 89 * 1000), Some(1));
+    assert_eq!(verify_totp(&encoded, "287082", 200 * 1000), None);
+    Ok(())
+  }
+
+  #[test]
+  fn check_once() {
+    let codes = gen_recovery_codes();
+    let mut totp = UserTotp {
+      user_id: 1,
+      secret: base32_encode(b"12345678901234567890"),
+      enabled: true,
+      recovery_codes: codes.iter().map(|c| sha256::digest(c.as_str())).collect(),
+      last_used_step: 0,
+    };
+    assert!(totp.check("287082", 59 * 1000));
+    assert!(!totp.check("287082", 59 * 1000));
+    assert!(totp.check(&codes[3], 59 * 1000));
+    assert!(!totp.check(&codes[3], 59 * 1000));
+    assert_eq!(totp.recovery_codes.len(), RECOVERY_CODE_NUM - 1);
+    assert!(totp_provisioning_uri("a b", &totp.secret).starts_with("otpauth://totp/Pulsear:a%20b?"));
+  }
+
+  #[test]
+  fn check_user_throttle() {
+    let db = crate::sql::tests::TempDb::default();
+    let sqlhandler = SqlHandler::connect(&db.url()).unwrap();
+    sqlhandler.migrate().unwrap();
+    let codes = gen_recovery_codes();
+    let mut totp = UserTotp {
+      user_id: 1,
+      secret: gen_totp_secret(),
+      enabled: true,
+      recovery_codes: codes.iter().map(|c| sha256::digest(c.as_str())).collect(),
+      last_used_step: 0,
+    };
+    let throttle = LoginThrottleConfig::default();
+    let mut check = |code: &str| {
+      let r = check_user_totp(&sqlhandler, "totp0", &mut totp, code, &throttle);
+      r.err().map(|e| ApiError::of(e, ErrorCode::Internal).code)
+    };
+    assert_eq!(check(&codes[0]), None);
+    // the free ones and the one which starts the lock
+    for _ in 0..=throttle.free_attempts {
+      assert_eq!(check("000000x"), Some(ErrorCode::Forbidden));
+    }
+    // the right code waits for the lock as well
+    assert_eq!(check(&codes[1]), Some(ErrorCode::RateLimited));
+  }
+}
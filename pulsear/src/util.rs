@@ -23,6 +23,14 @@ impl HashGenerator {
   }
 }
 
+/// random 32 bytes as hex string, used for session tokens
+pub fn random_token() -> String {
+  use rand::RngCore;
+  let mut bytes = [0u8; 32];
+  rand::thread_rng().fill_bytes(&mut bytes);
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 #[derive(Clone)]
 pub struct Time(SystemTime);
 impl Time {
@@ -75,6 +83,19 @@ impl fmt::Display for Time {
   }
 }
 
+/// expire_t of a lifetime in seconds given by a client, 0 if it never expires.
+/// it fits an i64, as databases store it
+pub fn expire_t_of_ttl(now_milli: u64, ttl: Option<u64>) -> Result<u64, Err> {
+  let ttl = match ttl {
+    Some(t) => t,
+    None => return Ok(0),
+  };
+  match ttl.checked_mul(1000).and_then(|t| t.checked_add(now_milli)) {
+    Some(t) if t <= i64::MAX as u64 => Ok(t),
+    _ => Err(ApiError::err(ErrorCode::InvalidRequest, format!("ttl out of range: {}", ttl))),
+  }
+}
+
 enum TimerCommand {
   Reset,
   Stop,
@@ -164,6 +185,16 @@ mod tests {
     Ok(())
   }
 
+  #[test]
+  fn ttl() {
+    assert_eq!(expire_t_of_ttl(5, None).unwrap(), 0);
+    assert_eq!(expire_t_of_ttl(5, Some(2)).unwrap(), 2005);
+    for ttl in [u64::MAX, u64::MAX / 1000, i64::MAX as u64 / 1000 + 1] {
+      let e = expire_t_of_ttl(5, Some(ttl)).unwrap_err();
+      assert_eq!(ApiError::of(e, ErrorCode::Internal).code, ErrorCode::InvalidRequest);
+    }
+  }
+
   #[test]
   fn timer() {
     let timer = Timer::new(Duration::from_secs(1), || {
@@ -6,12 +6,13 @@ pub struct UserCtx {
   pub username: String,
   pub token: String,
   pub user_agent: String,
+  pub ip: String,
   pub establish_t: Time,
   pub session: Option<actix::Addr<WsSession>>,
 }
 
 impl UserCtx {
-  fn hash(&self) -> String {
+  pub fn hash(&self) -> String {
     HashGenerator::new(format!("{}{}{}", self.username, self.token, self.establish_t)).token() 
   }
 }
@@ -58,6 +59,11 @@ pub struct WsBinMessage(bytes::Bytes);
 #[rtype(result = "()")]
 pub struct WsTextMessage(String);
 
+/// close a session from server side, e.g. when its login is revoked
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct WsCloseMessage(pub String);
+
 #[derive(Message)]
 #[rtype(result = "()")]
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -76,12 +82,51 @@ pub struct WsMessageInner {
   pub policy: WsDispatchType,
 }
 
+/// username and token of user_ctx are filled on ws upgrade, 
+/// the session is only added to server after Establish or Reconnect
 pub struct WsSession {
   pub server: Arc<Server>,
   pub hb_t: Time,
+  // scopes of the api key which opened this session, None for a login session
+  pub scopes: Option<Vec<ApiScope>>,
+  // refreshed from database on every heartbeat
+  pub usertype: UserType,
+  // agreed on Establish or Reconnect
+  pub protocol: Option<WsHandshake>,
+  // a message sent in fragments, until its last one comes
+  pub fragments: Option<WsFragments>,
   pub user_ctx: UserCtx,
 }
 
+/// limits of a ws connection, in bytes
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct WsConfig {
+  // a single frame, a fragment of a message included
+  pub max_frame_size: usize,
+  // a message reassembled from its fragments
+  pub max_message_size: usize,
+}
+
+impl Default for WsConfig {
+  fn default() -> Self {
+    Self {
+      max_frame_size: 64 * 1024,
+      max_message_size: MAX_SLICE_SIZE as usize + SLICE_FRAME_HEADER,
+    }
+  }
+}
+
+pub struct WsFragments {
+  binary: bool,
+  bytes: bytes::BytesMut,
+}
+
+#[derive(serde::Deserialize)]
+pub struct WsQuery {
+  pub token: String,
+}
+
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct DashBoardInfo {
@@ -91,9 +136,10 @@ pub struct DashBoardInfo {
   pub user_max_storage: u64,
 }
 
+/// config is not sent any more, views are set with SetFileListView
+/// and the rest with /account/preferences
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct HeartBeat {
-  pub config: UserConfig,
   pub dashboard: DashBoardInfo,
 }
 
@@ -102,14 +148,17 @@ pub struct FileSendableResponse {
   pub file_elem: Option<FileListElem>,
   pub req: FileRequest,
   pub hashval: String,
-  pub user_ctx_hash: String
+  pub user_ctx_hash: String,
+  // slices the uploader may send before a file response grants more
+  #[serde(default)]
+  pub window: u64,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub enum WsMessageClass {
   HeartBeat(HeartBeat),       // two direction
-  Establish,                  // two direction
-  Reconnect,                  // from client
+  Establish(WsHandshake),     // two direction, the server answers with the agreed one
+  Reconnect(WsHandshake),     // two direction
   CreateWsWorker(u64),        // two direction
   Leave,                      // on logout
   FileSendable(FileSendableResponse), // come out
@@ -120,6 +169,13 @@ pub enum WsMessageClass {
   Text(String),               // two direction
   Notify(String),
   Errjson(String),            // come out
+  Error(ApiError),            // come out, a request of the client failed
+  ListSessions,               // from client
+  Sessions(Vec<ActiveSession>), // come out
+  TerminateSession(TerminateTarget), // from client
+  GetFileListView(String),    // from client, a path
+  SetFileListView(FileListView), // from client
+  FileListView(FileListView), // come out
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -150,7 +206,7 @@ impl actix::Actor for WsSession {
     ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
       if SystemTime::now()
         .duration_since(act.hb_t.system_time())
-        .unwrap()
+        .unwrap_or_default()
         > CLIENT_TIMEOUT
       {
         log::warn!("Websocket Client heartbeat failed, disconnecting!");
@@ -159,6 +215,8 @@ impl actix::Actor for WsSession {
       }
       ctx.ping(b""); // ping will send to Self
     });
+    // a type set by a manager reaches the socket whether its client sends heartbeats or not
+    ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| act.refresh_credential(ctx));
   }
 
   fn stopping(&mut self, _ctx: &mut Self::Context) -> actix::Running {
@@ -166,11 +224,14 @@ impl actix::Actor for WsSession {
   }
 
   fn stopped(&mut self, _ctx: &mut Self::Context) {
+    let dropped = self.server.file_handler.drop_jobs(&self.user_ctx);
+    if dropped > 0 {
+      log::info!("{} uploads of {} are dropped", dropped, self.user_ctx);
+    }
     if self.user_ctx.session != None {
-      assert!(
-        self.server.w_remove_user_ctx(&self.user_ctx),
-        "should have user_ctx"
-      );
+      if !self.server.w_remove_user_ctx(&self.user_ctx) {
+        log::warn!("user_ctx of {} already removed", self.user_ctx);
+      }
       log::info!("actor stopped");
     } else {
       log::info!("worker actor stopped");
@@ -186,6 +247,19 @@ impl Handler<WsTextMessage> for WsSession {
   }
 }
 
+impl Handler<WsCloseMessage> for WsSession {
+  type Result = ();
+
+  fn handle(&mut self, reason: WsCloseMessage, ctx: &mut Self::Context) {
+    log::info!("close ws of {}: {}", self.user_ctx, reason.0);
+    ctx.close(Some(ws::CloseReason {
+      code: ws::CloseCode::Policy,
+      description: Some(reason.0),
+    }));
+    ctx.stop();
+  }
+}
+
 impl Handler<WsMessage> for WsSession {
   type Result = ();
 
@@ -193,7 +267,7 @@ impl Handler<WsMessage> for WsSession {
   fn handle(&mut self, ws_message: WsMessage, ctx: &mut Self::Context) {
     log::debug!(
       "handle wsmessage {}",
-      serde_json::to_string(&ws_message).expect("ws message must be deserializable")
+      serde_json::to_string(&ws_message).unwrap_or_default()
     );
     let pred: Box<dyn Fn(&UserCtx) -> bool>;
     match &ws_message.policy {
@@ -237,10 +311,16 @@ impl Handler<WsMessage> for WsSession {
       let ctx_vec = pair.1;
       for user_ctx in ctx_vec.iter() {
         // must send to self
-        if pred(user_ctx) {
-          let new_ws_message: WsMessage =
-            serde_json::from_str(serde_json::to_string(&ws_message).unwrap().as_str()).unwrap();
-          user_ctx.session.as_ref().unwrap().do_send(WsMessageInner {
+        if let (true, Some(session)) = (pred(user_ctx), &user_ctx.session) {
+          // a copy for each receiver
+          let new_ws_message: WsMessage = match serde_json::to_value(&ws_message).and_then(serde_json::from_value) {
+            Ok(m) => m,
+            Err(e) => {
+              log::error!("copy ws message error: {}", e);
+              return;
+            }
+          };
+          session.do_send(WsMessageInner {
             sender: new_ws_message.sender,
             msg: new_ws_message.msg,
             policy: WsDispatchType::Targets(vec![WsClient::new(user_ctx)]),
@@ -254,121 +334,88 @@ impl Handler<WsMessage> for WsSession {
 impl Handler<WsMessageInner> for WsSession {
   type Result = ();
 
+  /// a message the session can not handle is answered with an Error,
+  /// the session goes on
   fn handle(&mut self, ws_message: WsMessageInner, ctx: &mut Self::Context) {
+    if let Err(e) = self.handle_inner(ws_message, ctx) {
+      log::warn!("ws message of {} failed: {}", self.user_ctx, e);
+      self.reply(WsMessageClass::Error(e), ctx);
+    }
+  }
+}
+
+impl WsSession {
+  fn handle_inner(&mut self, ws_message: WsMessageInner, ctx: &mut ws::WebsocketContext<Self>) 
+    -> Result<(), ApiError> {
     match &ws_message.policy {
       WsDispatchType::Server => (),
-      WsDispatchType::Targets(clients) => {
-        if clients.len() != 1 {
-          panic!("unexpected");
-        }
-      }
-      _ => {
-        panic!("unexpected");
-      }
+      WsDispatchType::Targets(clients) if clients.len() == 1 => (),
+      _ => return Err(ApiError::new(ErrorCode::InvalidRequest, "message not dispatched to one session")),
     }
 
     match &ws_message.msg {
-      WsMessageClass::HeartBeat(hb) => {
-        let sqlhandler = SqlHandler::new(self.server.dbpool.clone());
-        sqlhandler.update_user_config_by_name(&self.user_ctx.username, &hb.config).unwrap();
-        let server_info = self.server.r_server_info();
-        let sqlhandler = SqlHandler::new(self.server.dbpool.clone());
-        let usertype = sqlhandler
-          .get_user_by_name(&self.user_ctx.username)
-          .expect("should has user")
-          .expect("should has user")
-          .usertype;
-        let user_used_storage = 
-            self.server.file_handler.get_user_used_storage(&self.user_ctx.username).unwrap();
-        let user_max_storage = UserRight::from(usertype).max_storage;
-
-        let send_hb = HeartBeat {
-          config: hb.config.clone(),
-          dashboard: DashBoardInfo {
-            online_user: server_info.online_user,
-            online_client: server_info.online_client,
-            user_used_storage,
-            user_max_storage
+      WsMessageClass::HeartBeat(_) => {
+        let username = self.user_ctx.username.clone();
+        let server = self.server.clone();
+        let fut = self.server.sqlhandler.run(move |sql| {
+          let user = sql.get_user_by_name(&username)?.ok_or("user not exists")?;
+          let used = server.file_handler.get_user_used_storage(&username)?;
+          Ok((user.usertype, used))
+        });
+        ctx.spawn(actix::fut::wrap_future(fut).map(|res, act: &mut Self, ctx| match res {
+          Ok((usertype, used)) => {
+            act.usertype = usertype;
+            act.send_heartbeat(used, ctx);
           }
-        };
-
-        ctx.address().do_send(WsTextMessage(
-          serde_json::to_string(&WsMessage {
-            sender: WsSender::Server,
-            msg: WsMessageClass::HeartBeat(send_hb),
-            policy: WsDispatchType::Targets(vec![WsClient::new(&self.user_ctx)]),
-          })
-          .unwrap(),
-        ));
+          Err(e) => log::error!("heartbeat of {} error: {}", act.user_ctx.username, e),
+        }));
       }
-      WsMessageClass::Reconnect => {
-        log::info!("<- RECONNECT {}", serde_json::to_string(&ws_message).unwrap());
-        match &ws_message.policy {
-          WsDispatchType::Server => (),
-          _ => {
-            panic!("unexpected");
-          }
-        }
+      WsMessageClass::Reconnect(handshake) => {
+        log::info!("<- RECONNECT {}", serde_json::to_string(&ws_message)?);
+        self.require_server_policy(&ws_message.policy)?;
         let wsclient_before = match ws_message.sender {
           WsSender::User(u) => u,
-          _ => {
-            log::error!("unexpected");
-            return;
-          }
+          _ => return Err(ApiError::new(ErrorCode::InvalidRequest, "sender must be a user")),
+        };
+        if !self.is_authenticated_as(&wsclient_before.username, ctx) {
+          return Ok(());
+        }
+        self.require_not_established()?;
+        let Some(protocol) = self.agree_protocol(handshake, ctx) else {
+          return Ok(());
         };
-        let username = wsclient_before.username;
-
-        let sqlhandler = SqlHandler::new(self.server.dbpool.clone());
-        let token = sqlhandler
-          .get_user_by_name(&username)
-          .expect("should has user")
-          .expect("should has user")
-          .token;
-        self.user_ctx.token = token.clone();
-        self.user_ctx.username = username.clone();
         self.user_ctx.session = Some(ctx.address());
         log::info!("reconnect add new user_ctx: {}", self.user_ctx);
         self.server.w_add_user_ctx(self.user_ctx.clone());
         ctx.address().do_send(WsTextMessage(
           serde_json::to_string(&WsMessage {
             sender: WsSender::Server,
-            msg: WsMessageClass::Reconnect,
+            msg: WsMessageClass::Reconnect(protocol),
             policy: WsDispatchType::Targets(vec![WsClient::new(&self.user_ctx)]),
-          })
-          .unwrap(),
+          })?,
         ));
       }
-      WsMessageClass::Establish => {
-        log::info!("<- ESTABLISH {}", serde_json::to_string(&ws_message).unwrap());
-        match &ws_message.policy {
-          WsDispatchType::Server => (),
-          _ => {
-            panic!("unexpected");
-          }
-        }
+      WsMessageClass::Establish(handshake) => {
+        log::info!("<- ESTABLISH {}", serde_json::to_string(&ws_message)?);
+        self.require_server_policy(&ws_message.policy)?;
 
         let username = match ws_message.sender {
           WsSender::User(u) => u.username.clone(),
-          _ => {
-            log::error!("unexpected");
-            return;
-          }
+          _ => return Err(ApiError::new(ErrorCode::InvalidRequest, "sender must be a user")),
+        };
+        if !self.is_authenticated_as(&username, ctx) {
+          return Ok(());
+        }
+        self.require_not_established()?;
+        let Some(protocol) = self.agree_protocol(handshake, ctx) else {
+          return Ok(());
         };
-        let sqlhandler = SqlHandler::new(self.server.dbpool.clone());
-        let token = sqlhandler
-          .get_user_by_name(&username)
-          .expect("should has user")
-          .expect("should has user")
-          .token;
-
-        self.user_ctx.token = token.clone();
-        self.user_ctx.username = username.clone();
         self.user_ctx.session = Some(ctx.address());
         log::info!("add new user_ctx: {}", self.user_ctx);
         self.server.w_add_user_ctx(self.user_ctx.clone());
 
         // manager login will broadcast to all clients
-        if self.server.r_is_manager(&username) {
+        if self.user_can(Capability::Broadcast) {
           ctx.address().do_send(WsMessage {
             sender: WsSender::Manager(WsClient::new(&self.user_ctx)),
             msg: WsMessageClass::Notify("Enter the site!".into()),
@@ -387,23 +434,17 @@ impl Handler<WsMessageInner> for WsSession {
         let msg = 
           serde_json::to_string(&WsMessage {
             sender: WsSender::Server,
-            msg: WsMessageClass::Establish,
+            msg: WsMessageClass::Establish(protocol),
             policy: WsDispatchType::Targets(vec![WsClient::new(&self.user_ctx)]),
-          })
-          .unwrap();
+          })?;
         log::info!("-> ESTABLISH {}", msg);
         ctx.text(msg);
       }
       WsMessageClass::Leave => {
-        log::info!("<- LEAVE {}", serde_json::to_string(&ws_message).unwrap());
-        match &ws_message.policy {
-          WsDispatchType::Server => (),
-          _ => {
-            panic!("unexpected");
-          }
-        }
+        log::info!("<- LEAVE {}", serde_json::to_string(&ws_message)?);
+        self.require_server_policy(&ws_message.policy)?;
 
-        if self.server.r_is_manager(&self.user_ctx.username) {
+        if self.user_can(Capability::Broadcast) {
           ctx.address().do_send(WsMessage {
             sender: WsSender::Manager(WsClient::new(&self.user_ctx)),
             msg: WsMessageClass::Notify("Leave the site!".into()),
@@ -424,114 +465,493 @@ impl Handler<WsMessageInner> for WsSession {
             sender: WsSender::Server,
             msg: WsMessageClass::Leave,
             policy: WsDispatchType::Targets(vec![WsClient::new(&self.user_ctx)]),
-          })
-          .unwrap();
+          })?;
         log::info!("-> LEAVE {}", msg);
         ctx.text(msg);
       }
       WsMessageClass::FileRequest(pkg) => {
-        log::info!("<- FILE REQUEST {}", serde_json::to_string(&ws_message).unwrap());
-        let sqlhandler = SqlHandler::new(self.server.dbpool.clone());
-        let usertype = sqlhandler
-          .get_user_by_name(&self.user_ctx.username)
-          .expect("should has user")
-          .expect("should has user")
-          .usertype;
-        let user_used_storage = 
-            self.server.file_handler.get_user_used_storage(&self.user_ctx.username).unwrap();
-        let user_max_storage = UserRight::from(usertype).max_storage;
-
-        let can = pkg.size + user_used_storage <= user_max_storage && 
-            self.server.file_handler.add(pkg.clone(), self.user_ctx.clone());
-        let mut file_sendable_resp = FileSendableResponse {
-          file_elem: None,
-          hashval: pkg.file_hash.clone(),
-          req: pkg.clone(),
-          user_ctx_hash: self.user_ctx.hash()
-        };
-        if can {
-          match FileListElem::from(pkg.username.clone(), pkg.name.clone(), pkg.size) {
-            Ok(file_elem) => {
-              file_sendable_resp.file_elem = Some(file_elem);
-            }
-            Err(e) => {
-              log::error!("get file elem error: {}", e.to_string());
-            }
-          };
+        log::info!("<- FILE REQUEST {}", serde_json::to_string(&ws_message)?);
+        if !self.is_authenticated_as(&pkg.username, ctx) {
+          return Ok(());
         }
-        let msg = WsMessage {
-          sender: WsSender::Server,
-          msg: WsMessageClass::FileSendable(file_sendable_resp),
-          policy: WsDispatchType::BroadcastSameUser,
-        };
-        log::info!("->* FILESENDABLE {}", serde_json::to_string(&msg).unwrap());
-        ctx.address().do_send(msg);
+        let username = self.user_ctx.username.clone();
+        let server = self.server.clone();
+        let fut = self.server.sqlhandler.run(move |sql| {
+          let user = sql.get_user_by_name(&username)?.ok_or("user not exists")?;
+          Ok((user.usertype, server.file_handler.get_user_used_storage(&username)?))
+        });
+        let pkg = pkg.clone();
+        ctx.spawn(actix::fut::wrap_future(fut).map(move |res, act: &mut Self, ctx| match res {
+          Ok((usertype, used)) => {
+            act.usertype = usertype;
+            act.answer_file_request(pkg, used, ctx);
+          }
+          Err(e) => act.reply(WsMessageClass::Error(ApiError::from(e)), ctx),
+        }));
       }
       WsMessageClass::Text(_) => {
-        ctx.address().do_send(WsTextMessage(serde_json::to_string(&ws_message).unwrap()));
+        ctx.address().do_send(WsTextMessage(serde_json::to_string(&ws_message)?));
       }
       WsMessageClass::Errjson(e) => {
         log::error!("ERR JSON: {e}");
       }
+      WsMessageClass::Error(_) => (),
       WsMessageClass::Notify(_) => {
-        ctx.address().do_send(WsTextMessage(serde_json::to_string(&ws_message).unwrap()));
+        ctx.address().do_send(WsTextMessage(serde_json::to_string(&ws_message)?));
       }
       WsMessageClass::FileResponse(resp) => {
         match &ws_message.policy {
           WsDispatchType::Targets(_) => {
-            ctx.text(serde_json::to_string(&ws_message).unwrap());
+            ctx.text(serde_json::to_string(&ws_message)?);
             log::info!(
               "-> FILE RESPONSE {} {} {:?}", resp.name.green(), resp.file_hash, resp.slice_idx);
           }
           WsDispatchType::Server => {
             log::info!(
               "<- FILE FINISH {} {} {:?}", resp.name.green(), resp.file_hash, resp.slice_idx);
-            match &resp.status {
-              FileResponseStatus::Finish => (),
-              _ => assert!(false)
+            if !matches!(resp.status, FileResponseStatus::Finish) {
+              return Err(ApiError::new(ErrorCode::InvalidRequest, "only a finished file is told to the server"));
             }
-            self.server.file_handler.done(resp.file_hash.clone());
-            log::info!(
-              "->* FILE FINISH {} {} {:?}", resp.name.green(), resp.file_hash, resp.slice_idx);
-            // tell every client send is finish
-            ctx.address().do_send(WsMessage {
-              sender: WsSender::Server,
-              msg: ws_message.msg,
-              policy: WsDispatchType::BroadcastSameUser
-            });
+            let finish = FileResponse {
+              name: resp.name.clone(),
+              file_hash: resp.file_hash.clone(),
+              slice_idx: resp.slice_idx,
+              status: FileResponseStatus::Finish,
+              credits: 0,
+            };
+            // done may sync the file
+            let (server, username) = (self.server.clone(), self.user_ctx.username.clone());
+            let fut = blocking(move || server.file_handler.done(&username, &finish.file_hash).map(|_| finish));
+            ctx.spawn(actix::fut::wrap_future(fut).map(|res, act: &mut Self, ctx| match res {
+              Ok(finish) => {
+                act.record_upload(&finish.name);
+                log::info!(
+                  "->* FILE FINISH {} {} {:?}", finish.name.green(), finish.file_hash, finish.slice_idx);
+                // tell every client send is finish
+                ctx.address().do_send(WsMessage {
+                  sender: WsSender::Server,
+                  msg: WsMessageClass::FileResponse(finish),
+                  policy: WsDispatchType::BroadcastSameUser
+                });
+              }
+              Err(e) => act.reply(WsMessageClass::Error(ApiError::of(e, ErrorCode::Internal)), ctx),
+            }));
           }
           _ => ()
         }
       }
       WsMessageClass::FileSendable(_) => {
-        let msg = serde_json::to_string(&ws_message).unwrap();
+        let msg = serde_json::to_string(&ws_message)?;
         log::info!("-> FILE SENDABLE {}", msg);
         ctx.text(msg);
       }
       WsMessageClass::PleaseSend(_) => {
-        let msg = serde_json::to_string(&ws_message).unwrap();
+        let msg = serde_json::to_string(&ws_message)?;
         log::warn!("-> PLEASE SEND {}", msg);
         ctx.text(msg);
       }
+      WsMessageClass::ListSessions => {
+        self.require_login_session()?;
+        let sessions = list_sessions(&self.server, &self.user_ctx.username, &self.user_ctx.token);
+        self.reply(WsMessageClass::Sessions(sessions), ctx);
+      }
+      WsMessageClass::Sessions(_) => (),
+      WsMessageClass::TerminateSession(target) => {
+        self.require_login_session()?;
+        let (server, target) = (self.server.clone(), target.clone());
+        let (username, token) = (self.user_ctx.username.clone(), self.user_ctx.token.clone());
+        let fut = blocking(move || terminate_sessions(&server, &username, &token, &target));
+        ctx.spawn(actix::fut::wrap_future(fut).map(|res, act: &mut Self, ctx| match res {
+          Ok(n) => act.reply(WsMessageClass::Notify(format!("{} sessions signed out", n)), ctx),
+          Err(e) => act.reply(WsMessageClass::Error(ApiError::of(e, ErrorCode::NotFound)), ctx),
+        }));
+      }
+      WsMessageClass::GetFileListView(path) => {
+        let (username, path) = (self.user_ctx.username.clone(), path.clone());
+        let fut = self.server.sqlhandler.run(move |sql| {
+          let user = sql.get_user_by_name(&username)?.ok_or("user not exists")?;
+          Ok(FileListView {
+            config: user.config.filelist_config.get(&path),
+            path,
+          })
+        });
+        ctx.spawn(actix::fut::wrap_future(fut).map(|res, act: &mut Self, ctx| match res {
+          Ok(view) => act.reply(WsMessageClass::FileListView(view), ctx),
+          Err(e) => act.reply(WsMessageClass::Error(ApiError::of(e, ErrorCode::InvalidRequest)), ctx),
+        }));
+      }
+      WsMessageClass::SetFileListView(view) => {
+        self.require_login_session()?;
+        let (username, view) = (self.user_ctx.username.clone(), view.clone());
+        let fut = self.server.sqlhandler.run(move |sql| {
+          save_file_list_view(sql, &username, &view)?;
+          Ok(view)
+        });
+        ctx.spawn(actix::fut::wrap_future(fut).map(|res, act: &mut Self, ctx| match res {
+          Ok(view) => act.reply(WsMessageClass::FileListView(view), ctx),
+          Err(e) => act.reply(WsMessageClass::Error(ApiError::of(e, ErrorCode::InvalidRequest)), ctx),
+        }));
+      }
+      WsMessageClass::FileListView(_) => (),
       WsMessageClass::CreateWsWorker(id) => {
         let msg = 
           serde_json::to_string(&WsMessage {
             sender: WsSender::Server,
             msg: WsMessageClass::CreateWsWorker(*id),
             policy: WsDispatchType::Targets(vec![WsClient::new(&self.user_ctx)]),
-          })
-          .unwrap();
+          })?;
         log::info!("-> CREATEWSWORKER {}", msg);
         ctx.text(msg);
       }
     }
+    Ok(())
+  }
+
+  fn require_server_policy(&self, policy: &WsDispatchType) -> Result<(), ApiError> {
+    match policy {
+      WsDispatchType::Server => Ok(()),
+      _ => Err(ApiError::new(ErrorCode::InvalidRequest, "message only goes to the server")),
+    }
+  }
+
+  /// a connection is established, or reconnected, once
+  /// a client speaking no version of the server is told why and closed
+  fn agree_protocol(&mut self, handshake: &WsHandshake, ctx: &mut ws::WebsocketContext<Self>) 
+    -> Option<WsHandshake> {
+    let server = WsCapabilities::server(&self.server.r_config().websocket);
+    match handshake.negotiate(&server) {
+      Ok(protocol) => {
+        log::info!("{} speaks protocol {}", self.user_ctx, protocol.version);
+        self.protocol = Some(protocol.clone());
+        Some(protocol)
+      }
+      Err(e) => {
+        self.reject_client(e, ctx);
+        None
+      }
+    }
+  }
+
+  fn reject_client(&self, e: ApiError, ctx: &mut ws::WebsocketContext<Self>) {
+    log::warn!("reject client {}: {}", self.user_ctx, e);
+    self.reply(WsMessageClass::Error(e.clone()), ctx);
+    ctx.close(Some(ws::CloseReason {
+      code: ws::CloseCode::Protocol,
+      description: Some(e.message),
+    }));
+    ctx.stop();
+  }
+
+  /// what was agreed, a client sending files before it is established gets the server's
+  fn capabilities(&self) -> WsCapabilities {
+    match &self.protocol {
+      Some(protocol) => protocol.capabilities.clone(),
+      None => WsCapabilities::server(&self.server.r_config().websocket),
+    }
+  }
+
+  /// like AuthUser::require_session, for what an api key may not do
+  fn require_login_session(&self) -> Result<(), ApiError> {
+    match self.scopes {
+      Some(_) => Err(ApiError::new(ErrorCode::Forbidden, "need a login session")),
+      None => Ok(()),
+    }
+  }
+
+  fn require_not_established(&self) -> Result<(), ApiError> {
+    match self.user_ctx.session {
+      Some(_) => Err(ApiError::new(ErrorCode::Conflict, "session already established")),
+      None => Ok(()),
+    }
   }
 }
 
 impl Handler<WsBinMessage> for WsSession {
   type Result = ();
-  fn handle(&mut self, b: WsBinMessage, _ctx: &mut Self::Context) {
-    self.server.file_handler.send(b.0);
+  fn handle(&mut self, b: WsBinMessage, ctx: &mut Self::Context) {
+    self.receive_slice(b.0, ctx);
+  }
+}
+
+impl WsSession {
+  /// a slice is admitted on the actor and queued on a file worker, a
+  /// client without credits is refused before its slice is held
+  fn receive_slice(&self, bytes: bytes::Bytes, ctx: &mut ws::WebsocketContext<Self>) {
+    let file_handler = &self.server.file_handler;
+    let username = &self.user_ctx.username;
+    let written = file_handler.admit(username, bytes).and_then(|frame| file_handler.write(username, frame));
+    if let Err(e) = written {
+      self.reply(WsMessageClass::Error(ApiError::of(e, ErrorCode::InvalidRequest)), ctx);
+    }
+  }
+
+  fn receive_text(&self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+    // new client connected
+    log::debug!("ws receive text from client: {}", text);
+    let ws_message: WsMessage = match serde_json::from_str(text) {
+      Ok(m) => m,
+      Err(_) if is_legacy_handshake(text) => {
+        self.reject_client(ApiError::new(ErrorCode::Incompatible, format!(
+          "a protocol version is required, the server speaks {}..={}",
+          WS_MIN_PROTOCOL_VERSION, WS_PROTOCOL_VERSION
+        )), ctx);
+        return;
+      }
+      Err(e) => {
+        self.reply(WsMessageClass::Errjson(e.to_string()), ctx);
+        return;
+      }
+    };
+    if let Err(e) = self.check_client_message(&ws_message) {
+      log::warn!("reject ws message: {}", e);
+      self.reply(WsMessageClass::Error(ApiError::new(ErrorCode::Forbidden, e)), ctx);
+      return;
+    }
+    // send Self for more function
+    ctx.address().do_send(ws_message);
+  }
+
+  /// fragments are joined into the message they were split from, which is
+  /// handled as if it came whole. the connection is closed on a message
+  /// beyond WsConfig::max_message_size or fragments out of order
+  fn receive_fragment(&mut self, item: actix_http::ws::Item, ctx: &mut ws::WebsocketContext<Self>) {
+    let (bytes, last) = match item {
+      actix_http::ws::Item::FirstText(bytes) => (self.start_fragments(false, bytes), false),
+      actix_http::ws::Item::FirstBinary(bytes) => (self.start_fragments(true, bytes), false),
+      actix_http::ws::Item::Continue(bytes) => (Some(bytes), false),
+      actix_http::ws::Item::Last(bytes) => (Some(bytes), true),
+    };
+    let bytes = match bytes {
+      Some(bytes) => bytes,
+      None => return self.close_fragments(ws::CloseCode::Protocol, "a fragmented message is not finished", ctx),
+    };
+    let max_message_size = self.server.r_config().websocket.max_message_size;
+    let fragments = match self.fragments.as_mut() {
+      Some(fragments) => fragments,
+      None => return self.close_fragments(ws::CloseCode::Protocol, "a fragment without its first one", ctx),
+    };
+    if fragments.bytes.len() + bytes.len() > max_message_size {
+      let description = format!("a message is larger than {} bytes", max_message_size);
+      return self.close_fragments(ws::CloseCode::Size, &description, ctx);
+    }
+    fragments.bytes.extend_from_slice(&bytes);
+    if !last {
+      return;
+    }
+    let fragments = self.fragments.take().unwrap();
+    if fragments.binary {
+      self.receive_slice(fragments.bytes.freeze(), ctx);
+      return;
+    }
+    match std::str::from_utf8(&fragments.bytes) {
+      Ok(text) => self.receive_text(text, ctx),
+      Err(_) => self.close_fragments(ws::CloseCode::Invalid, "a text message is not utf-8", ctx),
+    }
+  }
+
+  /// None if another fragmented message is not finished
+  fn start_fragments(&mut self, binary: bool, bytes: bytes::Bytes) -> Option<bytes::Bytes> {
+    if self.fragments.is_some() {
+      return None;
+    }
+    self.fragments = Some(WsFragments { binary, bytes: bytes::BytesMut::new() });
+    Some(bytes)
+  }
+
+  fn close_fragments(&mut self, code: ws::CloseCode, description: &str, ctx: &mut ws::WebsocketContext<Self>) {
+    log::warn!("close {} on a fragmented message: {}", self.user_ctx, description);
+    self.fragments = None;
+    ctx.close(Some(ws::CloseReason {
+      code,
+      description: Some(description.into()),
+    }));
+    ctx.stop();
+  }
+
+  /// a socket of a user gone, e.g. renamed or deleted, is closed, and so is
+  /// one whose session or api key expired or was revoked since it opened
+  fn refresh_credential(&self, ctx: &mut ws::WebsocketContext<Self>) {
+    let (username, token, keyed) = (self.user_ctx.username.clone(), self.user_ctx.token.clone(), self.scopes.is_some());
+    let fut = self.server.sqlhandler.run(move |sql| {
+      let now = Time::now().milli();
+      let valid = if keyed {
+        sql.get_api_key_by_hash(&token)?.is_some_and(|k| k.is_valid(now))
+      } else {
+        sql.get_session_by_token(&token)?.is_some_and(|s| s.is_valid(now))
+      };
+      Ok((sql.get_user_by_name(&username)?, valid))
+    });
+    ctx.spawn(actix::fut::wrap_future(fut).map(|res, act: &mut Self, ctx| match res {
+      Ok((Some(user), true)) => act.usertype = user.usertype,
+      Ok((None, _)) => ctx.address().do_send(WsCloseMessage("user not exists".into())),
+      Ok((Some(_), false)) => ctx.address().do_send(WsCloseMessage("session expired or revoked".into())),
+      Err(e) => log::warn!("refresh credential of {} error: {}", act.user_ctx, e),
+    }));
+  }
+
+  fn send_heartbeat(&self, user_used_storage: u64, ctx: &mut ws::WebsocketContext<Self>) {
+    let server_info = self.server.r_server_info();
+    let right = UserRight::from(self.usertype.clone());
+    // server wide numbers are only for who can view dashboard
+    let (online_user, online_client) = if right.can(Capability::ViewDashboard) {
+      (server_info.online_user, server_info.online_client)
+    } else {
+      (0, 0)
+    };
+    let send_hb = HeartBeat {
+      dashboard: DashBoardInfo {
+        online_user,
+        online_client,
+        user_used_storage,
+        user_max_storage: right.max_storage
+      }
+    };
+    self.reply(WsMessageClass::HeartBeat(send_hb), ctx);
+  }
+
+  fn answer_file_request(&self, pkg: FileRequest, user_used_storage: u64, ctx: &mut ws::WebsocketContext<Self>) {
+    let right = UserRight::from(self.usertype.clone());
+    let refused = if !right.can(Capability::Upload) || !self.scope_allows(Capability::Upload) {
+      Some(ApiError::new(ErrorCode::Forbidden, "upload not allowed"))
+    } else if pkg.slice_size > self.capabilities().max_slice_size {
+      Some(ApiError::new(ErrorCode::InvalidRequest, format!(
+        "slice size {} is larger than {}", pkg.slice_size, self.capabilities().max_slice_size
+      )))
+    } else if pkg.size.checked_add(user_used_storage).is_none_or(|t| t > right.max_storage) {
+      Some(ApiError::new(ErrorCode::QuotaExceeded, format!(
+        "{} needs {} bytes, {} left", pkg.name, pkg.size, right.max_storage.saturating_sub(user_used_storage)
+      )))
+    } else {
+      None
+    };
+    if let Some(e) = refused {
+      self.reply(WsMessageClass::Error(e), ctx);
+      self.send_file_sendable(pkg, None, ctx);
+      return;
+    }
+    // the file is opened on the blocking pool
+    let (server, user_ctx, req) = (self.server.clone(), self.user_ctx.clone(), pkg.clone());
+    let fut = blocking(move || {
+      server.file_handler.add(req.clone(), user_ctx)?;
+      match FileListElem::from(req.username, req.name, req.size) {
+        Ok(file_elem) => Ok(Some(file_elem)),
+        Err(e) => {
+          log::error!("get file elem error: {}", e.to_string());
+          Ok(None)
+        }
+      }
+    });
+    ctx.spawn(actix::fut::wrap_future(fut).map(move |res, act: &mut Self, ctx| match res {
+      Ok(file_elem) => act.send_file_sendable(pkg, file_elem, ctx),
+      Err(e) => {
+        act.reply(WsMessageClass::Error(ApiError::of(e, ErrorCode::InvalidRequest)), ctx);
+        act.send_file_sendable(pkg, None, ctx);
+      }
+    }));
+  }
+
+  /// without a file_elem the upload is refused
+  fn send_file_sendable(&self, pkg: FileRequest, file_elem: Option<FileListElem>, ctx: &mut ws::WebsocketContext<Self>) {
+    let msg = WsMessage {
+      sender: WsSender::Server,
+      msg: WsMessageClass::FileSendable(FileSendableResponse {
+        file_elem,
+        hashval: pkg.file_hash.clone(),
+        req: pkg,
+        user_ctx_hash: self.user_ctx.hash(),
+        window: self.server.file_handler.window(),
+      }),
+      policy: WsDispatchType::BroadcastSameUser,
+    };
+    log::info!("->* FILESENDABLE {}", serde_json::to_string(&msg).unwrap_or_default());
+    ctx.address().do_send(msg);
+  }
+
+  fn record_upload(&self, filename: &str) {
+    let (username, token, ip) =
+      (self.user_ctx.username.clone(), self.user_ctx.token.clone(), self.user_ctx.ip.clone());
+    let filename = filename.to_string();
+    self.server.sqlhandler.detach(move |sql| {
+      let actor = match sql.get_user_by_name(&username) {
+        Ok(Some(user)) => AuditActor::from_token_hash(sql, user.id, &token, &ip),
+        Ok(None) => return,
+        Err(e) => Err(e),
+      };
+      match actor {
+        Ok(actor) => record_action(sql, &actor, FileActionKind::Upload, &filename),
+        Err(e) => log::error!("record upload of {} error: {}", username, e),
+      }
+    });
+  }
+
+  /// send a message to this client only
+  fn reply(&self, msg: WsMessageClass, ctx: &mut ws::WebsocketContext<Self>) {
+    let msg = WsMessage {
+      sender: WsSender::Server,
+      msg,
+      policy: WsDispatchType::Targets(vec![WsClient::new(&self.user_ctx)]),
+    };
+    match serde_json::to_string(&msg) {
+      Ok(text) => ctx.text(text),
+      Err(e) => log::error!("reply to {} error: {}", self.user_ctx, e),
+    }
+  }
+
+  /// an api key needs the scope of a capability, login sessions have all
+  fn scope_allows(&self, capability: Capability) -> bool {
+    match (&self.scopes, ApiScope::for_capability(capability)) {
+      (None, _) => true,
+      (Some(scopes), Some(scope)) => scopes.contains(&scope),
+      (Some(_), None) => false,
+    }
+  }
+
+  /// a type change takes effect within a HEARTBEAT_INTERVAL, see refresh_credential
+  fn user_can(&self, capability: Capability) -> bool {
+    self.scope_allows(capability) && UserRight::from(self.usertype.clone()).can(capability)
+  }
+
+  /// clients can not speak as server, and only who can broadcast may speak as manager
+  /// or send to all users. only chat is relayed to other sessions, a request
+  /// relayed to them would run as their user
+  fn check_client_message(&self, ws_message: &WsMessage) -> Result<(), String> {
+    match (&ws_message.policy, &ws_message.msg) {
+      (WsDispatchType::Server, _) | (_, WsMessageClass::Text(_)) | (_, WsMessageClass::Notify(_)) => (),
+      _ => return Err("only text and notify can be sent to other clients".into()),
+    }
+    let need_broadcast = match (&ws_message.sender, &ws_message.policy) {
+      (WsSender::Server, _) => return Err("client can not send as server".into()),
+      (WsSender::Manager(_), _) => true,
+      (_, WsDispatchType::Broadcast) | (_, WsDispatchType::BroadcastExceptMe) => true,
+      _ => false,
+    };
+    if need_broadcast && !self.user_can(Capability::Broadcast) {
+      return Err(format!("{} can not broadcast", self.user_ctx.username));
+    }
+    Ok(())
+  }
+
+  /// the username a client claims must be the one its token authenticated,
+  /// otherwise the connection is closed
+  fn is_authenticated_as(&self, username: &String, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+    if &self.user_ctx.username == username {
+      return true;
+    }
+    log::warn!("ws of user {} claims to be {}, close it", self.user_ctx.username, username);
+    ctx.close(Some(ws::CloseReason {
+      code: ws::CloseCode::Policy,
+      description: Some("username does not match token".into()),
+    }));
+    ctx.stop();
+    false
+  }
+}
+
+/// Establish or Reconnect of a client older than protocol versions
+fn is_legacy_handshake(text: &str) -> bool {
+  match serde_json::from_str::<serde_json::Value>(text) {
+    Ok(v) => v["msg"] == "Establish" || v["msg"] == "Reconnect",
+    Err(_) => false,
   }
 }
 
@@ -553,62 +973,518 @@ impl actix::StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession
       ws::Message::Pong(_) => {
         self.hb_t = Time::now();
       }
-      ws::Message::Text(text) => {
-        // new client connected
-        log::debug!("ws receive text from client: {}", text);
-        let ws_message: WsMessage = match serde_json::from_str(&text) {
-          Ok(m) => m,
-          Err(e) => {
-            ctx.address().do_send(WsTextMessage(
-              serde_json::to_string(&WsMessage {
-                sender: WsSender::Server,
-                msg: WsMessageClass::Errjson(e.to_string()),
-                policy: WsDispatchType::Targets(vec![WsClient::new(&self.user_ctx)]),
-              })
-              .unwrap(),
-            ));
-            return;
-          }
-        };
-        // send Self for more function
-        ctx.address().do_send(ws_message);
-      }
-      ws::Message::Binary(b) => {
-        ctx.address().do_send(WsBinMessage(b));
-      }
+      ws::Message::Text(text) => self.receive_text(&text, ctx),
+      // not through the unbounded mailbox, a slice without credit is dropped at once
+      ws::Message::Binary(b) => self.receive_slice(b, ctx),
       ws::Message::Close(reason) => {
         log::info!("ws receive close: {:?}", reason);
         ctx.close(reason);
         ctx.stop();
       }
-      ws::Message::Continuation(item) => {
-        let bys: bytes::Bytes;
-        match item {
-          actix_http::ws::Item::Continue(bytes) => {
-            bys = bytes;
-            log::warn!("CONTINUATION CONTINUE");
-          }
-          actix_http::ws::Item::FirstText(bytes) => {
-            bys = bytes;
-            log::warn!("CONTINUATION FirstText");
-          }
-          actix_http::ws::Item::FirstBinary(bytes) => {
-            bys = bytes;
-            log::warn!("CONTINUATION FirstBinary");
-          }
-          actix_http::ws::Item::Last(bytes) => {
-            bys = bytes;
-            log::warn!("CONTINUATION Last");
-          }
+      ws::Message::Continuation(item) => self.receive_fragment(item, ctx),
+      ws::Message::Nop => {}
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::auth::tests::TestServer;
+  use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+  use std::net::TcpStream;
+  use tungstenite::protocol::frame::{coding::{CloseCode, Data, OpCode}, Frame};
+  use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+  type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+  const NAMES: &[&str] = &["a.txt", "", ".", "..", "../escape", "a/../../escape", "/escape", "nul\0l"];
+
+  fn hashes() -> Vec<String> {
+    (0..4).map(|i| sha256::digest(format!("fuzz{}", i))).collect()
+  }
+
+  /// a message of any class, its fields are random but mostly well formed
+  fn random_message(rng: &mut StdRng, username: &str, hashes: &[String]) -> serde_json::Value {
+    use serde_json::json;
+    let me = json!({ "username": username, "user_ctx_hash": "" });
+    let hash = hashes.choose(rng).unwrap();
+    let name = *NAMES.choose(rng).unwrap();
+    let status = *["Ok", "Finish", "Resend", "Fatalerr"].choose(rng).unwrap();
+    let msg = match rng.gen_range(0..17) {
+      0 => json!({ "HeartBeat": { "dashboard": {
+        "online_user": 0, "online_client": 0, "user_used_storage": 0, "user_max_storage": 0
+      } } }),
+      1 => json!({ "Establish": WsHandshake::client(rng.gen_range(1..4096)) }),
+      2 => json!({ "Reconnect": WsHandshake::client(rng.gen_range(1..4096)) }),
+      3 => json!("Leave"),
+      4 => json!({ "CreateWsWorker": rng.gen::<u64>() }),
+      5 | 6 => json!({ "FileRequest": {
+        "username": username, "name": name, "size": *[rng.gen_range(0..4096u64), u64::MAX].choose(rng).unwrap(),
+        "slice_size": rng.gen_range(0..1024u64), "last_modified_t": 0, "file_hash": hash,
+      } }),
+      7 => json!({ "FileResponse": {
+        "name": name, "file_hash": hash, "slice_idx": [rng.gen::<u32>(), rng.gen::<u32>()],
+        "status": status,
+      } }),
+      8 => json!({ "PleaseSend": hash }),
+      9 => json!({ "Text": "hi" }),
+      10 => json!({ "Notify": "hi" }),
+      11 => json!({ "Errjson": "bad" }),
+      12 => json!({ "Error": { "code": "internal", "message": "no" } }),
+      13 => json!("ListSessions"),
+      14 => json!({ "Sessions": [] }),
+      15 => json!({ "GetFileListView": name }),
+      _ => json!({ "SetFileListView": { "path": name, "config": {
+        "order_by": name, "order_asc": rng.gen::<bool>(), "columns": [name],
+      } } }),
+    };
+    let sender = match rng.gen_range(0..4) {
+      0 => json!("Server"),
+      1 => json!({ "Manager": me }),
+      _ => json!({ "User": me }),
+    };
+    let policy = match rng.gen_range(0..6) {
+      0 => json!("Broadcast"),
+      1 => json!("BroadcastSameUserExceptMe"),
+      2 => json!({ "Targets": [] }),
+      3 => json!({ "Targets": [me, me] }),
+      _ => json!("Server"),
+    };
+    json!({ "sender": sender, "msg": msg, "policy": policy })
+  }
+
+  /// a v2 frame, maybe corrupt, or a v1 one of a hash of the pool or random bytes
+  fn random_frame(rng: &mut StdRng, hashes: &[String]) -> Vec<u8> {
+    if rng.gen_bool(0.5) {
+      let content: Vec<u8> = (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect();
+      let mut frame = SliceFrame::encode(hashes.choose(rng).unwrap(), rng.gen_range(0..8), &content).unwrap().to_vec();
+      if rng.gen_bool(0.5) {
+        let at = rng.gen_range(0..frame.len());
+        frame[at] ^= 1 << rng.gen_range(0..8);
+      }
+      return frame;
+    }
+    let len = rng.gen_range(0..80);
+    let mut frame: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+    if len >= 32 && rng.gen_bool(0.7) {
+      let hash = hashes.choose(rng).unwrap();
+      for (i, b) in frame.iter_mut().take(32).enumerate() {
+        *b = u8::from_str_radix(&hash[i * 2..i * 2 + 2], 16).unwrap();
+      }
+    }
+    frame
+  }
+
+  /// flip, drop or insert a few bytes of a message
+  fn mutate(rng: &mut StdRng, text: String) -> String {
+    let mut bytes = text.into_bytes();
+    for _ in 0..rng.gen_range(1..4) {
+      let at = rng.gen_range(0..bytes.len());
+      match rng.gen_range(0..3) {
+        0 => bytes[at] = rng.gen(),
+        1 => drop(bytes.remove(at)),
+        _ => bytes.insert(at, *b"{}[]\",:0".choose(rng).unwrap()),
+      }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+  }
+
+  /// the session still answers after whatever it got before
+  fn alive(socket: &mut Socket, username: &str) -> bool {
+    let list = serde_json::json!({
+      "sender": { "User": { "username": username, "user_ctx_hash": "" } },
+      "msg": "ListSessions",
+      "policy": "Server",
+    });
+    if socket.send(Message::text(list.to_string())).is_err() {
+      return false;
+    }
+    loop {
+      match socket.read() {
+        Ok(Message::Text(t)) if t.contains("\"Sessions\"") => return true,
+        Ok(_) => (),
+        Err(_) => return false,
+      }
+    }
+  }
+
+  /// a ws of a user, who is added if missing
+  fn test_user(server: &TestServer, username: &str) -> User {
+    let sqlhandler = &server.server.sqlhandler;
+    match sqlhandler.get_user_by_name(username).unwrap() {
+      Some(user) => user,
+      None => sqlhandler
+        .add_user(&User {
+          id: 0,
+          username: username.into(),
+          token: password_token(username, "fuzz"),
+          config: UserConfig::default(),
+          usertype: UserType::default(),
+        })
+        .unwrap()
+        .unwrap(),
+    }
+  }
+
+  /// a socket opened with a session token or an api key
+  fn connect_token(addr: &str, token: &str) -> Socket {
+    let (mut socket, _) = tungstenite::connect(format!("ws://{}/ws?token={}", addr, token)).unwrap();
+    if let MaybeTlsStream::Plain(s) = socket.get_mut() {
+      s.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+    }
+    socket
+  }
+
+  fn connect(server: &TestServer, addr: &str, username: &str) -> Socket {
+    let user = test_user(server, username);
+    let issued = issue_session(&server.server.sqlhandler, &user, "fuzz", &server.server.r_config()).unwrap();
+    connect_token(addr, &issued.token)
+  }
+
+  /// the next message of the server, None once it closed
+  fn next_message(socket: &mut Socket) -> Option<serde_json::Value> {
+    loop {
+      match socket.read() {
+        Ok(Message::Text(t)) => return Some(serde_json::from_str(&t).unwrap()),
+        Ok(Message::Close(_)) | Err(_) => return None,
+        Ok(_) => (),
+      }
+    }
+  }
+
+  #[actix_web::test]
+  async fn handshake() {
+    let addr = "127.0.0.1:9995";
+    let server = TestServer::new(&String::from("error"), &addr.to_string());
+    server.run().await;
+    let establish = |msg: serde_json::Value| -> String {
+      serde_json::json!({
+        "sender": { "User": { "username": "shake0", "user_ctx_hash": "" } },
+        "msg": msg,
+        "policy": "Server",
+      }).to_string()
+    };
+
+    // a client without a version is told so and closed
+    let mut socket = connect(&server, addr, "shake0");
+    socket.send(Message::text(establish("Establish".into()))).unwrap();
+    let reply = next_message(&mut socket).unwrap();
+    assert_eq!(reply["msg"]["Error"]["code"], "incompatible");
+    assert!(next_message(&mut socket).is_none());
+
+    let mut socket = connect(&server, addr, "shake0");
+    let mut too_new = WsHandshake::client(4096);
+    too_new.min_version = WS_PROTOCOL_VERSION + 1;
+    socket.send(Message::text(establish(serde_json::json!({ "Establish": too_new })))).unwrap();
+    assert_eq!(next_message(&mut socket).unwrap()["msg"]["Error"]["code"], "incompatible");
+    assert!(next_message(&mut socket).is_none());
+
+    // a newer client is answered with what the server speaks
+    let mut socket = connect(&server, addr, "shake0");
+    let mut newer = WsHandshake::client(u64::MAX);
+    newer.version = WS_PROTOCOL_VERSION + 1;
+    socket.send(Message::text(establish(serde_json::json!({ "Establish": newer })))).unwrap();
+    let agreed = loop {
+      let reply = next_message(&mut socket).unwrap();
+      if let Some(agreed) = reply["msg"].get("Establish") {
+        break serde_json::from_value::<WsHandshake>(agreed.clone()).unwrap();
+      }
+    };
+    assert_eq!(agreed.version, WS_PROTOCOL_VERSION);
+    assert_eq!(agreed.capabilities, WsCapabilities::server(&WsConfig::default()));
+    let _ = socket.close(None);
+    while socket.read().is_ok() {}
+  }
+
+  #[actix_web::test]
+  async fn corrupt_slice() {
+    let addr = "127.0.0.1:9994";
+    let server = TestServer::new(&String::from("error"), &addr.to_string());
+    server.run().await;
+    let username = "slice0";
+    let mut socket = connect(&server, addr, username);
+    let send = |socket: &mut Socket, msg: serde_json::Value| {
+      let msg = serde_json::json!({
+        "sender": { "User": { "username": username, "user_ctx_hash": "" } },
+        "msg": msg,
+        "policy": "Server",
+      });
+      socket.send(Message::text(msg.to_string())).unwrap();
+    };
+    // the next file response, other messages are skipped
+    let response = |socket: &mut Socket| -> (serde_json::Value, String) {
+      loop {
+        let reply = next_message(socket).unwrap();
+        if let Some(resp) = reply["msg"].get("FileResponse") {
+          return (resp["slice_idx"].clone(), resp["status"].as_str().unwrap().to_string());
         }
-        let hashstr: String = bys.slice(0..32).iter().map(|b| {
-          format!("{:02x}", b).to_string()
-        }).collect();
-        let index: u64 = bys.slice(32..36).get_u32_le() as u64;
-        log::warn!("hash {}, index {}, len {}", hashstr, index, bys.len());
-        ctx.stop();
+        assert!(reply["msg"].get("Error").is_none(), "{}", reply);
+      }
+    };
+    send(&mut socket, serde_json::json!({ "Establish": WsHandshake::client(4) }));
+    let hash = sha256::digest("slices");
+    send(&mut socket, serde_json::json!({ "FileRequest": {
+      "username": username, "name": "slices.txt", "size": 8, "slice_size": 4,
+      "last_modified_t": 0, "file_hash": hash,
+    } }));
+    loop {
+      let reply = next_message(&mut socket).unwrap();
+      if let Some(sendable) = reply["msg"].get("FileSendable") {
+        assert!(!sendable["file_elem"].is_null(), "{}", reply);
+        break;
       }
-      ws::Message::Nop => {}
     }
+
+    let mut corrupt = SliceFrame::encode(&hash, 1, b"efgh").unwrap().to_vec();
+    *corrupt.last_mut().unwrap() = b'x';
+    socket.send(Message::binary(corrupt)).unwrap();
+    assert_eq!(response(&mut socket), (serde_json::json!([1, 2]), "Resend".to_string()));
+    for (index, slice) in [(1, b"efgh"), (0, b"abcd")] {
+      socket.send(Message::binary(SliceFrame::encode(&hash, index, slice).unwrap().to_vec())).unwrap();
+      assert_eq!(response(&mut socket), (serde_json::json!([index, index + 1]), "Ok".to_string()));
+    }
+    let folder = std::path::PathBuf::from("inner/storage").join(username);
+    assert_eq!(std::fs::read(folder.join("slices.txt")).unwrap(), b"abcdefgh");
+    let _ = socket.close(None);
+    while socket.read().is_ok() {}
+    let _ = std::fs::remove_dir_all(folder);
+  }
+
+  /// a message sent in fragments of at most size bytes, false if the socket is gone
+  fn send_fragments(socket: &mut Socket, data: &[u8], kind: Data, size: usize) -> bool {
+    let chunks: Vec<&[u8]> = data.chunks(size).collect();
+    chunks.iter().enumerate().all(|(i, chunk)| {
+      let opcode = if i == 0 { OpCode::Data(kind) } else { OpCode::Data(Data::Continue) };
+      socket.send(Message::Frame(Frame::message(chunk.to_vec(), opcode, i + 1 == chunks.len()))).is_ok()
+    })
+  }
+
+  #[actix_web::test]
+  async fn fragmented_slice() {
+    let addr = "127.0.0.1:9993";
+    let server = TestServer::new(&String::from("error"), &addr.to_string());
+    server.run().await;
+    let username = "fragment0";
+    let mut socket = connect(&server, addr, username);
+    let message = |msg: serde_json::Value| -> String {
+      serde_json::json!({
+        "sender": { "User": { "username": username, "user_ctx_hash": "" } },
+        "msg": msg,
+        "policy": "Server",
+      }).to_string()
+    };
+    // a text message may be fragmented too
+    let establish = message(serde_json::json!({ "Establish": WsHandshake::client(MAX_SLICE_SIZE) }));
+    assert!(send_fragments(&mut socket, establish.as_bytes(), Data::Text, 16));
+
+    // a slice larger than a frame may be
+    let size = WsConfig::default().max_frame_size * 2;
+    let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    let hash = sha256::digest("fragments");
+    socket.send(Message::text(message(serde_json::json!({ "FileRequest": {
+      "username": username, "name": "fragments.bin", "size": size, "slice_size": size,
+      "last_modified_t": 0, "file_hash": hash,
+    } })))).unwrap();
+    loop {
+      let reply = next_message(&mut socket).unwrap();
+      if let Some(sendable) = reply["msg"].get("FileSendable") {
+        assert!(!sendable["file_elem"].is_null(), "{}", reply);
+        break;
+      }
+    }
+    let frame = SliceFrame::encode(&hash, 0, &content).unwrap();
+    assert!(send_fragments(&mut socket, &frame, Data::Binary, 32 * 1024));
+    loop {
+      let reply = next_message(&mut socket).unwrap();
+      if let Some(resp) = reply["msg"].get("FileResponse") {
+        assert_eq!(resp["status"], "Ok", "{}", reply);
+        break;
+      }
+      assert!(reply["msg"].get("Error").is_none(), "{}", reply);
+    }
+    let folder = std::path::PathBuf::from("inner/storage").join(username);
+    assert_eq!(std::fs::read(folder.join("fragments.bin")).unwrap(), content);
+    let _ = std::fs::remove_dir_all(folder);
+
+    // a message beyond the limit closes the connection
+    let oversized = vec![0u8; WsConfig::default().max_message_size + 1];
+    send_fragments(&mut socket, &oversized, Data::Binary, 32 * 1024);
+    let code = loop {
+      match socket.read() {
+        Ok(Message::Close(frame)) => break frame.map(|f| f.code),
+        Ok(_) => (),
+        Err(e) => panic!("no close frame: {}", e),
+      }
+    };
+    assert_eq!(code, Some(CloseCode::Size));
+    while socket.read().is_ok() {}
+  }
+
+  #[actix_web::test]
+  async fn session_control() {
+    let addr = "127.0.0.1:9992";
+    let server = TestServer::new(&String::from("error"), &addr.to_string());
+    server.run().await;
+    let username = "apikey0";
+    let mut login = connect(&server, addr, username);
+    let user = test_user(&server, username);
+    let created = do_create_api_key(&user, &CreateApiKeyRequest {
+      name: "read".into(),
+      scopes: vec![ApiScope::Read],
+      ttl: None,
+    }, &server.server.sqlhandler).unwrap();
+    let mut keyed = connect_token(addr, &created.key);
+    let send = |socket: &mut Socket, msg: serde_json::Value| {
+      let msg = serde_json::json!({
+        "sender": { "User": { "username": username, "user_ctx_hash": "" } },
+        "msg": msg,
+        "policy": "Server",
+      });
+      socket.send(Message::text(msg.to_string())).unwrap();
+    };
+    let error = |socket: &mut Socket| -> serde_json::Value {
+      loop {
+        let reply = next_message(socket).unwrap();
+        if let Some(e) = reply["msg"].get("Error") {
+          return e.clone();
+        }
+      }
+    };
+    send(&mut login, serde_json::json!({ "Establish": WsHandshake::client(4) }));
+    send(&mut keyed, serde_json::json!({ "Establish": WsHandshake::client(4) }));
+
+    // an api key neither sees nor signs out the login sessions of its owner,
+    // nor rewrites its views
+    send(&mut keyed, serde_json::json!("ListSessions"));
+    assert_eq!(error(&mut keyed)["code"], "forbidden");
+    send(&mut keyed, serde_json::json!({ "TerminateSession": "AllOthers" }));
+    assert_eq!(error(&mut keyed)["code"], "forbidden");
+    let view = FileListView { path: "/".into(), config: FileListPathConfig::default() };
+    send(&mut keyed, serde_json::json!({ "SetFileListView": view }));
+    assert_eq!(error(&mut keyed)["code"], "forbidden");
+    assert!(alive(&mut login, username));
+    // a revoked key loses its sockets at once
+    do_revoke_api_key(&server.server, &user, created.api_key.id).unwrap();
+    while next_message(&mut keyed).is_some() {}
+    assert!(alive(&mut login, username));
+
+    // every socket of a signed out login is closed, not only the one named
+    let issued = issue_session(&server.server.sqlhandler, &user, "tabs", &server.server.r_config()).unwrap();
+    let mut tabs = [connect_token(addr, &issued.token), connect_token(addr, &issued.token)];
+    for tab in tabs.iter_mut() {
+      send(tab, serde_json::json!({ "Establish": WsHandshake::client(4) }));
+      assert!(alive(tab, username));
+    }
+    send(&mut login, serde_json::json!("ListSessions"));
+    let sessions = loop {
+      let reply = next_message(&mut login).unwrap();
+      if let Some(sessions) = reply["msg"].get("Sessions") {
+        break serde_json::from_value::<Vec<ActiveSession>>(sessions.clone()).unwrap();
+      }
+    };
+    let tab = sessions.iter().find(|s| !s.current).unwrap();
+    send(&mut login, serde_json::json!({ "TerminateSession": { "One": tab.id } }));
+    for tab in tabs.iter_mut() {
+      while next_message(tab).is_some() {}
+    }
+    let revoked = server.server.sqlhandler.get_session_by_token(&hash_session_token(&issued.token)).unwrap();
+    assert!(revoked.unwrap().revoked);
+    assert!(alive(&mut login, username));
+
+    // a logout closes the sockets of its session only
+    let issued = issue_session(&server.server.sqlhandler, &user, "logout", &server.server.r_config()).unwrap();
+    let mut tab = connect_token(addr, &issued.token);
+    send(&mut tab, serde_json::json!({ "Establish": WsHandshake::client(4) }));
+    assert!(alive(&mut tab, username));
+    let auth = AuthUser {
+      user: test_user(&server, username),
+      credential: Credential::Session(issued.session),
+      token_hash: hash_session_token(&issued.token),
+    };
+    let logout = web::Json(LogoutRequest { basic_info: StreamBasicInfo { time_stamp: 0 } });
+    do_logout(&auth, &logout, &web::Data::new(server.server.clone())).unwrap();
+    while next_message(&mut tab).is_some() {}
+    assert!(!alive(&mut tab, username));
+    assert!(alive(&mut login, username));
+    let _ = login.close(None);
+    while login.read().is_ok() {}
+  }
+
+  #[actix_web::test]
+  async fn demoted_manager() {
+    let addr = "127.0.0.1:9991";
+    let server = TestServer::new(&String::from("error"), &addr.to_string());
+    server.run().await;
+    let username = "demoted0";
+    test_user(&server, username);
+    let sqlhandler = &server.server.sqlhandler;
+    sqlhandler.update_user_type_by_name(username, &UserType::Manager).unwrap();
+    let user = test_user(&server, username);
+    let issued = issue_session(sqlhandler, &user, "demoted", &server.server.r_config()).unwrap();
+    let mut socket = connect_token(addr, &issued.token);
+    let send = |socket: &mut Socket, msg: serde_json::Value, policy: &str| {
+      let msg = serde_json::json!({
+        "sender": { "User": { "username": username, "user_ctx_hash": "" } },
+        "msg": msg,
+        "policy": policy,
+      });
+      socket.send(Message::text(msg.to_string())).unwrap();
+    };
+    send(&mut socket, serde_json::json!({ "Establish": WsHandshake::client(4) }), "Server");
+    // a broadcast only reaches established sessions
+    while next_message(&mut socket).unwrap()["msg"].get("Establish").is_none() {}
+    let broadcast = |socket: &mut Socket| -> serde_json::Value {
+      send(socket, serde_json::json!({ "Text": "hello" }), "Broadcast");
+      loop {
+        let reply = next_message(socket).unwrap();
+        if reply["msg"].get("Text").is_some() || reply["msg"].get("Error").is_some() {
+          return reply["msg"].clone();
+        }
+      }
+    };
+    assert_eq!(broadcast(&mut socket)["Text"], "hello");
+
+    // no heartbeat is sent by this client, the server refreshes the type itself
+    sqlhandler.update_user_type_by_name(username, &UserType::User).unwrap();
+    std::thread::sleep(HEARTBEAT_INTERVAL + Duration::from_secs(1));
+    assert_eq!(broadcast(&mut socket)["Error"]["code"], "forbidden");
+
+    // nor is the session only checked by the handshake
+    sqlhandler.revoke_session(issued.session.id).unwrap();
+    std::thread::sleep(HEARTBEAT_INTERVAL + Duration::from_secs(1));
+    assert!(!alive(&mut socket, username));
+  }
+
+  #[actix_web::test]
+  async fn fuzz_session() {
+    let addr = "127.0.0.1:9996";
+    let server = TestServer::new(&String::from("error"), &addr.to_string());
+    server.run().await;
+    let username = "fuzz0";
+    let mut socket = connect(&server, addr, username);
+    // some storage is used, such that a huge size can overflow the quota check
+    let storage = std::path::PathBuf::from("inner/storage");
+    std::fs::create_dir_all(storage.join(username)).unwrap();
+    std::fs::write(storage.join(username).join("used"), b"used").unwrap();
+
+    let hashes = hashes();
+    let mut rng = StdRng::seed_from_u64(45);
+    for round in 0..300 {
+      let message = random_message(&mut rng, username, &hashes).to_string();
+      let sent = match rng.gen_range(0..4) {
+        0 => Message::binary(random_frame(&mut rng, &hashes)),
+        1 => Message::text(mutate(&mut rng, message.clone())),
+        _ => Message::text(message.clone()),
+      };
+      socket.send(sent.clone()).unwrap();
+      assert!(alive(&mut socket, username), "session died at round {} after {:?}", round, sent);
+    }
+    let _ = socket.close(None);
+    while socket.read().is_ok() {}
+
+    assert!(!storage.join("escape").exists());
+    assert!(!std::path::Path::new("inner/escape").exists());
+    let _ = std::fs::remove_dir_all(storage.join(username));
   }
 }